@@ -1,8 +1,163 @@
 use clap::{App, Arg};
 
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+#[cfg(feature = "task-scheduler")]
+fn task_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("exclude-newer-than-task")
+            .long("exclude-newer-than-task")
+            .help("Removes only the files created before the scheduled task was installed"),
+        Arg::new("force")
+            .long("force")
+            .help("Installs the task even without a --created-before of at least one day"),
+        Arg::new("force-reinstall")
+            .long("force-reinstall")
+            .help("Registers the task again even if it's already installed with the same arguments"),
+        Arg::new("install-task")
+            .long("install-task")
+            .help("Creates a new task in the scheduler for cleaning during startup as SYSTEM user"),
+        Arg::new("show-task-script")
+            .long("show-task-script")
+            .help("Prints the PowerShell script --install-task or --update-task would run, with the arguments of the task, without running it. Doesn't require administrator privileges"),
+        Arg::new("task-install-cpu-limit")
+            .long("task-install-cpu-limit")
+            .takes_value(true)
+            .value_name("duration")
+            .number_of_values(1)
+            .help("Kills the PowerShell process registering the task if it uses more CPU time than the duration (10s, 1min, etc...)"),
+        Arg::new("task-install-memory-limit")
+            .long("task-install-memory-limit")
+            .takes_value(true)
+            .value_name("size")
+            .number_of_values(1)
+            .help("Kills the PowerShell process registering the task if it commits more memory than the size (256MiB, 1 GB, etc...)"),
+        Arg::new("update-task")
+            .long("update-task")
+            .help("Updates the arguments of the installed task, keeping the options not given again and the triggers edited in the Task Scheduler (installs the task if missing)"),
+    ]
+}
+
+#[cfg(not(feature = "task-scheduler"))]
+fn task_args() -> Vec<Arg<'static>> {
+    vec![]
+}
+
 pub fn build_app() -> App<'static> {
     App::new(clap::crate_name!())
         .version(clap::crate_version!())
+        .args(task_args())
+        .arg(
+            Arg::new("abort-on-system-dir")
+                .long("abort-on-system-dir")
+                .help("Fails before cleaning anything if a directory to clean is a system directory like Windows\\System32 or Program Files, or is within one, even with --force")
+        )
+        .arg(
+            Arg::new("age-basis")
+                .long("age-basis")
+                .takes_value(true)
+                .value_name("basis")
+                .number_of_values(1)
+                .possible_values(["created", "modified", "accessed"])
+                .help("Timestamp compared by --created-before and --histogram (defaults to created)")
+        )
+        .arg(
+            Arg::new("age-from-name")
+                .long("age-from-name")
+                .takes_value(true)
+                .value_name("pattern")
+                .number_of_values(1)
+                .help("Reads the age compared by --created-before and --created-after from the date in the file name matching the pattern, like log_%Y%m%d with %Y, %y, %m, %d, %H, %M and %S in UTC. Other names use the timestamps")
+        )
+        .arg(
+            Arg::new("age-histogram")
+                .long("age-histogram")
+                .help("Analyzes without removing anything: prints the files that would be removed by age with a bar chart of their size, to choose the --created-before duration. Implies --dry-run")
+        )
+        .arg(
+            Arg::new("age-unit")
+                .long("age-unit")
+                .takes_value(true)
+                .value_name("unit")
+                .number_of_values(1)
+                .help("Unit of the bare numbers given to --created-before and --created-after, days or hours (--created-before 7 --age-unit days is the same as --created-before 7d)")
+        )
+        .arg(
+            Arg::new("all-ages")
+                .long("all-ages")
+                .alias("include-zero-age")
+                .help("Removes the entries of any age, even just created, for this run. Takes precedence over --created-before, --created-after, --idle, --extension-rule and --exclude-newer-than-task, the other filters still apply. Not kept by the scheduled task")
+        )
+        .arg(
+            Arg::new("audit")
+                .long("audit")
+                .takes_value(true)
+                .value_name("audit file")
+                .number_of_values(1)
+                .help("Appends every removed entry to the file with its time, size and a SHA-256 hash chained to the previous line, so changes to the record can be detected. Relative paths are within --state-dir")
+        )
+        .arg(
+            Arg::new("bytes-format")
+                .long("bytes-format")
+                .takes_value(true)
+                .value_name("format")
+                .number_of_values(1)
+                .possible_values(["iec", "si"])
+                .help("Prints sizes in powers of 1024 (iec, KiB) or 1000 (si, kB), defaults to iec")
+        )
+        .arg(
+            Arg::new("clean-webcache")
+                .long("clean-webcache")
+                .help("Also cleans the Edge and Internet Explorer WebCache of every user, skipping the files in use by the browser")
+        )
+        .arg(
+            Arg::new("clean-windows-update-cache")
+                .long("clean-windows-update-cache")
+                .alias("clean-update-cache")
+                .help("Also cleans the Windows Update download cache in Windows\\SoftwareDistribution\\Download, stopping the Windows Update service meanwhile unless --keep-update-service-running is given")
+        )
+        .arg(
+            Arg::new("color-summary-by-severity")
+                .long("color-summary-by-severity")
+                .help("Prints the final summary in green without errors, yellow with some errors and red when errors dominated, on the terminal only")
+        )
+        .arg(
+            Arg::new("compare")
+                .long("compare")
+                .takes_value(true)
+                .value_names(&["before", "after"])
+                .number_of_values(2)
+                .help("Prints the directories using less and more space in the second scan file than in the first, written by --scan-before or --scan-after, with the totals and exits")
+        )
+        .arg(
+            Arg::new("compress-before-delete")
+                .long("compress-before-delete")
+                .takes_value(true)
+                .value_name("size")
+                .number_of_values(1)
+                .help("Instead of removing the files at least as large as the size (1024, 10MiB, 1.5 GB, etc...), compresses them to --quarantine-dir with a .meta.json file recording their original path, size and deletion time")
+        )
+        .arg(
+            Arg::new("continue-from")
+                .long("continue-from")
+                .takes_value(true)
+                .value_name("state file")
+                .number_of_values(1)
+                .help("Records the completed directories in the state file and skips them when resuming an interrupted run, the file is removed once a run completes. Relative paths are within --state-dir")
+        )
+        .arg(
+            Arg::new("count-ads")
+                .long("count-ads")
+                .help("Includes the size of the NTFS alternate data streams in the removed bytes")
+        )
+        .arg(
+            Arg::new("created-after")
+                .long("created-after")
+                .takes_value(true)
+                .value_name("duration")
+                .number_of_values(1)
+                .help("Removes only the files created after the specified duration, combine with --created-before for a window, in either order (--created-before 7d --created-after 30d)"),
+        )
         .arg(
             Arg::new("created-before")
                 .long("created-before")
@@ -10,7 +165,59 @@ pub fn build_app() -> App<'static> {
                 .takes_value(true)
                 .value_name("duration")
                 .number_of_values(1)
-                .help("Removes only the files created before the specified duration (60s, 10m, 10h, 10d, 10days 2min, etc...)"),
+                .help("Removes only the files created before the specified duration (60s, 10m, 10h, 10d, 10days 2min, etc...), or before the creation of the file given as @path"),
+        )
+        .arg(
+            Arg::new("dedupe")
+                .long("dedupe")
+                .help("Removes all but one copy of the identical files of each directory, regardless of their age")
+        )
+        .arg(
+            Arg::new("delete-list")
+                .long("delete-list")
+                .takes_value(true)
+                .value_name("list file")
+                .number_of_values(1)
+                .help("Removes the absolute paths listed in the file, one per line, instead of walking the directories. Paths outside the cleaned directories are skipped")
+        )
+        .arg(
+            Arg::new("delete-on-reboot")
+                .long("delete-on-reboot")
+                .help("Schedules the entries that are locked or denied for deletion at the next reboot, requires administrator privileges")
+        )
+        .arg(
+            Arg::new("dereference-count")
+                .long("dereference-count")
+                .takes_value(true)
+                .value_name("count")
+                .number_of_values(1)
+                .help("Maximum number of nested symbolic links or junctions followed while cleaning (defaults to 8)")
+        )
+        .arg(
+            Arg::new("diff")
+                .long("diff")
+                .help("Prints the files that appeared, disappeared or changed size between the --scan-before and --scan-after files and exits")
+        )
+        .arg(
+            Arg::new("dir-max-size")
+                .long("dir-max-size")
+                .takes_value(true)
+                .value_name("size")
+                .number_of_values(1)
+                .help("After cleaning, removes the oldest files of every directory regardless of their age until the remaining ones sum to at most the size (1024, 10MiB, 1.5 GB, etc...)")
+        )
+        .arg(
+            Arg::new("dir-name-pattern")
+                .long("dir-name-pattern")
+                .takes_value(true)
+                .value_name("regex")
+                .number_of_values(1)
+                .help("Removes only the top level directories whose name matches the regular expression, like ^\\{[0-9A-Fa-f-]{36}\\}$ for installer scratch directories. Files are unaffected")
+        )
+        .arg(
+            Arg::new("doctor")
+                .long("doctor")
+                .help("Prints a checklist of the elevation, the log file, the scheduled task, the directories to clean and the free space of their volumes, then exits")
         )
         .arg(
             Arg::new("dry-run")
@@ -19,9 +226,156 @@ pub fn build_app() -> App<'static> {
                 .help("Doesn't actually remove the files")
         )
         .arg(
-            Arg::new("install-task")
-            .long("install-task")
-            .help("Creates a new task in the scheduler for cleaning during startup as SYSTEM user")
+            Arg::new("dry-run-apply-threshold")
+                .long("dry-run-apply-threshold")
+                .takes_value(true)
+                .value_name("limit")
+                .number_of_values(1)
+                .help("Does a dry run first and cleans only if it finds less entries (1000) or bytes (5GiB) than the limit, otherwise fails")
+        )
+        .arg(
+            Arg::new("dry-run-estimate-time")
+                .long("dry-run-estimate-time")
+                .help("After the dry run, estimates how long a real run would take from the number of entries and the time to create and remove some test files in the first cleaned directory. Implies --dry-run, the test files are really written")
+        )
+        .arg(
+            Arg::new("elevate")
+                .long("elevate")
+                .help("Runs again with the same arguments as administrator after the UAC prompt, in a new console window (use --log to keep the output)")
+        )
+        .arg(
+            Arg::new("empty-only")
+                .long("empty-only")
+                .help("Removes only empty directories and zero-byte files, regardless of their age")
+        )
+        .arg(
+            Arg::new("error-format")
+                .long("error-format")
+                .takes_value(true)
+                .value_name("format")
+                .number_of_values(1)
+                .possible_values(["human", "json"])
+                .help("Format of the error printed when the program fails (defaults to human)")
+        )
+        .arg(
+            Arg::new("exclude-busy-dirs")
+                .long("exclude-busy-dirs")
+                .help("Skips the directories containing files in use by any process, along with their whole subtree")
+        )
+        .arg(
+            Arg::new("extension-rule")
+                .long("extension-rule")
+                .takes_value(true)
+                .value_name("extensions=duration")
+                .number_of_values(1)
+                .multiple_occurrences(true)
+                .help("Removes the files with one of the comma separated extensions only once older than the duration, like tmp=1d or log,etl=7d, even if --created-before would remove them sooner. The rule with the longest matching extension applies (repeatable)")
+        )
+        .arg(
+            Arg::new("failure-warning")
+                .long("failure-warning")
+                .takes_value(true)
+                .value_name("percent")
+                .number_of_values(1)
+                .help("Warns when more than the specified percentage of removals failed in a directory (defaults to 50)")
+        )
+        .arg(
+            Arg::new("filter-exec")
+                .long("filter-exec")
+                .takes_value(true)
+                .value_name("command")
+                .number_of_values(1)
+                .help("Runs the command with the path of every entry to remove, which is kept unless it exits with 0. The path, the size, the times and whether it's a directory are in the WINTEMPCLEAN_* environment variables. Starts a process per entry")
+        )
+        .arg(
+            Arg::new("flush-every")
+                .long("flush-every")
+                .takes_value(true)
+                .value_name("count")
+                .number_of_values(1)
+                .help("Flushes the --audit file every specified number of entries instead of after each one, faster but the last entries may be lost if the process is killed")
+        )
+        .arg(
+            Arg::new("follow-reparse-path")
+                .long("follow-reparse-path")
+                .takes_value(true)
+                .value_name("path")
+                .number_of_values(1)
+                .multiple_occurrences(true)
+                .help("Cleans the contents of the specified symbolic link or junction, which are never followed otherwise (repeatable)")
+        )
+        .arg(
+            Arg::new("gc-threshold")
+                .long("gc-threshold")
+                .takes_value(true)
+                .value_name("percent")
+                .number_of_values(1)
+                .help("Cleans only while the free space of the volume is below the specified percentage, checked before every directory")
+        )
+        .arg(
+            Arg::new("histogram")
+                .long("histogram")
+                .takes_value(true)
+                .value_name("format")
+                .min_values(0)
+                .max_values(1)
+                .default_missing_value("human")
+                .possible_values(["human", "json"])
+                .help("Prints the removed files by age at the end, use with --dry-run to only analyze")
+        )
+        .arg(
+            Arg::new("history")
+                .long("history")
+                .takes_value(true)
+                .value_name("path")
+                .number_of_values(1)
+                .help("Appends a CSV row with the time, the removed entries and bytes, the errors and the duration of the run to the file, not in a dry run")
+        )
+        .arg(
+            Arg::new("idle")
+                .long("idle")
+                .takes_value(true)
+                .value_name("duration")
+                .number_of_values(1)
+                .help("Removes only the files not created, modified or accessed within the specified duration (60s, 10m, 10h, 10d, 10days 2min, etc...)")
+        )
+        .arg(
+            Arg::new("include-inetcache")
+                .long("include-inetcache")
+                .help("Also cleans the Internet Explorer and WinINet cache of every user, skipping the files in use")
+        )
+        .arg(
+            Arg::new("include-readonly-volumes")
+                .long("include-readonly-volumes")
+                .help("Attempts to clean the directories on volumes mounted read-only, which are skipped with a warning by default since every removal would fail")
+        )
+        .arg(
+            Arg::new("include-vs-temp")
+                .long("include-vs-temp")
+                .help("Also cleans the Visual Studio and MSBuild temporary directories and the obj directories under source of every user, skipping the files in use")
+        )
+        .arg(
+            Arg::new("keep-extensions")
+                .long("keep-extensions")
+                .takes_value(true)
+                .value_name("extensions")
+                .number_of_values(1)
+                .help("Never removes the files with the given comma separated extensions, like log,db,sqlite, ignoring case. Overrides all the other options, including --dedupe, --delete-list and --force")
+        )
+        .arg(
+            Arg::new("keep-update-service-running")
+                .long("keep-update-service-running")
+                .help("Leaves the Windows Update service running while cleaning its download cache, skipping the files it's using")
+        )
+        .arg(
+            Arg::new("limit-depth-report")
+                .long("limit-depth-report")
+                .help("Also reports the entries and bytes removed within every top level subdirectory of the cleaned directories, largest first")
+        )
+        .arg(
+            Arg::new("list-presets")
+                .long("list-presets")
+                .help("Lists the directory presets accepted by --preset")
         )
         .arg(
             Arg::new("log")
@@ -30,7 +384,175 @@ pub fn build_app() -> App<'static> {
                 .takes_value(true)
                 .value_name("log file")
                 .number_of_values(1)
-                .help("Log output to a file")
+                .help("Log output to a file, or to a new file for every run named after the time (wintempclean-20220131-235959.log) if it's a directory")
+        )
+        .arg(
+            Arg::new("log-compress")
+                .long("log-compress")
+                .help("Gzips the --log segments rotated by --log-max-size (.1.gz, .2.gz, etc...), the active log stays uncompressed")
+        )
+        .arg(
+            Arg::new("log-file-level")
+                .long("log-file-level")
+                .takes_value(true)
+                .value_name("level")
+                .number_of_values(1)
+                .possible_values(LOG_LEVELS)
+                .help("Level of the messages written to the --log file, defaults to the terminal level")
+        )
+        .arg(
+            Arg::new("log-keep")
+                .long("log-keep")
+                .takes_value(true)
+                .value_name("count")
+                .number_of_values(1)
+                .help("When --log is a directory, removes the oldest log files of the previous runs beyond the count")
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .takes_value(true)
+                .value_name("level")
+                .number_of_values(1)
+                .possible_values(LOG_LEVELS)
+                .help("Level of the messages shown, overrides --verbose and --quiet")
+        )
+        .arg(
+            Arg::new("log-max-size")
+                .long("log-max-size")
+                .takes_value(true)
+                .value_name("size")
+                .number_of_values(1)
+                .help("At the start of a run, renames the --log file to .1 once it reaches the size (1024, 10MiB, 1.5 GB, etc...), shifting the older segments and keeping the last 5")
+        )
+        .arg(
+            Arg::new("metrics-file")
+                .long("metrics-file")
+                .takes_value(true)
+                .value_name("path")
+                .number_of_values(1)
+                .help("Writes the removed entries and bytes, the errors and the time of the run to the file in the Prometheus text format for the node_exporter textfile collector, not in a dry run")
+        )
+        .arg(
+            Arg::new("min-dir-size")
+                .long("min-dir-size")
+                .takes_value(true)
+                .value_name("size")
+                .number_of_values(1)
+                .help("Skips the top level directories whose files, not counting subdirectories, sum to less than the size (1024, 10MiB, 1.5 GB, etc...)")
+        )
+        .arg(
+            Arg::new("no-cross-volume")
+                .long("no-cross-volume")
+                .help("Skips the directories on another volume than the system drive, or than --root when given")
+        )
+        .arg(
+            Arg::new("no-user-profiles")
+                .long("no-user-profiles")
+                .help("Cleans only the system temporary directories and the --preset ones without listing the user profiles in Users, which is slow on servers with many profiles")
+        )
+        .arg(
+            Arg::new("now")
+                .long("now")
+                .takes_value(true)
+                .value_name("time")
+                .number_of_values(1)
+                .hide(true)
+                .help("Measures the ages from the RFC 3339 time (2022-01-01T00:00:00Z) instead of the current time, to reproduce a run at that time")
+        )
+        .arg(
+            Arg::new("only-between")
+                .long("only-between")
+                .takes_value(true)
+                .value_name("window")
+                .number_of_values(1)
+                .help("Exits without cleaning when started outside the local time window (02:00-05:00, may cross midnight)")
+        )
+        .arg(
+            Arg::new("op-timeout")
+                .long("op-timeout")
+                .takes_value(true)
+                .value_name("duration")
+                .number_of_values(1)
+                .help("Abandons a metadata read or a removal that doesn't complete within the duration (10s, 1min, etc...) with a warning and moves on to the next entry")
+        )
+        .arg(
+            Arg::new("output-format")
+                .long("output-format")
+                .takes_value(true)
+                .value_name("format")
+                .number_of_values(1)
+                .possible_values(["human", "json"])
+                .help("Format of the --compare report, a table by default")
+        )
+        .arg(
+            Arg::new("parallel-users")
+                .long("parallel-users")
+                .takes_value(true)
+                .value_name("threads")
+                .number_of_values(1)
+                .help("Cleans the directories of the user profiles with the given number of threads, the system directories are cleaned first")
+        )
+        .arg(
+            Arg::new("preset")
+                .long("preset")
+                .takes_value(true)
+                .value_name("name")
+                .number_of_values(1)
+                .multiple_occurrences(true)
+                .help("Also cleans the well-known temporary directories of a server application, skipping the files in use (repeatable, see --list-presets)")
+        )
+        .arg(
+            Arg::new("preserve-inetcache-index")
+                .long("preserve-inetcache-index")
+                .help("Keeps the index.dat and desktop.ini files of the cache directories cleaned with --include-inetcache")
+        )
+        .arg(
+            Arg::new("preview")
+                .long("preview")
+                .takes_value(true)
+                .value_name("count")
+                .number_of_values(1)
+                .help("Lists the first entries that would be removed and stops, implies --dry-run")
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("pattern")
+                .number_of_values(1)
+                .multiple_occurrences(true)
+                .help("Cleans only the user profiles whose directory name matches the pattern, where * matches any characters and ? a single one, ignoring case, like svc_* (repeatable)")
+        )
+        .arg(
+            Arg::new("protect-dir")
+                .long("protect-dir")
+                .takes_value(true)
+                .value_name("path")
+                .number_of_values(1)
+                .multiple_occurrences(true)
+                .help("Never touches the subdirectory at the relative path within each cleaned directory, like MyApp\\Cache, nor anything in it (repeatable)")
+        )
+        .arg(
+            Arg::new("protect-running")
+                .long("protect-running")
+                .help("Never removes the executables and DLLs loaded by the running processes, like portable applications started from a temp directory. The processes are listed once per run")
+        )
+        .arg(
+            Arg::new("quarantine-dir")
+                .long("quarantine-dir")
+                .takes_value(true)
+                .value_name("path")
+                .number_of_values(1)
+                .help("Directory of the compressed copies made by --compress-before-delete. Relative paths are within --state-dir")
+        )
+        .arg(
+            Arg::new("quarantine-ttl")
+                .long("quarantine-ttl")
+                .takes_value(true)
+                .value_name("duration")
+                .number_of_values(1)
+                .help("Permanently removes the compressed copies in --quarantine-dir older than the duration (7days, 1month, etc...) at the start of every run")
         )
         .arg(
             Arg::new("quiet")
@@ -38,10 +560,143 @@ pub fn build_app() -> App<'static> {
                 .short('q')
                 .help("Suppress all terminal output")
         )
+        .arg(
+            Arg::new("remote")
+                .long("remote")
+                .takes_value(true)
+                .value_name("host")
+                .number_of_values(1)
+                .help("Experimental: cleans the standard locations of another machine through its administrative share (\\\\host\\C$), the account running the cleanup needs administrator rights on it")
+        )
+        .arg(
+            Arg::new("rename-pending")
+                .long("rename-pending")
+                .help("Renames the directories that are still not empty after a retry to *.pending-delete, which the next run removes regardless of their age")
+        )
+        .arg(
+            Arg::new("report-free-space-before-after")
+                .long("report-free-space-before-after")
+                .help("Prints the free space of every cleaned volume before and after cleaning, next to the bytes removed from it")
+        )
+        .arg(
+            Arg::new("report-skipped")
+                .long("report-skipped")
+                .help("Prints after every directory how many entries were left by the age filters, by their name and because they were in use")
+        )
+        .arg(
+            Arg::new("retention")
+                .long("retention")
+                .takes_value(true)
+                .value_name("policy")
+                .number_of_values(1)
+                .help("Keeps the newest file of each hour, day, week, month or year in the directories, like 24h,7d,4w,12m")
+        )
+        .arg(
+            Arg::new("root")
+                .long("root")
+                .takes_value(true)
+                .value_name("path")
+                .number_of_values(1)
+                .help("Cleans the Windows installation at the given path, like a mounted VHD or an offline image, instead of the running one")
+        )
+        .arg(
+            Arg::new("scan-after")
+                .long("scan-after")
+                .takes_value(true)
+                .value_name("file")
+                .number_of_values(1)
+                .help("Writes the path and size of every file in the directories after cleaning to the JSON file, see --diff")
+        )
+        .arg(
+            Arg::new("scan-before")
+                .long("scan-before")
+                .takes_value(true)
+                .value_name("file")
+                .number_of_values(1)
+                .help("Writes the path and size of every file in the directories before cleaning to the JSON file, see --diff")
+        )
+        .arg(
+            Arg::new("sentinel")
+                .long("sentinel")
+                .takes_value(true)
+                .value_name("name")
+                .number_of_values(1)
+                .help("Name of the file that protects the directory containing it and its subtree from cleaning, defaults to .keep")
+        )
+        .arg(
+            Arg::new("show-targets")
+                .long("show-targets")
+                .alias("show-roots")
+                .help("Prints the directories that would be cleaned with the given options, one per line, and exits")
+        )
+        .arg(
+            Arg::new("simulate-permissions")
+                .long("simulate-permissions")
+                .help("Dry run that also opens every entry for deletion to report whether removing it would succeed, without changing anything")
+        )
+        .arg(
+            Arg::new("state-dir")
+                .long("state-dir")
+                .takes_value(true)
+                .value_name("path")
+                .number_of_values(1)
+                .help("Directory of the state kept between runs, created when needed. Holds the --audit and --continue-from files given as relative paths (defaults to %ProgramData%\\wintempclean)")
+        )
+        .arg(
+            Arg::new("skip-cloud")
+                .long("skip-cloud")
+                .help("Skips the files stored in the cloud, like OneDrive files available online only, instead of downloading them when their contents are read")
+        )
+        .arg(
+            Arg::new("skip-if-storage-sense-ran-within")
+                .long("skip-if-storage-sense-ran-within")
+                .takes_value(true)
+                .value_name("duration")
+                .number_of_values(1)
+                .help("Skips the directories of the user profiles if the Storage Sense scheduled task ran within the specified duration, the system directories are still cleaned")
+        )
+        .arg(
+            Arg::new("summary-file")
+                .long("summary-file")
+                .takes_value(true)
+                .value_name("path")
+                .number_of_values(1)
+                .help("Writes only the summary lines of every directory and of the whole run to the file, replaced on each run")
+        )
+        .arg(
+            Arg::new("tag")
+                .long("tag")
+                .takes_value(true)
+                .value_name("key=value")
+                .number_of_values(1)
+                .multiple_occurrences(true)
+                .help("Annotates the run with the key and value, which are logged and added to the --audit entries and the JSON histogram (repeatable)")
+        )
+        .arg(
+            Arg::new("timings")
+                .long("timings")
+                .help("Prints the time spent resolving the directories, walking them and removing entries, per directory with --verbose")
+        )
+        .arg(
+            Arg::new("trim-sparse")
+                .long("trim-sparse")
+                .takes_value(true)
+                .value_name("size")
+                .number_of_values(1)
+                .help("Advanced: instead of removing files of at least the specified size (100MiB, 1GB, etc...), deallocates their zeroed blocks leaving them in place")
+        )
         .arg(
             Arg::new("verbose")
                 .long("verbose")
                 .short('v')
                 .help("Shows what files are removed")
         )
+        .arg(
+            Arg::new("when-above")
+                .long("when-above")
+                .takes_value(true)
+                .value_name("percent")
+                .number_of_values(1)
+                .help("Cleans a directory only when the usage of its volume is above the specified percentage")
+        )
 }
@@ -0,0 +1,250 @@
+//! Append-only record of the removed entries written with --audit.
+//!
+//! Every line holds four tab separated fields, five when the run was given --tag:
+//!
+//! ```text
+//! <timestamp>\t<size>\t<path>[\t<tags>]\t<hash>
+//! ```
+//!
+//! where the timestamp is RFC 3339 in UTC, the tags are comma separated key=value pairs
+//! and the hash is the lowercase hex SHA-256 of the previous line's hash followed by a
+//! tab and the other fields of the line, tab separated. The first line of a file chains
+//! to 64 zeros. Changing, removing or reordering any line changes the hashes of all the
+//! following ones, so a file can be verified by recomputing the chain from the start and
+//! comparing the last hash with a copy kept elsewhere.
+//!
+//! Lines are flushed after every entry unless --flush-every batches them, the pending
+//! lines are flushed when the record is dropped, including when the run fails.
+
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub struct AuditLog {
+    file: BufWriter<fs::File>,
+    /// Number of lines written between flushes
+    flush_every: u64,
+    last_hash: String,
+    path: PathBuf,
+    /// Lines written since the last flush
+    pending: u64,
+    /// Tags of the run added to every line, empty without --tag
+    tags: String,
+}
+
+impl AuditLog {
+    /// Opens or creates the record, continuing the chain of the existing lines
+    pub fn open(path: &Path, flush_every: u64, tags: String) -> Result<AuditLog> {
+        // The state directory is created on first use
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Unable to create the directory {}", dir.display()))?;
+        }
+
+        let file = fs::File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Unable to open the audit file {}", path.display()))?;
+
+        let mut last_line = None;
+        for line in BufReader::new(&file).lines() {
+            let line =
+                line.with_context(|| format!("Unable to read the audit file {}", path.display()))?;
+            if !line.is_empty() {
+                last_line = Some(line);
+            }
+        }
+
+        let last_hash = match last_line {
+            Some(line) => match line.rsplit_once('\t') {
+                Some((_, hash)) if hash.len() == GENESIS_HASH.len() => hash.to_string(),
+                _ => bail!(
+                    "The last line of the audit file {} has no hash, it may have been altered",
+                    path.display()
+                ),
+            },
+            None => String::from(GENESIS_HASH),
+        };
+
+        Ok(AuditLog {
+            file: BufWriter::new(file),
+            flush_every,
+            last_hash,
+            path: path.to_path_buf(),
+            pending: 0,
+            tags,
+        })
+    }
+
+    /// Appends the removed entry, flushed once enough entries are pending
+    pub fn record(&mut self, path: &Path, size: u64) {
+        let mut fields = format!(
+            "{}\t{}\t{}",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            size,
+            path.display()
+        );
+        if !self.tags.is_empty() {
+            fields.push('\t');
+            fields.push_str(&self.tags);
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.last_hash.as_bytes());
+        hasher.update(b"\t");
+        hasher.update(fields.as_bytes());
+        let hash = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        // The chain continues from the last line actually written
+        match writeln!(self.file, "{}\t{}", fields, hash) {
+            Ok(()) => {
+                self.last_hash = hash;
+                self.pending += 1;
+            }
+            Err(err) => {
+                self.write_failed(&err);
+                return;
+            }
+        }
+
+        if self.pending >= self.flush_every {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.file.flush() {
+            self.write_failed(&err);
+        }
+        self.pending = 0;
+    }
+
+    fn write_failed(&self, err: &std::io::Error) {
+        error!(
+            "Unable to write to the audit file {}: {}",
+            self.path.display(),
+            err
+        );
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        if self.pending > 0 {
+            self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    /// Recomputes the chain as documented, returning the hash of the last line if every
+    /// line matches
+    fn verify_chain(contents: &str) -> Option<String> {
+        let mut last_hash = String::from(GENESIS_HASH);
+
+        for line in contents.lines() {
+            let (fields, hash) = line.rsplit_once('\t')?;
+            let expected = Sha256::digest(format!("{}\t{}", last_hash, fields))
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>();
+            if hash != expected {
+                return None;
+            }
+            last_hash = expected;
+        }
+
+        Some(last_hash)
+    }
+
+    #[test]
+    fn records_are_chained() {
+        let dir = temp_dir("audit");
+        let path = dir.join("audit.log");
+
+        let mut audit = AuditLog::open(&path, 1, String::new()).unwrap();
+        audit.record(Path::new(r"C:\Temp\a.tmp"), 10);
+        audit.record(Path::new(r"C:\Temp\b.tmp"), 20);
+        drop(audit);
+
+        // Reopening continues the chain
+        let mut audit = AuditLog::open(&path, 1, String::from("site=paris")).unwrap();
+        audit.record(Path::new(r"C:\Temp\c.tmp"), 30);
+        let last_hash = audit.last_hash.clone();
+        drop(audit);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].split('\t').nth(2), Some(r"C:\Temp\a.tmp"));
+        assert_eq!(lines[2].split('\t').nth(3), Some("site=paris"));
+        assert_eq!(verify_chain(&contents), Some(last_hash));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn altered_line_breaks_the_chain() {
+        let dir = temp_dir("audit-altered");
+        let path = dir.join("audit.log");
+
+        let mut audit = AuditLog::open(&path, 1, String::new()).unwrap();
+        audit.record(Path::new(r"C:\Temp\a.tmp"), 10);
+        audit.record(Path::new(r"C:\Temp\b.tmp"), 20);
+        drop(audit);
+
+        let contents = fs::read_to_string(&path)
+            .unwrap()
+            .replacen("\t10\t", "\t11\t", 1);
+        assert_eq!(verify_chain(&contents), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn last_line_without_hash_is_rejected() {
+        let dir = temp_dir("audit-truncated");
+        let path = dir.join("audit.log");
+        fs::write(&path, "2022-01-01T00:00:00Z\t10\tC:\\Temp\\a.tmp\n").unwrap();
+
+        assert!(AuditLog::open(&path, 1, String::new()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batched_records_are_all_written() {
+        let dir = temp_dir("audit-batched");
+        let path = dir.join("audit.log");
+        let line_count = || fs::read_to_string(&path).unwrap().lines().count();
+
+        let mut audit = AuditLog::open(&path, 3, String::new()).unwrap();
+        for i in 0..5 {
+            audit.record(Path::new(&format!(r"C:\Temp\{}.tmp", i)), i);
+        }
+        // The last two lines wait for the next batch
+        assert_eq!(line_count(), 3);
+
+        drop(audit);
+        assert_eq!(line_count(), 5);
+        assert!(verify_chain(&fs::read_to_string(&path).unwrap()).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Directories completed by an interrupted run, kept in a state file with one path per line.
+/// Completed directories are appended as soon as they're done so a run can be
+/// resumed after a reboot.
+pub struct Checkpoint {
+    done: HashSet<PathBuf>,
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    /// Reads the state file, which is created if missing
+    pub fn open(path: &Path) -> Result<Checkpoint> {
+        // The state directory is created on first use
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Unable to create the directory {}", dir.display()))?;
+        }
+
+        let file = fs::File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Unable to open the state file {}", path.display()))?;
+
+        let done = BufReader::new(&file)
+            .lines()
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Unable to read the state file {}", path.display()))?
+            .into_iter()
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect::<HashSet<_>>();
+
+        if !done.is_empty() {
+            info!(
+                "Resuming from {}, skipping {} completed directories",
+                path.display(),
+                done.len()
+            );
+        }
+
+        Ok(Checkpoint {
+            done,
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn is_done(&self, path: &Path) -> bool {
+        self.done.contains(path)
+    }
+
+    /// Records the directory as completed, a failed write only means it's cleaned again
+    pub fn mark_done(&mut self, path: &Path) {
+        if let Err(err) = writeln!(self.file, "{}", path.display()) {
+            warn!(
+                "Unable to update the state file {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+
+        self.done.insert(path.to_path_buf());
+    }
+
+    /// Removes the state file once the run completed, so the next one starts over
+    pub fn finish(self) {
+        drop(self.file);
+
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(
+                "Unable to remove the state file {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let dir = temp_dir("checkpoint");
+        let path = dir.join("state").join("checkpoint.txt");
+
+        // The state directory is created on first use
+        let mut checkpoint = Checkpoint::open(&path).unwrap();
+        assert!(dir.join("state").is_dir());
+        assert!(!checkpoint.is_done(Path::new(r"C:\Temp\a")));
+        checkpoint.mark_done(Path::new(r"C:\Temp\a"));
+        checkpoint.mark_done(Path::new(r"C:\Temp\b c"));
+        assert!(checkpoint.is_done(Path::new(r"C:\Temp\a")));
+        drop(checkpoint);
+
+        let resumed = Checkpoint::open(&path).unwrap();
+        assert!(resumed.is_done(Path::new(r"C:\Temp\a")));
+        assert!(resumed.is_done(Path::new(r"C:\Temp\b c")));
+        assert!(!resumed.is_done(Path::new(r"C:\Temp\d")));
+
+        resumed.finish();
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,31 +1,1168 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::ArgMatches;
+use log::LevelFilter;
+use regex::Regex;
 
+use crate::name_date::NameDatePattern;
+use crate::parse_bytes;
+use crate::presets::check_preset;
+use crate::retention::{parse_retention, RetentionTier};
+#[cfg(feature = "task-scheduler")]
+use crate::task::get_task_creation_time;
+
+pub const DEFAULT_DEREFERENCE_COUNT: usize = 8;
+pub const DEFAULT_FAILURE_WARNING: f64 = 50.0;
+pub const DEFAULT_SENTINEL: &str = ".keep";
+
+/// Directories of the Windows installation refused with --abort-on-system-dir, along
+/// with everything within them
+pub const BLOCKED_PATHS: &[&str] = &[
+    r"Program Files",
+    r"Program Files (x86)",
+    r"Windows\Boot",
+    r"Windows\Fonts",
+    r"Windows\SysWOW64",
+    r"Windows\System32",
+    r"Windows\WinSxS",
+];
+
+/// Directories refused with --abort-on-system-dir, though their subdirectories aren't
+pub const BLOCKED_DIRS: &[&str] = &["", "ProgramData", "Users", "Windows"];
+
+/// Timestamp compared by the age filters
+#[derive(Clone, Copy, PartialEq)]
+pub enum AgeBasis {
+    Accessed,
+    Created,
+    Modified,
+}
+
+impl AgeBasis {
+    fn parse(value: &str) -> Result<AgeBasis> {
+        match value {
+            "accessed" => Ok(AgeBasis::Accessed),
+            "created" => Ok(AgeBasis::Created),
+            "modified" => Ok(AgeBasis::Modified),
+            _ => bail!(
+                "Invalid age basis {}, expected created, modified or accessed",
+                value
+            ),
+        }
+    }
+
+    #[cfg(feature = "task-scheduler")]
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgeBasis::Accessed => "accessed",
+            AgeBasis::Created => "created",
+            AgeBasis::Modified => "modified",
+        }
+    }
+
+    /// Reads the selected timestamp from the metadata
+    pub fn time_of(&self, meta: &fs::Metadata) -> io::Result<SystemTime> {
+        match self {
+            AgeBasis::Accessed => meta.accessed(),
+            AgeBasis::Created => meta.created(),
+            AgeBasis::Modified => meta.modified(),
+        }
+    }
+}
+
+/// Units used to print sizes
+#[derive(Clone, Copy, PartialEq)]
+pub enum BytesFormat {
+    /// Powers of 1024: KiB, MiB, ...
+    Iec,
+    /// Powers of 1000: kB, MB, ...
+    Si,
+}
+
+/// Daily window set by --only-between, in minutes since midnight
+#[derive(Clone, Copy)]
+pub struct TimeWindow {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeWindow {
+    /// Parses a window like 02:00-05:00, the end may be on the next day (22:00-04:00)
+    fn parse(value: &str) -> Result<TimeWindow> {
+        let parse_time = |time: &str| -> Option<u32> {
+            let (hours, minutes) = time.trim().split_once(':')?;
+            let hours = hours.parse::<u32>().ok().filter(|x| *x < 24)?;
+            let minutes = minutes.parse::<u32>().ok().filter(|x| *x < 60)?;
+            Some(hours * 60 + minutes)
+        };
+
+        let window = value.split_once('-').and_then(|(start, end)| {
+            Some(TimeWindow {
+                start: parse_time(start)?,
+                end: parse_time(end)?,
+            })
+        });
+
+        match window {
+            Some(window) if window.start != window.end => Ok(window),
+            _ => bail!(
+                "Invalid value {} for --only-between, expected a start and end time like 02:00-05:00",
+                value
+            ),
+        }
+    }
+
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            // Crosses midnight
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
+impl OutputFormat {
+    fn parse(value: &str) -> Result<OutputFormat> {
+        match value {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("Invalid output format {}, expected human or json", value),
+        }
+    }
+}
+
+/// Limit of the scan done first by --dry-run-apply-threshold
+#[derive(Clone, Copy)]
+pub enum ApplyThreshold {
+    Entries(u64),
+    Bytes(u64),
+}
+
+impl ApplyThreshold {
+    /// Parses a number of entries like 1000 or a size with its unit like 5GiB
+    fn parse(value: &str) -> Result<ApplyThreshold> {
+        let threshold = if value.trim().chars().all(|c| c.is_ascii_digit()) {
+            value.trim().parse().map(ApplyThreshold::Entries).ok()
+        } else {
+            parse_bytes(value).map(ApplyThreshold::Bytes).ok()
+        };
+
+        match threshold {
+            Some(threshold) => Ok(threshold),
+            None => bail!(
+                "Invalid value {} for --dry-run-apply-threshold, expected a number of entries \
+                (1000) or a size with its unit (5GiB)",
+                value
+            ),
+        }
+    }
+
+    /// Returns true if the scanned entries reach the threshold
+    pub fn reached(&self, entries: u64, bytes: u64) -> bool {
+        match self {
+            ApplyThreshold::Entries(limit) => entries >= *limit,
+            ApplyThreshold::Bytes(limit) => bytes >= *limit,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            ApplyThreshold::Entries(entries) => entries.to_string(),
+            ApplyThreshold::Bytes(bytes) => format!("{}B", bytes),
+        }
+    }
+}
+
+/// Minimum age of the files with one of the extensions, overriding --created-before
+#[derive(Clone)]
+pub struct ExtensionRule {
+    /// Lowercase without the leading dot, may hold inner dots like tar.gz
+    pub extensions: Vec<String>,
+    pub created_before: Duration,
+}
+
+impl ExtensionRule {
+    /// Parses a rule like tmp,log=7d
+    fn parse(value: &str) -> Result<ExtensionRule> {
+        let (extensions, age) = match value.split_once('=') {
+            Some(rule) => rule,
+            None => bail!(
+                "Invalid extension rule \"{}\", expected comma separated extensions and a duration like tmp,log=7d",
+                value
+            ),
+        };
+
+        let extensions = extensions
+            .split(',')
+            .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+            .filter(|extension| !extension.is_empty())
+            .collect::<Vec<_>>();
+        if extensions.is_empty() {
+            bail!("The extension rule \"{}\" has no extension", value);
+        }
+
+        Ok(ExtensionRule {
+            extensions,
+            created_before: parse_duration_arg("extension-rule", age.trim())?,
+        })
+    }
+
+    #[cfg(feature = "task-scheduler")]
+    pub fn name(&self) -> String {
+        format!(
+            "{}={}",
+            self.extensions.join(","),
+            humantime::format_duration(self.created_before)
+        )
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
+    /// Fails if a target is a system directory, even with --force
+    pub abort_on_system_dir: bool,
+    pub age_basis: AgeBasis,
+    /// Date pattern of the file names, used for the age instead of the timestamps
+    pub age_from_name: Option<NameDatePattern>,
+    /// Prints the --histogram as a bar chart
+    pub age_histogram: bool,
+    /// Hash chained record of the removed entries
+    pub audit: Option<PathBuf>,
+    pub bytes_format: BytesFormat,
+    /// Cleans the Windows Update download cache
+    pub clean_update_cache: bool,
+    pub clean_webcache: bool,
+    /// Prints the summary colored by the health of the run
+    pub color_summary: bool,
+    /// Scan files whose space used by directory is compared instead of cleaning
+    pub compare: Option<(PathBuf, PathBuf)>,
+    /// Files at least this large are quarantined compressed instead of removed
+    pub compress_before_delete: Option<u64>,
+    /// State file used to resume an interrupted run
+    pub continue_from: Option<PathBuf>,
+    pub count_ads: bool,
+    /// Maximum age set by --created-after, files older than it are kept. With both flags
+    /// the shorter duration is since and the longer one is this.
+    pub created_after: Option<Duration>,
+    /// Removes duplicate files regardless of their age
+    pub dedupe: bool,
+    /// File listing the paths to remove instead of walking the directories
+    pub delete_list: Option<PathBuf>,
+    pub delete_on_reboot: bool,
+    pub dereference_count: usize,
+    /// Compares the scan files instead of cleaning
+    pub diff: bool,
+    /// Top level directories whose name doesn't match are kept
+    pub dir_name_pattern: Option<Regex>,
+    /// Size the files of every directory are reduced to, oldest first
+    pub dir_max_size: Option<u64>,
+    /// Prints the troubleshooting checklist instead of cleaning
+    pub doctor: bool,
     pub dry_run: bool,
+    /// Cleans only if a dry run first finds less than the threshold
+    pub dry_run_apply_threshold: Option<ApplyThreshold>,
+    /// Relaunches the process as administrator when not elevated
+    pub elevate: bool,
+    pub empty_only: bool,
+    /// Measures the deletion throughput to estimate the duration of a real run
+    pub estimate_time: bool,
+    pub exclude_busy_dirs: bool,
+    #[cfg(feature = "task-scheduler")]
+    pub exclude_newer_than_task: bool,
+    /// Minimum ages by file extension, checked along with the date filters
+    pub extension_rules: Vec<ExtensionRule>,
+    /// Percentage of failed removals in a directory above which a warning is logged
+    pub failure_warning: f64,
+    /// Command deciding whether each entry is removed
+    pub filter_exec: Option<String>,
+    /// Number of audit records written between flushes, each one if not given
+    pub flush_every: Option<u64>,
+    pub follow_reparse_paths: Vec<PathBuf>,
+    #[cfg(feature = "task-scheduler")]
+    pub force: bool,
+    /// Registers the task again even if installed with the same arguments
+    #[cfg(feature = "task-scheduler")]
+    pub force_reinstall: bool,
+    pub gc_threshold: Option<f64>,
+    pub histogram: Option<OutputFormat>,
+    /// CSV file receiving a row per run
+    pub history: Option<PathBuf>,
+    /// Minimum time since the latest of the creation, modification and access times
+    pub idle: Option<Duration>,
+    pub include_inetcache: bool,
+    /// Cleans the directories on read-only volumes too, skipped otherwise
+    pub include_readonly_volumes: bool,
+    pub include_vs_temp: bool,
     pub install_task: bool,
+    /// Extensions of the files never removed, lowercase without the dot
+    pub keep_extensions: Vec<String>,
+    /// Leaves the Windows Update service running while cleaning its cache
+    pub keep_update_service: bool,
+    /// Reports the removals grouped by top level subdirectory
+    pub limit_depth_report: bool,
+    pub list_presets: bool,
+    /// Gzips the rotated --log segments
+    pub log_compress: bool,
+    /// Level of the --log file, defaults to the terminal level
+    pub log_file_level: Option<LevelFilter>,
+    /// Per-run log files kept when --log is a directory
+    pub log_keep: Option<usize>,
+    /// Set with --log-level, overrides verbose and quiet
+    pub log_level: Option<LevelFilter>,
+    /// Size of the --log file rotated at the start of a run
+    pub log_max_size: Option<u64>,
     pub log_path: Option<String>,
+    /// Prometheus textfile replaced after every run
+    pub metrics_file: Option<PathBuf>,
+    /// Top level directories whose files sum to less are skipped
+    pub min_dir_size: Option<u64>,
+    /// Skips the directories outside of the system volume
+    pub no_cross_volume: bool,
+    /// Skips the profile directories without reading Users
+    pub no_user_profiles: bool,
+    /// Time the ages are measured from instead of the current one, set by --now
+    pub now: Option<SystemTime>,
+    pub only_between: Option<TimeWindow>,
+    /// Format of the --compare report
+    pub output_format: OutputFormat,
+    /// Time after which a metadata read or a removal is abandoned
+    pub op_timeout: Option<Duration>,
+    /// Number of threads cleaning the profile directories
+    pub parallel_users: Option<usize>,
+    /// Keeps the index files of the WinINet cache directories
+    pub preserve_inetcache_index: bool,
+    /// Stops after listing the first entries that would be removed
+    pub preview: Option<u64>,
+    /// Names of the presets whose directories are also cleaned
+    pub presets: Vec<String>,
+    /// Wildcard patterns of the profile directory names cleaned, all if empty
+    pub profiles: Vec<String>,
+    /// Subdirectories never touched, relative to each cleaned directory
+    pub protect_dirs: Vec<PathBuf>,
+    /// Leaves the files loaded by the running processes
+    pub protect_running: bool,
+    /// Directory of the compressed copies made by --compress-before-delete
+    pub quarantine_dir: Option<PathBuf>,
+    /// Age after which the compressed copies are purged, kept forever if not given
+    pub quarantine_ttl: Option<Duration>,
     pub quiet: bool,
+    /// Renames the directories that can't be removed yet for a later run
+    pub rename_pending: bool,
+    /// Prints the free space of the volumes before and after cleaning
+    pub report_free_space: bool,
+    /// Prints the skipped entries of every directory by reason
+    pub report_skipped: bool,
+    /// Tiers of the --retention policy, empty if not given
+    pub retention: Vec<RetentionTier>,
+    /// Host cleaned through its administrative share, sets root
+    pub remote: Option<String>,
+    /// Root of an offline Windows installation to clean instead of the running one
+    pub root: Option<PathBuf>,
+    /// Snapshot of the files written after cleaning
+    pub scan_after: Option<PathBuf>,
+    /// Snapshot of the files written before cleaning
+    pub scan_before: Option<PathBuf>,
+    /// Prints the script installing the task instead of running it, implies install_task
+    #[cfg(feature = "task-scheduler")]
+    pub show_task_script: bool,
+    /// Prints the directories that would be cleaned and exits
+    pub show_targets: bool,
+    /// Name of the file protecting the directory containing it
+    pub sentinel: String,
     pub since: Option<Duration>,
+    /// Marker file given to --created-before, whose age is since
+    pub since_marker: Option<PathBuf>,
+    /// Checks whether each entry could be removed, implies dry_run
+    pub simulate_permissions: bool,
+    /// Files with the offline or recall attributes are left alone
+    pub skip_cloud: bool,
+    /// The profile directories are skipped if Storage Sense ran more recently
+    pub skip_if_storage_sense_ran_within: Option<Duration>,
+    /// File receiving the summary lines, independent of the log
+    pub summary_file: Option<PathBuf>,
+    /// Key-value pairs annotating the run, sorted by key
+    pub tags: BTreeMap<String, String>,
+    /// Installation time of the scheduled task, set by --exclude-newer-than-task
+    pub task_created: Option<SystemTime>,
+    /// CPU time allowed to the PowerShell process installing the task
+    #[cfg(feature = "task-scheduler")]
+    pub task_install_cpu_limit: Option<Duration>,
+    /// Memory allowed to the PowerShell process installing the task
+    #[cfg(feature = "task-scheduler")]
+    pub task_install_memory_limit: Option<u64>,
+    /// Prints the time spent in each phase
+    pub timings: bool,
+    pub trim_sparse: Option<u64>,
+    /// Set with --update-task, implies install_task
+    #[cfg(feature = "task-scheduler")]
+    pub update_task: bool,
     pub verbose: bool,
+    pub when_above: Option<f64>,
 }
 
 pub fn build_config(matches: &ArgMatches) -> Result<Config> {
-    let since = match matches.value_of("created-before") {
-        Some(value) => Some(humantime::parse_duration(value)?),
+    let now = matches
+        .value_of("now")
+        .map(humantime::parse_rfc3339)
+        .transpose()
+        .context("Invalid value for --now, expected an RFC 3339 time like 2022-01-01T00:00:00Z")?;
+
+    // A @path value is the age of the marker file
+    let since_marker = matches
+        .value_of("created-before")
+        .and_then(|value| value.strip_prefix('@'))
+        .map(PathBuf::from);
+
+    let age_unit = match matches.value_of("age-unit") {
+        Some("days") => Some('d'),
+        Some("hours") => Some('h'),
+        Some(value) => bail!("Invalid age unit {}, expected days or hours", value),
+        None => None,
+    };
+
+    let since = match (matches.value_of("created-before"), &since_marker) {
+        (Some(_), Some(marker)) => Some(marker_age(marker, now)?),
+        (Some(value), None) => Some(parse_duration_arg(
+            "created-before",
+            &with_age_unit(value, age_unit),
+        )?),
+        _ => None,
+    };
+
+    let created_after = match matches.value_of("created-after") {
+        Some(value) => Some(parse_duration_arg(
+            "created-after",
+            &with_age_unit(value, age_unit),
+        )?),
+        _ => None,
+    };
+
+    // Both bounds form the window of ages to remove, whichever of them is the longer
+    let (since, created_after) = match (since, created_after) {
+        (Some(min_age), Some(max_age)) if min_age == max_age => bail!(
+            "--created-before and --created-after are both {}, which leaves nothing to remove",
+            humantime::format_duration(min_age)
+        ),
+        (Some(min_age), Some(max_age)) => (Some(min_age.min(max_age)), Some(min_age.max(max_age))),
+        bounds => bounds,
+    };
+
+    let skip_if_storage_sense_ran_within =
+        match matches.value_of("skip-if-storage-sense-ran-within") {
+            Some(value) => Some(parse_duration_arg(
+                "skip-if-storage-sense-ran-within",
+                value,
+            )?),
+            _ => None,
+        };
+
+    let idle = match matches.value_of("idle") {
+        Some(value) => Some(parse_duration_arg("idle", value)?),
+        _ => None,
+    };
+
+    let op_timeout = match matches.value_of("op-timeout") {
+        Some(value) => Some(parse_duration_arg("op-timeout", value)?),
+        _ => None,
+    };
+
+    let quarantine_ttl = match matches.value_of("quarantine-ttl") {
+        Some(value) => Some(parse_duration_arg("quarantine-ttl", value)?),
         _ => None,
     };
 
-    let config = Config {
-        dry_run: matches.is_present("dry-run"),
-        install_task: matches.is_present("install-task"),
+    let age_basis = match matches.value_of("age-basis") {
+        Some(value) => AgeBasis::parse(value)?,
+        _ => AgeBasis::Created,
+    };
+
+    let age_from_name = matches
+        .value_of("age-from-name")
+        .map(NameDatePattern::parse)
+        .transpose()?;
+
+    let bytes_format = match matches.value_of("bytes-format") {
+        Some("si") => BytesFormat::Si,
+        _ => BytesFormat::Iec,
+    };
+
+    let dereference_count = matches
+        .value_of("dereference-count")
+        .map(|value| value.parse())
+        .transpose()
+        .context("Invalid value for --dereference-count")?
+        .unwrap_or(DEFAULT_DEREFERENCE_COUNT);
+
+    let age_histogram = matches.is_present("age-histogram");
+
+    let output_format = match matches.value_of("output-format") {
+        Some(value) => OutputFormat::parse(value)?,
+        None => OutputFormat::Human,
+    };
+
+    let histogram = match matches.value_of("histogram") {
+        Some(value) => Some(OutputFormat::parse(value)?),
+        None if age_histogram => Some(OutputFormat::Human),
+        None => None,
+    };
+
+    // The task can't be read while installing it
+    #[cfg(feature = "task-scheduler")]
+    let exclude_newer_than_task = matches.is_present("exclude-newer-than-task");
+    #[cfg(feature = "task-scheduler")]
+    let task_created = if exclude_newer_than_task
+        && !matches.is_present("install-task")
+        && !matches.is_present("update-task")
+        && !matches.is_present("all-ages")
+    {
+        Some(get_task_creation_time(clap::crate_name!())?)
+    } else {
+        None
+    };
+    #[cfg(not(feature = "task-scheduler"))]
+    let task_created = None;
+
+    #[cfg(feature = "task-scheduler")]
+    let task_install_cpu_limit = match matches.value_of("task-install-cpu-limit") {
+        Some(value) => Some(parse_duration_arg("task-install-cpu-limit", value)?),
+        _ => None,
+    };
+    #[cfg(feature = "task-scheduler")]
+    let task_install_memory_limit = matches
+        .value_of("task-install-memory-limit")
+        .map(parse_bytes)
+        .transpose()
+        .context("Invalid value for --task-install-memory-limit")?;
+
+    let log_level = parse_level_arg(matches, "log-level")?;
+    let log_file_level = parse_level_arg(matches, "log-file-level")?;
+
+    let log_max_size = matches
+        .value_of("log-max-size")
+        .map(parse_bytes)
+        .transpose()
+        .context("Invalid value for --log-max-size")?;
+
+    if log_max_size.is_some() && !matches.is_present("log") {
+        bail!("--log-max-size requires --log");
+    }
+
+    let log_keep = matches
+        .value_of("log-keep")
+        .map(|value| value.parse::<usize>())
+        .transpose()
+        .context("Invalid value for --log-keep")?;
+
+    if log_keep == Some(0) {
+        bail!("--log-keep must be at least 1");
+    }
+
+    if log_keep.is_some() && !matches.is_present("log") {
+        bail!("--log-keep requires --log");
+    }
+
+    if let Some(host) = matches.value_of("remote") {
+        if host.is_empty() || host.contains(['\\', '/']) {
+            bail!(
+                "Invalid host {} for --remote, expected a name or an address",
+                host
+            );
+        }
+
+        for name in ["root", "delete-on-reboot"] {
+            if matches.is_present(name) {
+                bail!("--remote can't be combined with --{}", name);
+            }
+        }
+    }
+
+    if matches.is_present("log-compress") && log_max_size.is_none() {
+        bail!("--log-compress requires --log-max-size");
+    }
+
+    let only_between = matches
+        .value_of("only-between")
+        .map(TimeWindow::parse)
+        .transpose()?;
+
+    let presets = matches
+        .values_of("preset")
+        .map(|values| values.map(String::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    for preset in &presets {
+        check_preset(preset)?;
+    }
+
+    let tags = parse_tags(matches)?;
+    let protect_dirs = parse_protect_dirs(matches)?;
+
+    let profiles = matches
+        .values_of("profile")
+        .map(|values| {
+            values
+                .map(|value| value.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if let Some(pattern) = profiles
+        .iter()
+        .find(|pattern| pattern.is_empty() || pattern.contains(['\\', '/']))
+    {
+        bail!(
+            "Invalid profile pattern \"{}\", expected a directory name like svc_*",
+            pattern
+        );
+    }
+
+    // Relative state files are within the state directory, absolute ones are kept
+    let state_dir = match matches.value_of("state-dir") {
+        Some(path) => PathBuf::from(path),
+        None => default_state_dir(),
+    };
+
+    let keep_extensions = matches
+        .value_of("keep-extensions")
+        .map(|value| {
+            value
+                .split(',')
+                .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+                .filter(|extension| !extension.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let dir_name_pattern = matches
+        .value_of("dir-name-pattern")
+        .map(Regex::new)
+        .transpose()
+        .context("Invalid regular expression for --dir-name-pattern")?;
+
+    let preview = matches
+        .value_of("preview")
+        .map(|value| value.parse())
+        .transpose()
+        .context("Invalid value for --preview")?;
+
+    let retention = match matches.value_of("retention") {
+        Some(spec) => parse_retention(spec)?,
+        _ => vec![],
+    };
+
+    let dir_max_size = matches
+        .value_of("dir-max-size")
+        .map(parse_bytes)
+        .transpose()
+        .context("Invalid value for --dir-max-size")?;
+
+    let min_dir_size = matches
+        .value_of("min-dir-size")
+        .map(parse_bytes)
+        .transpose()
+        .context("Invalid value for --min-dir-size")?;
+
+    let parallel_users = matches
+        .value_of("parallel-users")
+        .map(|value| value.parse::<usize>())
+        .transpose()
+        .context("Invalid value for --parallel-users")?;
+
+    if parallel_users == Some(0) {
+        bail!("--parallel-users must be at least 1");
+    }
+
+    // These need a single walk over all the directories
+    if parallel_users.is_some() {
+        for name in ["audit", "continue-from", "preview"] {
+            if matches.is_present(name) {
+                bail!("--parallel-users can't be used with --{}", name);
+            }
+        }
+    }
+
+    if matches.is_present("preserve-inetcache-index") && !matches.is_present("include-inetcache") {
+        bail!("--preserve-inetcache-index requires --include-inetcache");
+    }
+
+    if matches.is_present("no-user-profiles") {
+        for name in [
+            "clean-webcache",
+            "include-inetcache",
+            "include-vs-temp",
+            "profile",
+        ] {
+            if matches.is_present(name) {
+                bail!(
+                    "--no-user-profiles can't be combined with --{}, which cleans the user profiles",
+                    name
+                );
+            }
+        }
+    }
+
+    if matches.is_present("keep-update-service-running")
+        && !matches.is_present("clean-windows-update-cache")
+    {
+        bail!("--keep-update-service-running requires --clean-windows-update-cache");
+    }
+
+    let flush_every = matches
+        .value_of("flush-every")
+        .map(|value| value.parse::<u64>())
+        .transpose()
+        .context("Invalid value for --flush-every")?;
+
+    if flush_every == Some(0) {
+        bail!("--flush-every must be at least 1");
+    }
+
+    if flush_every.is_some() && !matches.is_present("audit") {
+        bail!("--flush-every requires --audit");
+    }
+
+    if matches.is_present("diff")
+        && !(matches.is_present("scan-before") && matches.is_present("scan-after"))
+    {
+        bail!("--diff requires --scan-before and --scan-after");
+    }
+
+    let dry_run_apply_threshold = matches
+        .value_of("dry-run-apply-threshold")
+        .map(ApplyThreshold::parse)
+        .transpose()?;
+
+    let trim_sparse = matches
+        .value_of("trim-sparse")
+        .map(parse_bytes)
+        .transpose()
+        .context("Invalid value for --trim-sparse")?;
+
+    let compress_before_delete = matches
+        .value_of("compress-before-delete")
+        .map(parse_bytes)
+        .transpose()
+        .context("Invalid value for --compress-before-delete")?;
+
+    if compress_before_delete.is_some() && !matches.is_present("quarantine-dir") {
+        bail!("--compress-before-delete requires --quarantine-dir");
+    }
+
+    if quarantine_ttl.is_some() && !matches.is_present("quarantine-dir") {
+        bail!("--quarantine-ttl requires --quarantine-dir");
+    }
+
+    let extension_rules = matches
+        .values_of("extension-rule")
+        .into_iter()
+        .flatten()
+        .map(ExtensionRule::parse)
+        .collect::<Result<Vec<_>>>()?;
+
+    let failure_warning =
+        parse_percent_arg(matches, "failure-warning")?.unwrap_or(DEFAULT_FAILURE_WARNING);
+    let gc_threshold = parse_percent_arg(matches, "gc-threshold")?;
+    let when_above = parse_percent_arg(matches, "when-above")?;
+
+    let mut config = Config {
+        abort_on_system_dir: matches.is_present("abort-on-system-dir"),
+        age_basis,
+        age_from_name,
+        age_histogram,
+        audit: matches.value_of("audit").map(|path| state_dir.join(path)),
+        bytes_format,
+        clean_update_cache: matches.is_present("clean-windows-update-cache"),
+        clean_webcache: matches.is_present("clean-webcache"),
+        color_summary: matches.is_present("color-summary-by-severity"),
+        compare: matches.values_of("compare").and_then(|mut values| {
+            Some((PathBuf::from(values.next()?), PathBuf::from(values.next()?)))
+        }),
+        compress_before_delete,
+        continue_from: matches
+            .value_of("continue-from")
+            .map(|path| state_dir.join(path)),
+        count_ads: matches.is_present("count-ads"),
+        created_after,
+        dedupe: matches.is_present("dedupe"),
+        delete_list: matches.value_of("delete-list").map(PathBuf::from),
+        delete_on_reboot: matches.is_present("delete-on-reboot"),
+        dereference_count,
+        diff: matches.is_present("diff"),
+        dir_max_size,
+        dir_name_pattern,
+        doctor: matches.is_present("doctor"),
+        dry_run: matches.is_present("dry-run")
+            || matches.is_present("simulate-permissions")
+            || preview.is_some()
+            || age_histogram
+            || matches.is_present("dry-run-estimate-time"),
+        dry_run_apply_threshold,
+        elevate: matches.is_present("elevate"),
+        empty_only: matches.is_present("empty-only"),
+        estimate_time: matches.is_present("dry-run-estimate-time"),
+        exclude_busy_dirs: matches.is_present("exclude-busy-dirs"),
+        #[cfg(feature = "task-scheduler")]
+        exclude_newer_than_task,
+        extension_rules,
+        failure_warning,
+        filter_exec: matches.value_of("filter-exec").map(|x| x.to_string()),
+        flush_every,
+        follow_reparse_paths: matches
+            .values_of("follow-reparse-path")
+            .map(|values| values.map(PathBuf::from).collect())
+            .unwrap_or_default(),
+        #[cfg(feature = "task-scheduler")]
+        force: matches.is_present("force"),
+        #[cfg(feature = "task-scheduler")]
+        force_reinstall: matches.is_present("force-reinstall"),
+        gc_threshold,
+        histogram,
+        history: matches.value_of("history").map(PathBuf::from),
+        idle,
+        include_inetcache: matches.is_present("include-inetcache"),
+        include_readonly_volumes: matches.is_present("include-readonly-volumes"),
+        include_vs_temp: matches.is_present("include-vs-temp"),
+        install_task: cfg!(feature = "task-scheduler")
+            && (matches.is_present("install-task")
+                || matches.is_present("update-task")
+                || matches.is_present("show-task-script")),
         quiet: matches.is_present("quiet"),
+        rename_pending: matches.is_present("rename-pending"),
+        report_free_space: matches.is_present("report-free-space-before-after"),
+        report_skipped: matches.is_present("report-skipped"),
+        retention,
+        remote: matches.value_of("remote").map(String::from),
+        root: match matches.value_of("remote") {
+            // The administrative share of the system drive
+            Some(host) => Some(PathBuf::from(format!(r"\\{}\C$", host))),
+            None => matches.value_of("root").map(PathBuf::from),
+        },
+        scan_after: matches.value_of("scan-after").map(PathBuf::from),
+        scan_before: matches.value_of("scan-before").map(PathBuf::from),
         verbose: matches.is_present("verbose"),
+        keep_extensions,
+        keep_update_service: matches.is_present("keep-update-service-running"),
+        limit_depth_report: matches.is_present("limit-depth-report"),
+        list_presets: matches.is_present("list-presets"),
+        log_file_level,
+        log_level,
         log_path: matches.value_of("log").map(|x| x.to_string()),
+        log_compress: matches.is_present("log-compress"),
+        log_keep,
+        log_max_size,
+        metrics_file: matches.value_of("metrics-file").map(PathBuf::from),
+        min_dir_size,
+        no_cross_volume: matches.is_present("no-cross-volume"),
+        no_user_profiles: matches.is_present("no-user-profiles"),
+        now,
+        only_between,
+        output_format,
+        op_timeout,
+        parallel_users,
+        preserve_inetcache_index: matches.is_present("preserve-inetcache-index"),
+        preview,
+        presets,
+        profiles,
+        protect_dirs,
+        protect_running: matches.is_present("protect-running"),
+        quarantine_dir: matches
+            .value_of("quarantine-dir")
+            .map(|path| state_dir.join(path)),
+        quarantine_ttl,
+        show_targets: matches.is_present("show-targets"),
+        sentinel: matches
+            .value_of("sentinel")
+            .unwrap_or(DEFAULT_SENTINEL)
+            .to_string(),
         since,
+        since_marker,
+        simulate_permissions: matches.is_present("simulate-permissions"),
+        skip_cloud: matches.is_present("skip-cloud"),
+        #[cfg(feature = "task-scheduler")]
+        show_task_script: matches.is_present("show-task-script"),
+        skip_if_storage_sense_ran_within,
+        summary_file: matches.value_of("summary-file").map(PathBuf::from),
+        tags,
+        task_created,
+        #[cfg(feature = "task-scheduler")]
+        task_install_cpu_limit,
+        #[cfg(feature = "task-scheduler")]
+        task_install_memory_limit,
+        timings: matches.is_present("timings"),
+        trim_sparse,
+        #[cfg(feature = "task-scheduler")]
+        update_task: matches.is_present("update-task"),
+        when_above,
     };
 
+    // Precedence: --all-ages overrides every age threshold given along with it, the
+    // size, name and other filters still apply
+    if matches.is_present("all-ages") {
+        config.created_after = None;
+        config.extension_rules.clear();
+        config.idle = None;
+        config.since = None;
+        config.since_marker = None;
+        config.task_created = None;
+        #[cfg(feature = "task-scheduler")]
+        {
+            config.exclude_newer_than_task = false;
+        }
+    }
+
     Ok(config)
 }
+
+/// Returns the time elapsed since the creation of the marker file, which must exist
+fn marker_age(marker: &Path, now: Option<SystemTime>) -> Result<Duration> {
+    let created = fs::metadata(marker)
+        .and_then(|meta| meta.created())
+        .with_context(|| {
+            format!(
+                "Unable to read the creation time of the --created-before marker {}",
+                marker.display()
+            )
+        })?;
+
+    // A marker created in the future leaves nothing older
+    Ok(now
+        .unwrap_or_else(SystemTime::now)
+        .duration_since(created)
+        .unwrap_or_default())
+}
+
+/// Returns the state directory within ProgramData, named after the executable
+fn default_state_dir() -> PathBuf {
+    let program_data = env::var("ProgramData").unwrap_or_else(|_| String::from(r"C:\ProgramData"));
+    Path::new(&program_data).join(clap::crate_name!())
+}
+
+/// Parses the value of a percentage flag, the % sign is optional
+fn parse_percent_arg(matches: &ArgMatches, name: &str) -> Result<Option<f64>> {
+    let percent = matches
+        .value_of(name)
+        .map(|value| value.trim_end_matches('%').parse::<f64>())
+        .transpose()
+        .with_context(|| format!("Invalid value for --{}", name))?;
+
+    if let Some(percent) = percent {
+        if !(0.0..=100.0).contains(&percent) {
+            bail!("--{} must be a percentage between 0 and 100", name);
+        }
+    }
+
+    Ok(percent)
+}
+
+/// Parses the value of a log level flag
+fn parse_level_arg(matches: &ArgMatches, name: &str) -> Result<Option<LevelFilter>> {
+    let level = match matches.value_of(name) {
+        Some("error") => LevelFilter::Error,
+        Some("warn") => LevelFilter::Warn,
+        Some("info") => LevelFilter::Info,
+        Some("debug") => LevelFilter::Debug,
+        Some("trace") => LevelFilter::Trace,
+        Some(value) => bail!(
+            "Invalid value {} for --{}, expected error, warn, info, debug or trace",
+            value,
+            name
+        ),
+        None => return Ok(None),
+    };
+
+    Ok(Some(level))
+}
+
+/// Appends the --age-unit to a bare number, other values are returned as they are
+fn with_age_unit(value: &str, unit: Option<char>) -> String {
+    let value = value.trim();
+
+    match unit {
+        Some(unit) if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) => {
+            format!("{}{}", value, unit)
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Parses the value of a duration flag, errors name the flag and show the accepted formats
+fn parse_duration_arg(name: &str, value: &str) -> Result<Duration> {
+    humantime::parse_duration(value).with_context(|| {
+        format!(
+            "Invalid duration \"{}\" for --{}, expected a number followed by a unit \
+            (60s, 10m, 10h, 10d, 10days 2min, etc...)",
+            value, name
+        )
+    })
+}
+
+/// Parses the repeated --tag key=value pairs, a repeated key keeps the last value.
+/// Commas and control characters are rejected since the tags are joined on one line.
+fn parse_tags(matches: &ArgMatches) -> Result<BTreeMap<String, String>> {
+    let mut tags = BTreeMap::new();
+
+    for tag in matches.values_of("tag").into_iter().flatten() {
+        let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+        let (key, value) = (key.trim(), value.trim());
+
+        if key.is_empty() || value.is_empty() {
+            bail!(
+                "Invalid tag \"{}\", expected a key and a value like environment=production",
+                tag
+            );
+        }
+
+        if tag.contains(|c: char| c == ',' || c.is_control()) {
+            bail!(
+                "Invalid tag \"{}\", commas and control characters aren't allowed",
+                tag
+            );
+        }
+
+        tags.insert(key.to_string(), value.to_string());
+    }
+
+    Ok(tags)
+}
+
+/// Parses the repeated --protect-dir paths, which must stay within the cleaned directory
+fn parse_protect_dirs(matches: &ArgMatches) -> Result<Vec<PathBuf>> {
+    matches
+        .values_of("protect-dir")
+        .into_iter()
+        .flatten()
+        .map(|value| {
+            let path = PathBuf::from(value.trim_matches('\\'));
+            let escapes = path
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)));
+
+            if value.trim_matches('\\').is_empty() || escapes {
+                bail!(
+                    "Invalid protected directory \"{}\", expected a relative path like MyApp\\Cache",
+                    value
+                );
+            }
+
+            // Separators are normalized for the comparisons with the walked paths
+            Ok(path.components().collect())
+        })
+        .collect()
+}
+
+/// Joins the tags as key=value pairs
+pub fn format_tags(tags: &BTreeMap<String, String>, separator: &str) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, try_config};
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    #[test]
+    fn created_window_in_either_order() {
+        for args in [
+            ["--created-before", "30d", "--created-after", "7d"],
+            ["--created-before", "7d", "--created-after", "30d"],
+        ] {
+            let config = config(&args);
+            assert_eq!(config.since, Some(Duration::from_secs(7 * DAY)));
+            assert_eq!(config.created_after, Some(Duration::from_secs(30 * DAY)));
+        }
+    }
+
+    #[test]
+    fn created_window_of_equal_bounds_is_rejected() {
+        assert!(try_config(&["--created-before", "7d", "--created-after", "7d"]).is_err());
+    }
+
+    #[test]
+    fn age_unit_applies_to_bare_numbers() {
+        assert_eq!(with_age_unit("7", Some('d')), "7d");
+        assert_eq!(with_age_unit(" 12 ", Some('h')), "12h");
+        assert_eq!(with_age_unit("7d", Some('h')), "7d");
+        assert_eq!(with_age_unit("7", None), "7");
+        assert_eq!(with_age_unit("", Some('d')), "");
+
+        assert_eq!(
+            config(&["--created-before", "7", "--age-unit", "days"]).since,
+            config(&["--created-before", "7d"]).since
+        );
+        assert!(try_config(&["--created-before", "7", "--age-unit", "weeks"]).is_err());
+    }
+
+    #[test]
+    fn invalid_duration_names_the_flag() {
+        let err = try_config(&["--created-before", "10x"]).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Invalid duration \"10x\" for --created-before, expected a number followed by a unit \
+            (60s, 10m, 10h, 10d, 10days 2min, etc...)"
+        );
+
+        let err = try_config(&["--idle", "soon"]).err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid duration \"soon\" for --idle,"));
+    }
+
+    #[test]
+    fn state_files_are_within_the_state_dir() {
+        let custom = config(&[
+            "--state-dir",
+            r"D:\State",
+            "--audit",
+            "audit.log",
+            "--continue-from",
+            r"C:\Runs\checkpoint.txt",
+        ]);
+        assert_eq!(custom.audit, Some(PathBuf::from(r"D:\State\audit.log")));
+        assert_eq!(
+            custom.continue_from,
+            Some(PathBuf::from(r"C:\Runs\checkpoint.txt"))
+        );
+
+        let default = config(&["--audit", "audit.log"]);
+        assert_eq!(default.audit, Some(default_state_dir().join("audit.log")));
+        assert!(default_state_dir().ends_with(clap::crate_name!()));
+    }
+
+    #[test]
+    fn protect_dirs_stay_within_the_directory() {
+        assert_eq!(
+            config(&["--protect-dir", r"\Vendor\Cache\"]).protect_dirs,
+            vec![PathBuf::from(r"Vendor\Cache")]
+        );
+        assert!(try_config(&["--protect-dir", r"..\Vendor"]).is_err());
+        assert!(try_config(&["--protect-dir", r"C:\Vendor"]).is_err());
+        assert!(try_config(&["--protect-dir", r"\"]).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Returns the files whose contents are identical to another file of the list, the
+/// first path in order of each set of copies is kept. Empty files are never duplicates.
+pub fn duplicate_files(files: &[(PathBuf, u64)]) -> HashSet<PathBuf> {
+    let mut by_size: HashMap<u64, Vec<&PathBuf>> = HashMap::new();
+    for (path, size) in files {
+        if *size > 0 {
+            by_size.entry(*size).or_default().push(path);
+        }
+    }
+
+    let mut duplicates = HashSet::new();
+
+    // Only files of the same size are hashed
+    for mut paths in by_size.into_values().filter(|paths| paths.len() > 1) {
+        paths.sort();
+
+        // Kept copies by hash, compared byte by byte in case of collisions
+        let mut kept: HashMap<u64, Vec<&PathBuf>> = HashMap::new();
+
+        for path in paths {
+            let hash = match hash_file(path) {
+                Ok(hash) => hash,
+                Err(err) => {
+                    debug!("Can't hash {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+
+            let copies = kept.entry(hash).or_default();
+            if copies
+                .iter()
+                .any(|copy| same_contents(copy, path).unwrap_or(false))
+            {
+                duplicates.insert(path.clone());
+            } else {
+                copies.push(path);
+            }
+        }
+    }
+
+    duplicates
+}
+
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut hasher = DefaultHasher::new();
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buffer[..read]);
+    }
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = fs::File::open(a)?;
+    let mut b = fs::File::open(b)?;
+    let mut buffer_a = vec![0u8; BUFFER_SIZE];
+    let mut buffer_b = vec![0u8; BUFFER_SIZE];
+
+    loop {
+        let read = a.read(&mut buffer_a)?;
+        if read == 0 {
+            // Same size, unless one of them changed meanwhile
+            return Ok(b.read(&mut buffer_b)? == 0);
+        }
+
+        b.read_exact(&mut buffer_b[..read])?;
+        if buffer_a[..read] != buffer_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn only_files_of_the_same_size_are_compared() {
+        let dir = temp_dir("dedupe-sizes");
+        let files = ["a.tmp", "b.tmp", "c.tmp", "d.tmp"].map(|name| dir.join(name));
+        fs::write(&files[0], b"content").unwrap();
+        fs::write(&files[1], b"content").unwrap();
+        fs::write(&files[2], b"").unwrap();
+        fs::write(&files[3], b"").unwrap();
+
+        // Identical files listed with different sizes are never hashed
+        let duplicates = duplicate_files(&[(files[0].clone(), 7), (files[1].clone(), 8)]);
+        assert!(duplicates.is_empty());
+
+        // Nor are the empty files
+        let duplicates = duplicate_files(&[(files[2].clone(), 0), (files[3].clone(), 0)]);
+        assert!(duplicates.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn identical_contents_are_duplicates() {
+        let dir = temp_dir("dedupe-contents");
+        let files = ["a.tmp", "b.tmp", "c.tmp"].map(|name| dir.join(name));
+        fs::write(&files[0], b"content").unwrap();
+        fs::write(&files[1], b"CONTENT").unwrap();
+        fs::write(&files[2], b"content").unwrap();
+
+        let sizes = files
+            .iter()
+            .map(|file| (file.clone(), 7))
+            .collect::<Vec<_>>();
+
+        assert_eq!(duplicate_files(&sizes), HashSet::from([files[2].clone()]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_path_in_order_is_kept() {
+        let dir = temp_dir("dedupe-kept");
+        let files = ["a.tmp", "b.tmp", "c.tmp"].map(|name| dir.join(name));
+        for file in &files {
+            fs::write(file, b"content").unwrap();
+        }
+
+        // The order of the list doesn't matter
+        let sizes = files
+            .iter()
+            .rev()
+            .map(|file| (file.clone(), 7))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            duplicate_files(&sizes),
+            HashSet::from([files[1].clone(), files[2].clone()])
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
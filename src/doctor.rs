@@ -0,0 +1,238 @@
+//! Troubleshooting checklist printed by --doctor.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use crate::config::Config;
+use crate::output::open_log_file;
+#[cfg(feature = "task-scheduler")]
+use crate::task::get_task_args;
+use crate::windows::{disk_space, is_app_elevated, volume_of};
+
+/// Free percentage of a volume below which the check warns
+const LOW_FREE_PERCENT: f64 = 10.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn name(&self) -> &'static str {
+        match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        }
+    }
+}
+
+struct Check {
+    status: Status,
+    description: String,
+}
+
+impl Check {
+    fn new(status: Status, description: String) -> Check {
+        Check {
+            status,
+            description,
+        }
+    }
+}
+
+/// Prints the checklist, failing if any check failed. The roots are the result of the
+/// discovery of the directories to clean.
+pub fn run_doctor(config: &Config, roots: Result<Vec<PathBuf>>) -> Result<()> {
+    let mut checks = vec![check_elevated(), check_log_file(config)];
+
+    #[cfg(feature = "task-scheduler")]
+    checks.push(check_task());
+
+    match roots {
+        Ok(roots) => {
+            checks.extend(roots.iter().map(|root| check_root(root)));
+            checks.extend(check_free_space(&roots));
+        }
+        Err(err) => checks.push(Check::new(
+            Status::Fail,
+            format!("Discovery of the directories to clean: {:#}", err),
+        )),
+    }
+
+    for check in &checks {
+        println!("[{}] {}", check.status.name(), check.description);
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+
+    Ok(())
+}
+
+fn check_elevated() -> Check {
+    if is_app_elevated() {
+        Check::new(Status::Pass, String::from("Running as administrator"))
+    } else {
+        Check::new(
+            Status::Warn,
+            String::from(
+                "Not running as administrator, the system and other users' directories \
+                can't be fully cleaned",
+            ),
+        )
+    }
+}
+
+fn check_log_file(config: &Config) -> Check {
+    let log_path = match &config.log_path {
+        Some(log_path) => Path::new(log_path),
+        None => return Check::new(Status::Pass, String::from("No log file given")),
+    };
+
+    match open_log_file(log_path) {
+        Ok(_) => Check::new(
+            Status::Pass,
+            format!("Log file {} is writable", log_path.display()),
+        ),
+        Err(err) => Check::new(Status::Fail, format!("{:#}", err)),
+    }
+}
+
+#[cfg(feature = "task-scheduler")]
+fn check_task() -> Check {
+    match get_task_args(clap::crate_name!()) {
+        Ok(Some(args)) => Check::new(
+            Status::Pass,
+            format!("Scheduled task installed with the arguments: {}", args),
+        ),
+        Ok(None) => Check::new(
+            Status::Warn,
+            String::from("Scheduled task not installed (see --install-task)"),
+        ),
+        Err(err) => Check::new(Status::Fail, format!("{:#}", err)),
+    }
+}
+
+fn check_root(root: &Path) -> Check {
+    match fs::read_dir(root) {
+        Ok(_) => Check::new(Status::Pass, format!("{} is readable", root.display())),
+        Err(err) => Check::new(
+            Status::Fail,
+            format!("Unable to read {}: {}", root.display(), err),
+        ),
+    }
+}
+
+/// Returns a check for every volume containing one of the roots
+fn check_free_space(roots: &[PathBuf]) -> Vec<Check> {
+    let mut serials = vec![];
+    let mut checks = vec![];
+
+    for root in roots {
+        // Unreadable directories already failed their own check
+        let volume = match volume_of(root) {
+            Ok(volume) => volume,
+            Err(_) => continue,
+        };
+
+        if serials.contains(&volume.serial) {
+            continue;
+        }
+        serials.push(volume.serial);
+
+        let check = match disk_space(&volume.root) {
+            Ok(space) => {
+                let status = if space.free_percent() < LOW_FREE_PERCENT {
+                    Status::Warn
+                } else {
+                    Status::Pass
+                };
+
+                Check::new(
+                    status,
+                    format!(
+                        "{} has {} of {} bytes free ({:.1}%)",
+                        volume.name(),
+                        space.free,
+                        space.total,
+                        space.free_percent()
+                    ),
+                )
+            }
+            Err(err) => Check::new(
+                Status::Fail,
+                format!(
+                    "Unable to read the free space of {}: {}",
+                    volume.name(),
+                    err
+                ),
+            ),
+        };
+        checks.push(check);
+    }
+
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, temp_dir};
+
+    #[test]
+    fn status_names() {
+        assert_eq!(Status::Pass.name(), "PASS");
+        assert_eq!(Status::Warn.name(), "WARN");
+        assert_eq!(Status::Fail.name(), "FAIL");
+    }
+
+    #[test]
+    fn root_must_be_readable() {
+        let dir = temp_dir("doctor-root");
+
+        assert!(check_root(&dir).status == Status::Pass);
+        assert!(check_root(&dir.join("missing")).status == Status::Fail);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_file_must_be_writable() {
+        let dir = temp_dir("doctor-log");
+        let log_path = dir.join("wintempclean.log");
+
+        assert!(check_log_file(&config(&[])).status == Status::Pass);
+
+        let writable = config(&["--log", &log_path.to_string_lossy()]);
+        assert!(check_log_file(&writable).status == Status::Pass);
+
+        let missing = dir.join("missing").join("wintempclean.log");
+        let unwritable = config(&["--log", &missing.to_string_lossy()]);
+        assert!(check_log_file(&unwritable).status == Status::Fail);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn free_space_is_checked_once_per_volume() {
+        let dir = temp_dir("doctor-space");
+        fs::create_dir(dir.join("sub")).unwrap();
+
+        let checks = check_free_space(&[dir.clone(), dir.join("sub"), dir.join("missing")]);
+
+        assert_eq!(checks.len(), 1);
+        assert!(checks[0].status != Status::Fail);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
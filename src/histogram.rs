@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use crate::config::BytesFormat;
+use crate::format_bytes;
+
+/// Width in characters of the largest bar of the chart
+const CHART_WIDTH: u64 = 40;
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// Labels and upper bounds (in seconds) of the age buckets, the last one has no bound
+const BUCKETS: [(&str, u64); 5] = [
+    ("< 1h", HOUR),
+    ("< 1d", DAY),
+    ("< 7d", 7 * DAY),
+    ("< 30d", 30 * DAY),
+    (">= 30d", u64::MAX),
+];
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    bytes: u64,
+    count: u64,
+}
+
+#[derive(Default)]
+pub struct AgeHistogram {
+    buckets: [Bucket; BUCKETS.len()],
+}
+
+impl AgeHistogram {
+    pub fn record(&mut self, age: Duration, bytes: u64) {
+        let idx = BUCKETS
+            .iter()
+            .position(|(_, limit)| age.as_secs() < *limit)
+            .unwrap_or(BUCKETS.len() - 1);
+
+        self.buckets[idx].bytes += bytes;
+        self.buckets[idx].count += 1;
+    }
+
+    pub fn add(&mut self, histogram: &AgeHistogram) {
+        for (bucket, other) in self.buckets.iter_mut().zip(histogram.buckets.iter()) {
+            bucket.bytes += other.bytes;
+            bucket.count += other.count;
+        }
+    }
+
+    pub fn print(&self, format: BytesFormat) {
+        info!("Files by age:");
+        for ((label, _), bucket) in BUCKETS.iter().zip(self.buckets.iter()) {
+            info!(
+                "  {:>6}: {} files ({})",
+                label,
+                bucket.count,
+                format_bytes(bucket.bytes as f64, format)
+            );
+        }
+    }
+
+    /// Prints the buckets with a bar of their bytes, scaled to the largest one
+    pub fn print_chart(&self, format: BytesFormat) {
+        let largest = self.buckets.iter().map(|x| x.bytes).max().unwrap_or(0);
+
+        info!("Files by age:");
+        for ((label, _), bucket) in BUCKETS.iter().zip(self.buckets.iter()) {
+            let width = match largest {
+                0 => 0,
+                // Any file at all gets at least one character
+                _ => ((bucket.bytes * CHART_WIDTH) / largest).max((bucket.count > 0) as u64),
+            };
+
+            info!(
+                "  {:>6}: {:<width$} {} files ({})",
+                label,
+                "#".repeat(width as usize),
+                bucket.count,
+                format_bytes(bucket.bytes as f64, format),
+                width = CHART_WIDTH as usize
+            );
+        }
+    }
+
+    /// Returns the buckets as a JSON array
+    pub fn to_json(&self) -> String {
+        let buckets = BUCKETS
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|((label, _), bucket)| {
+                format!(
+                    "{{\"age\":\"{}\",\"count\":{},\"bytes\":{}}}",
+                    label, bucket.count, bucket.bytes
+                )
+            })
+            .collect::<Vec<_>>();
+
+        format!("[{}]", buckets.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_buckets_by_age() {
+        let mut histogram = AgeHistogram::default();
+        histogram.record(Duration::from_secs(0), 1);
+        histogram.record(Duration::from_secs(HOUR - 1), 2);
+        histogram.record(Duration::from_secs(HOUR), 4);
+        histogram.record(Duration::from_secs(3 * DAY), 8);
+        histogram.record(Duration::from_secs(7 * DAY), 16);
+        histogram.record(Duration::from_secs(30 * DAY), 32);
+        histogram.record(Duration::from_secs(365 * DAY), 64);
+
+        assert_eq!(
+            histogram.to_json(),
+            "[{\"age\":\"< 1h\",\"count\":2,\"bytes\":3},\
+            {\"age\":\"< 1d\",\"count\":1,\"bytes\":4},\
+            {\"age\":\"< 7d\",\"count\":1,\"bytes\":8},\
+            {\"age\":\"< 30d\",\"count\":1,\"bytes\":16},\
+            {\"age\":\">= 30d\",\"count\":2,\"bytes\":96}]"
+        );
+    }
+
+    #[test]
+    fn add_sums_buckets() {
+        let mut first = AgeHistogram::default();
+        first.record(Duration::from_secs(10), 100);
+        let mut second = AgeHistogram::default();
+        second.record(Duration::from_secs(20), 50);
+        second.record(Duration::from_secs(40 * DAY), 5);
+
+        first.add(&second);
+
+        assert_eq!(first.buckets[0].count, 2);
+        assert_eq!(first.buckets[0].bytes, 150);
+        assert_eq!(first.buckets[4].count, 1);
+        assert_eq!(first.buckets[4].bytes, 5);
+    }
+}
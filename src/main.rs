@@ -1,49 +1,365 @@
 mod app;
+mod audit;
+mod checkpoint;
 mod config;
+mod dedupe;
+mod doctor;
+mod histogram;
+mod name_date;
 mod output;
+mod presets;
+mod quarantine;
+mod retention;
+mod scan;
+#[cfg(feature = "task-scheduler")]
 mod task;
+#[cfg(test)]
+mod testing;
+mod timings;
+mod volume;
 mod windows;
 
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fs;
+use std::os::windows::fs::MetadataExt;
+use std::os::windows::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
 use humantime::format_duration;
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE};
+use winapi::um::winbase::CREATE_NO_WINDOW;
+use winapi::um::wincon::{CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT};
+use winapi::um::winnt::{
+    FILE_ATTRIBUTE_OFFLINE, FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS, FILE_ATTRIBUTE_RECALL_ON_OPEN,
+};
 
 use crate::app::build_app;
-use crate::config::{build_config, Config};
-use crate::output::{init_logger, print_err};
+use crate::audit::AuditLog;
+use crate::checkpoint::Checkpoint;
+use crate::config::{
+    build_config, format_tags, BytesFormat, Config, OutputFormat, BLOCKED_DIRS, BLOCKED_PATHS,
+};
+use crate::dedupe::duplicate_files;
+use crate::doctor::run_doctor;
+use crate::histogram::AgeHistogram;
+use crate::output::{
+    append_history, error_json, flush_log_file, init_logger, json_string, print_err,
+    print_err_json, print_summary, write_metrics_file, write_summary_file, Severity,
+};
+use crate::presets::{preset_directories, print_presets};
+use crate::quarantine::{purge_expired, quarantine_file};
+use crate::retention::retained_files;
+use crate::scan::{compare_stats, print_diff, read_snapshot, take_snapshot, write_snapshot};
+#[cfg(feature = "task-scheduler")]
 use crate::task::install_task;
+use crate::timings::{estimate_deletion_throughput, format_seconds, Timings};
+use crate::volume::{add_volume_stats, VolumeStats};
+use crate::windows::{
+    alternate_streams_size, can_delete, delete_on_reboot, disk_space, file_id,
+    get_storage_sense_last_run, is_app_elevated, is_cancelled_error, is_dir_not_empty_error,
+    is_file_in_use, is_locked_error, is_logon_error, is_process_running, is_unreachable_error,
+    link_count, loaded_module_paths, local_minute_of_day, number_separators, run_elevated,
+    set_console_ctrl_handler, start_service, stop_service, trim_sparse, volume_of,
+    RestartManagerSession, Volume,
+};
 
 #[macro_use]
 extern crate log;
 
+/// Wait before retrying the removal of a directory that wasn't empty
+const DIR_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Download cache of Windows Update, within the Windows installation
+const UPDATE_CACHE_DIR: &str = r"Windows\SoftwareDistribution\Download";
+/// Name of the Windows Update service
+const UPDATE_SERVICE: &str = "wuauserv";
+/// Appended to the directories that couldn't be removed with --rename-pending
+const PENDING_DELETE_SUFFIX: &str = ".pending-delete";
+/// Time the run has to stop after a shutdown or logoff before the process exits
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(4);
+
+/// Set by the console handler when Windows shuts down or the user logs off
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+/// Modules of the running processes, listed on first use by --protect-running
+static RUNNING_MODULES: OnceLock<RunningModules> = OnceLock::new();
+
 struct Stats {
+    busy_dirs_skipped: u64,
+    /// Cloud files left by --skip-cloud
+    cloud_skipped: u64,
+    /// Size of the duplicate files removed by --dedupe
+    deduped_bytes: u64,
+    deduped_count: u64,
+    /// Directories to clean that couldn't be read at all
+    dirs_failed: u64,
+    /// Errors as JSON objects, kept for the report of --histogram json instead of logged
+    errors: Vec<String>,
     errors_total: u64,
+    histogram: AgeHistogram,
     removed_bytes: u64,
     removed_count: u64,
+    removed_dirs: u64,
+    removed_files: u64,
+    removed_symlinks: u64,
+    /// Files loaded by a running process left by --protect-running
+    running_skipped: u64,
+    scheduled_for_reboot: u64,
+    /// Size of the removed files with other hard links, which doesn't free space
+    shared_bytes: u64,
+    /// Entries left by the age filters, reported with --report-skipped
+    skipped_by_age: u64,
+    /// Entries left by their name, like --dir-name-pattern and --keep-extensions
+    skipped_by_pattern: u64,
+    /// Entries left because they're in use by another process
+    skipped_by_permission: u64,
+    /// Removed entries and bytes by top level subdirectory, with --limit-depth-report.
+    /// The files directly within a cleaned directory are under the directory itself.
+    subtotals: HashMap<PathBuf, (u64, u64)>,
+    /// Summary lines of the cleaned directories, kept for --summary-file
+    summary_lines: Vec<String>,
+    /// Filesystem operations abandoned after --op-timeout
+    timed_out: u64,
+    trimmed_bytes: u64,
+    trimmed_count: u64,
 }
 
 impl Stats {
     fn new() -> Stats {
         Stats {
+            busy_dirs_skipped: 0,
+            cloud_skipped: 0,
+            deduped_bytes: 0,
+            deduped_count: 0,
+            dirs_failed: 0,
+            errors: vec![],
             errors_total: 0,
+            histogram: AgeHistogram::default(),
             removed_bytes: 0,
             removed_count: 0,
+            removed_dirs: 0,
+            removed_files: 0,
+            removed_symlinks: 0,
+            running_skipped: 0,
+            scheduled_for_reboot: 0,
+            shared_bytes: 0,
+            skipped_by_age: 0,
+            skipped_by_pattern: 0,
+            skipped_by_permission: 0,
+            subtotals: HashMap::new(),
+            timed_out: 0,
+            summary_lines: vec![],
+            trimmed_bytes: 0,
+            trimmed_count: 0,
         }
     }
+}
 
-    fn add(&mut self, stats: Stats) {
+impl std::ops::AddAssign for Stats {
+    fn add_assign(&mut self, stats: Stats) {
+        self.busy_dirs_skipped += stats.busy_dirs_skipped;
+        self.cloud_skipped += stats.cloud_skipped;
+        self.deduped_bytes += stats.deduped_bytes;
+        self.deduped_count += stats.deduped_count;
+        self.dirs_failed += stats.dirs_failed;
+        self.errors.extend(stats.errors);
         self.errors_total += stats.errors_total;
+        self.histogram.add(&stats.histogram);
         self.removed_bytes += stats.removed_bytes;
         self.removed_count += stats.removed_count;
+        self.removed_dirs += stats.removed_dirs;
+        self.removed_files += stats.removed_files;
+        self.removed_symlinks += stats.removed_symlinks;
+        self.running_skipped += stats.running_skipped;
+        self.scheduled_for_reboot += stats.scheduled_for_reboot;
+        self.shared_bytes += stats.shared_bytes;
+        self.skipped_by_age += stats.skipped_by_age;
+        self.skipped_by_pattern += stats.skipped_by_pattern;
+        self.skipped_by_permission += stats.skipped_by_permission;
+        for (dir, (count, bytes)) in stats.subtotals {
+            let subtotal = self.subtotals.entry(dir).or_default();
+            subtotal.0 += count;
+            subtotal.1 += bytes;
+        }
+        self.summary_lines.extend(stats.summary_lines);
+        self.timed_out += stats.timed_out;
+        self.trimmed_bytes += stats.trimmed_bytes;
+        self.trimmed_count += stats.trimmed_count;
+    }
+}
+
+impl std::ops::Add for Stats {
+    type Output = Stats;
+
+    fn add(mut self, stats: Stats) -> Stats {
+        self += stats;
+        self
+    }
+}
+
+/// One line summary with the sizes in IEC units, like 5 files (1.00 MiB), 0 errors
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files ({}), {} errors",
+            self.removed_files,
+            format_bytes(self.removed_bytes as f64, BytesFormat::Iec),
+            self.errors_total
+        )
+    }
+}
+
+struct WalkState {
+    /// Record of the removed entries, set by --audit
+    audit: Option<AuditLog>,
+    /// Entries that reached removal so far, across all directories
+    candidates: u64,
+    /// Directories completed by a previous run, set by --continue-from
+    checkpoint: Option<Checkpoint>,
+    /// Entries a dry run counted as removed, still on disk for --dir-max-size
+    dry_run_removed: HashSet<PathBuf>,
+    /// Set inside directories already checked for files in use
+    idle: bool,
+    link_depth: usize,
+    /// The --protect-dir subdirectories of the directory being cleaned, and the
+    /// directories with a --sentinel file found so far
+    protected: Vec<PathBuf>,
+    skip_in_use: bool,
+    timings: Timings,
+    /// Top level subdirectory being walked, the key of the --limit-depth-report subtotals
+    top_dir: Option<PathBuf>,
+    visited: HashSet<(u32, u64)>,
+}
+
+impl WalkState {
+    fn new() -> WalkState {
+        WalkState {
+            audit: None,
+            candidates: 0,
+            checkpoint: None,
+            dry_run_removed: HashSet::new(),
+            idle: false,
+            link_depth: 0,
+            protected: vec![],
+            skip_in_use: false,
+            timings: Timings::default(),
+            top_dir: None,
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Returns true once the walk must be aborted
+    fn stopped(&self, config: &Config) -> bool {
+        config.preview.is_some_and(|limit| self.candidates >= limit)
+            || SHUTDOWN.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the entry is one of the --protect-dir subdirectories
+    fn is_protected(&self, path: &Path) -> bool {
+        self.protected.iter().any(|dir| same_path(path, dir))
+    }
+
+    /// Returns true if one of the protected subdirectories is within the directory
+    fn holds_protected(&self, path: &Path) -> bool {
+        self.protected.iter().any(|dir| is_within(dir, path))
+    }
+
+    /// Returns true if the directory was completed by the interrupted run
+    fn is_done(&self, path: &Path) -> bool {
+        self.checkpoint
+            .as_ref()
+            .is_some_and(|checkpoint| checkpoint.is_done(path))
+    }
+
+    /// Records the directory as completed, unless the walk was aborted within it
+    fn mark_done(&mut self, path: &Path, config: &Config) {
+        if self.stopped(config) {
+            return;
+        }
+
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.mark_done(path);
+        }
+    }
+
+    /// Records the directory as visited and returns whether it can be descended into.
+    /// Links are followed only if allowed, within the dereference limit and only if
+    /// their target wasn't visited already, which would mean a cycle.
+    fn enter(&mut self, path: &Path, is_link: bool, config: &Config) -> bool {
+        let id = file_id(path);
+
+        if !is_link {
+            if let Ok(id) = id {
+                self.visited.insert(id);
+            }
+            return true;
+        }
+
+        if !is_allowed_link(path, config) {
+            debug!("Not following link {}", path.display());
+            return false;
+        }
+
+        if self.link_depth >= config.dereference_count {
+            warn!(
+                "Not following link {}: dereference limit of {} reached",
+                path.display(),
+                config.dereference_count
+            );
+            return false;
+        }
+
+        match id {
+            Ok(id) if !self.visited.insert(id) => {
+                warn!(
+                    "Not following link {}: target already visited",
+                    path.display()
+                );
+                false
+            }
+            Ok(_) => {
+                self.link_depth += 1;
+                true
+            }
+            Err(err) => {
+                warn!("Not following link {}: {}", path.display(), err);
+                false
+            }
+        }
     }
 }
 
+/// Returns true if the link was allowed with --follow-reparse-path, the comparison
+/// ignores case and trailing separators like Windows paths do
+fn is_allowed_link(path: &Path, config: &Config) -> bool {
+    let normalize = |path: &Path| path.to_string_lossy().trim_end_matches('\\').to_lowercase();
+    let path = normalize(path);
+
+    config
+        .follow_reparse_paths
+        .iter()
+        .any(|allowed| normalize(allowed) == path)
+}
+
 fn main() {
-    if let Err(err) = try_main() {
-        if log_enabled!(log::Level::Error) {
+    let matches = build_app().get_matches();
+
+    let result = try_main(&matches);
+
+    if SHUTDOWN.load(Ordering::SeqCst) {
+        flush_log_file();
+    }
+
+    if let Err(err) = result {
+        if matches.value_of("error-format") == Some("json") {
+            print_err_json(&err);
+        } else if log_enabled!(log::Level::Error) {
             print_err(err);
         } else {
             eprintln!("{:?}", err);
@@ -52,193 +368,3432 @@ fn main() {
     }
 }
 
-fn try_main() -> Result<()> {
-    let matches = build_app().get_matches();
-    let config = build_config(&matches)?;
+/// Stops the walk when Windows shuts down or the user logs off, the process is ended
+/// as soon as the handler returns so it waits for the run to finish on its own first
+unsafe extern "system" fn shutdown_handler(ctrl_type: DWORD) -> BOOL {
+    match ctrl_type {
+        CTRL_SHUTDOWN_EVENT | CTRL_LOGOFF_EVENT => {
+            SHUTDOWN.store(true, Ordering::SeqCst);
+
+            // Still running, most likely stuck in a removal
+            thread::sleep(SHUTDOWN_GRACE);
+            warn!("Exiting before the run completed");
+            flush_log_file();
+            std::process::exit(1);
+        }
+        // Ctrl+C and the others keep the default handling
+        _ => FALSE,
+    }
+}
+
+fn try_main(matches: &ArgMatches) -> Result<()> {
+    let config = build_config(matches)?;
+
+    // Before the logger, which fails if the log file can't be opened
+    if config.doctor {
+        let roots =
+            get_roots(&config).map(|roots| roots.into_iter().map(|(path, _)| path).collect());
+        return run_doctor(&config, roots);
+    }
 
     init_logger(&config)?;
 
-    if matches.is_present("install-task") {
+    if let Err(err) = set_console_ctrl_handler(Some(shutdown_handler)) {
+        debug!("Unable to handle the shutdown: {}", err);
+    }
+
+    if config.list_presets {
+        print_presets();
+        return Ok(());
+    }
+
+    // Both scan files are required with --diff
+    if let (true, Some(before), Some(after)) =
+        (config.diff, &config.scan_before, &config.scan_after)
+    {
+        let before = read_snapshot(before)?;
+        let after = read_snapshot(after)?;
+        print_diff(&before, &after, config.bytes_format);
+        return Ok(());
+    }
+
+    if let Some((before, after)) = &config.compare {
+        let diff = compare_stats(&read_snapshot(before)?, &read_snapshot(after)?);
+        match config.output_format {
+            OutputFormat::Human => diff.print(config.bytes_format),
+            OutputFormat::Json => println!("{}", diff.to_json()),
+        }
+        return Ok(());
+    }
+
+    if config.show_targets {
+        for (path, _) in get_roots(&config)? {
+            println!("{}", path.display());
+        }
+        return Ok(());
+    }
+
+    if config.elevate && !is_app_elevated() {
+        return relaunch_elevated();
+    }
+
+    #[cfg(feature = "task-scheduler")]
+    if config.install_task {
         return install_task(&config);
     }
 
-    if let Some(duration) = config.since {
-        info!(
-            "Removing temporary files and directories older than {}",
-            format_duration(duration)
-        );
-    } else {
-        info!("Removing all temporary files and directories");
+    // The permissions of an offline image don't apply to the running system
+    if config.delete_on_reboot && config.root.is_none() && !is_app_elevated() {
+        bail!("--delete-on-reboot requires administrator privileges");
+    }
+
+    if let (Some(host), Some(share)) = (&config.remote, &config.root) {
+        check_remote(host, share)?;
+    }
+
+    if let Some(window) = config.only_between {
+        if !window.contains(local_minute_of_day()) {
+            info!("Outside the {} window, nothing to do", window.name());
+            return Ok(());
+        }
+    }
+
+    info!("{}", describe_filters(&config));
+
+    if config.filter_exec.is_some() {
+        warn!("--filter-exec starts a process for every entry to remove, which is slow on large directories");
+    }
+
+    if let Some(threshold) = config.dry_run_apply_threshold {
+        let scanned = scan_before_applying(&config)?;
+
+        if threshold.reached(scanned.removed_count, scanned.removed_bytes) {
+            bail!(
+                "Refusing to clean: the dry run found {} entries ({}), reaching the \
+                --dry-run-apply-threshold of {}",
+                scanned.removed_count,
+                format_bytes(scanned.removed_bytes as f64, config.bytes_format),
+                threshold.name()
+            );
+        }
+
+        info!("The dry run is below the threshold, cleaning");
     }
 
-    begin_cleaning(&config)
+    begin_cleaning(&config).map(|_| ())
 }
 
-fn begin_cleaning(config: &Config) -> Result<()> {
-    for tmp_path in get_temp_directories()? {
-        if tmp_path.exists() {
-            debug!("Cleaning: {:?}", &tmp_path);
+/// Runs the same command line as administrator, exiting with its exit code once it ends
+fn relaunch_elevated() -> Result<()> {
+    let exe = env::current_exe().context("can't find the executable to relaunch")?;
+    let args = env::args_os().skip(1).collect::<Vec<_>>();
 
-            if let Ok(stats) = remove_dir_contents(&tmp_path, config, false) {
-                info!(
-                    "Removed {} entries ({}) with {} errors from path {}",
-                    stats.removed_count,
-                    format_bytes(stats.removed_bytes as f64),
-                    stats.errors_total,
-                    tmp_path.display()
-                );
-            }
+    info!("Relaunching as administrator");
+    debug!("Forwarding the arguments {:?}", args);
+
+    match run_elevated(&exe, &args) {
+        Ok(code) => std::process::exit(code as i32),
+        Err(err) if is_cancelled_error(&err) => {
+            bail!("The administrator prompt was declined, nothing was cleaned")
         }
+        Err(err) => Err(err).context("Unable to relaunch as administrator"),
     }
+}
 
-    Ok(())
+/// Runs the cleaning as a dry run without the options that keep state or print reports,
+/// returning what would be removed
+fn scan_before_applying(config: &Config) -> Result<Stats> {
+    info!("Scanning with a dry run first");
+
+    let mut scan = config.clone();
+    scan.dry_run = true;
+    scan.audit = None;
+    scan.continue_from = None;
+    scan.histogram = None;
+    scan.report_free_space = false;
+    scan.scan_after = None;
+    scan.scan_before = None;
+    scan.summary_file = None;
+    scan.timings = false;
+
+    begin_cleaning(&scan)
 }
 
-fn get_temp_directories() -> Result<Vec<PathBuf>> {
-    let mut dirs = vec![
-        PathBuf::from(r"C:\Windows\Temp"),
-        PathBuf::from(r"C:\ProgramData\Temp"),
-    ];
+/// Describes which entries are removed with the given filters
+fn describe_filters(config: &Config) -> String {
+    if config.empty_only {
+        return String::from("Removing empty temporary files and directories");
+    }
 
-    let users_dirs = fs::read_dir(r"C:\Users")?
-        .into_iter()
-        .map(|x| x.map(|entry| entry.path().join("AppData\\Local\\Temp\\")))
-        .collect::<Result<Vec<_>, _>>()?;
+    let mut filters = vec![];
 
-    dirs.extend(users_dirs);
+    match (config.since, config.created_after) {
+        (Some(min_age), Some(max_age)) => filters.push(format!(
+            "created between {} and {} ago",
+            format_duration(min_age),
+            format_duration(max_age)
+        )),
+        (Some(min_age), None) => filters.push(format!("older than {}", format_duration(min_age))),
+        (None, Some(max_age)) => filters.push(format!("newer than {}", format_duration(max_age))),
+        (None, None) => {}
+    }
 
-    Ok(dirs)
+    if let Some(idle) = config.idle {
+        filters.push(format!("idle for at least {}", format_duration(idle)));
+    }
+
+    if config.task_created.is_some() {
+        filters.push(String::from(
+            "created before the scheduled task was installed",
+        ));
+    }
+
+    if !config.retention.is_empty() {
+        filters.push(String::from("except the newest of each retention period"));
+    }
+
+    for rule in &config.extension_rules {
+        filters.push(format!(
+            "{} files older than {}",
+            rule.extensions.join(", "),
+            format_duration(rule.created_before)
+        ));
+    }
+
+    if filters.is_empty() {
+        String::from("Removing all temporary files and directories")
+    } else {
+        format!(
+            "Removing temporary files and directories {}",
+            filters.join(", ")
+        )
+    }
 }
 
-fn remove_dir_contents(path: &Path, config: &Config, skip_date_check: bool) -> Result<Stats> {
-    let entries =
-        fs::read_dir(path).with_context(|| format!("can't read dir {}", path.display()))?;
+/// Free space of a volume before cleaning, for --report-free-space-before-after
+struct VolumeFreeSpace {
+    free_before: u64,
+    volume: Volume,
+}
 
-    let mut stats = Stats::new();
+/// Reads the free space of every volume containing one of the directories
+fn free_space_before(roots: &[(PathBuf, bool)]) -> Vec<VolumeFreeSpace> {
+    let mut volumes: Vec<VolumeFreeSpace> = vec![];
 
-    // Loop every entry
-    for entry in entries {
-        let entry = entry?;
+    for (path, _) in roots {
+        let volume = match volume_of(path) {
+            Ok(volume) => volume,
+            // Missing directories are skipped anyway
+            Err(_) => continue,
+        };
 
-        let meta = fs::metadata(entry.path())
-            .with_context(|| format!("can't read metadata {}", entry.path().display()));
+        if volumes.iter().any(|x| x.volume.serial == volume.serial) {
+            continue;
+        }
 
-        // Read metadata or report error
-        let meta = match meta {
-            Ok(result) => result,
+        match disk_space(&volume.root) {
+            Ok(space) => volumes.push(VolumeFreeSpace {
+                free_before: space.free,
+                volume,
+            }),
+            Err(err) => debug!("Can't read the free space of {}: {}", volume.name(), err),
+        }
+    }
+
+    volumes
+}
+
+/// Prints the free space of the volumes before and after cleaning next to the bytes
+/// removed from them, other processes may have changed it meanwhile
+fn print_free_space_after(volumes: &[VolumeFreeSpace], totals: &[VolumeStats], config: &Config) {
+    let (thousands, decimal) = number_separators();
+
+    for before in volumes {
+        let free_after = match disk_space(&before.volume.root) {
+            Ok(space) => space.free,
             Err(err) => {
-                stats.errors_total += 1;
-                print_err(err);
+                debug!(
+                    "Can't read the free space of {}: {}",
+                    before.volume.name(),
+                    err
+                );
                 continue;
             }
         };
 
-        // Store size for later
-        let size = meta.len();
+        let removed = totals
+            .iter()
+            .find(|x| x.volume.serial == before.volume.serial)
+            .map_or(0, |x| x.freed_bytes);
 
-        // Don't mind create date if subdir or no duration given
-        if skip_date_check
-            || config.since.is_none()
-            || create_date_older_than_duration(&meta, config.since.unwrap())
-        {
-            // Recurse into subdir and sum stats
-            if meta.is_dir() {
-                // Try remove sub contents
-                match remove_dir_contents(&entry.path(), config, true) {
-                    Ok(sub_stats) => {
-                        // Sum stats
-                        stats.add(sub_stats);
-                    }
-                    Err(err) => {
-                        // Error: return early
-                        stats.errors_total += 1;
-                        print_err(err);
-                        return Ok(stats);
-                    }
-                };
+        info!(
+            "Free space on {}: {}",
+            before.volume.name(),
+            describe_free_space(
+                before.free_before,
+                free_after,
+                removed,
+                config.bytes_format,
+                (&thousands, &decimal)
+            )
+        );
+    }
+}
+
+/// Describes the free space of a volume before and after cleaning, the gain may be
+/// negative if other processes used more space meanwhile
+fn describe_free_space(
+    free_before: u64,
+    free_after: u64,
+    removed: u64,
+    format: BytesFormat,
+    separators: (&str, &str),
+) -> String {
+    format!(
+        "{} before, {} after, {} gained ({} removed)",
+        format_bytes_with(free_before as f64, format, separators),
+        format_bytes_with(free_after as f64, format, separators),
+        format_bytes_with(free_after as f64 - free_before as f64, format, separators),
+        format_bytes_with(removed as f64, format, separators)
+    )
+}
+
+/// Fails with a clear error if the administrative share of the --remote host can't be read
+fn check_remote(host: &str, share: &Path) -> Result<()> {
+    match fs::read_dir(share) {
+        Ok(_) => Ok(()),
+        Err(err) if is_unreachable_error(&err) => bail!(
+            "The host {} is offline or doesn't share {}: {}",
+            host,
+            share.display(),
+            err
+        ),
+        Err(err) if is_logon_error(&err) => bail!(
+            "Access to {} was denied, the account running the cleanup needs administrator \
+            rights on {}: {}",
+            share.display(),
+            host,
+            err
+        ),
+        Err(err) => Err(err).with_context(|| format!("Unable to read {}", share.display())),
+    }
+}
+
+/// Returns the directories to clean, paired with whether files in use are skipped
+fn get_roots(config: &Config) -> Result<Vec<(PathBuf, bool)>> {
+    let root = config.root.as_deref();
+    let mut roots = get_temp_directories(root, !config.no_user_profiles)?
+        .into_iter()
+        .map(|path| (path, false))
+        .collect::<Vec<_>>();
+
+    // Windows Update may hold its downloads open if its service isn't stopped
+    if config.clean_update_cache {
+        roots.push((system_path(root, UPDATE_CACHE_DIR), true));
+    }
+
+    if config.clean_webcache {
+        roots.extend(
+            get_webcache_directories(root)?
+                .into_iter()
+                .map(|path| (path, true)),
+        );
+    }
+
+    if config.include_inetcache {
+        if root.is_none() && is_process_running("iexplore.exe").unwrap_or(false) {
+            warn!("Internet Explorer is running, the cache files it's using will be skipped");
+        }
+
+        roots.extend(
+            get_user_directories(root, "AppData\\Local\\Microsoft\\Windows\\INetCache\\")?
+                .into_iter()
+                .map(|path| (path, true)),
+        );
+    }
+
+    if config.include_vs_temp {
+        if root.is_none() && is_process_running("devenv.exe").unwrap_or(false) {
+            warn!("Visual Studio is running, the files it's using will be skipped");
+        }
+
+        roots.extend(
+            get_vs_directories(root)?
+                .into_iter()
+                .map(|path| (path, true)),
+        );
+    }
+
+    // Server applications may hold their temporary files open
+    for preset in &config.presets {
+        roots.extend(
+            preset_directories(preset, root)
+                .into_iter()
+                .map(|path| (path, true)),
+        );
+    }
+
+    if let Some(within) = config.skip_if_storage_sense_ran_within {
+        // Dates in the future count as just now
+        let ran_ago = get_storage_sense_last_run()
+            .map(|time| now(config).duration_since(time).unwrap_or_default());
+        match ran_ago {
+            Ok(ago) if ago < within => {
+                info!(
+                    "Storage Sense ran {} ago, skipping the user profiles",
+                    format_duration(Duration::from_secs(ago.as_secs()))
+                );
+                let users = system_path(root, "Users");
+                roots.retain(|(path, _)| !is_within(path, &users));
             }
+            Ok(_) => {}
+            Err(err) => warn!(
+                "Can't read when Storage Sense last ran, cleaning the user profiles: {}",
+                err
+            ),
+        }
+    }
 
-            // Remove entry or report error
-            if let Err(err) = remove_entry(&entry, &meta, config) {
-                stats.errors_total += 1;
-                print_err(err);
-            } else {
-                stats.removed_bytes += size;
-                stats.removed_count += 1;
+    // Only the profiles matching a --profile pattern are cleaned
+    if !config.profiles.is_empty() {
+        let users = system_path(root, "Users");
+        roots.retain(|(path, _)| match path.strip_prefix(&users) {
+            Ok(relative) => relative.iter().next().is_none_or(|name| {
+                let name = name.to_string_lossy();
+                config
+                    .profiles
+                    .iter()
+                    .any(|pattern| matches_wildcard(pattern, &name))
+            }),
+            Err(_) => true,
+        });
+    }
+
+    if config.no_cross_volume {
+        // The volume of an offline installation is the one of its root
+        let system_drive = match root {
+            Some(root) => root.to_path_buf(),
+            None => PathBuf::from(format!(
+                "{}\\",
+                env::var("SystemDrive").unwrap_or_else(|_| String::from("C:"))
+            )),
+        };
+        let serial = volume_of(&system_drive)
+            .with_context(|| format!("can't read the volume of {}", system_drive.display()))?
+            .serial;
+
+        roots.retain(|(path, _)| match volume_of(path) {
+            Ok(volume) if volume.serial == serial => true,
+            Ok(volume) => {
+                debug!(
+                    "Skipping {}, on the volume {} instead of the system one",
+                    path.display(),
+                    volume.name()
+                );
+                false
+            }
+            Err(err) => {
+                debug!(
+                    "Skipping {}, can't read its volume: {}",
+                    path.display(),
+                    err
+                );
+                false
+            }
+        });
+    }
+
+    // Every removal would fail on a read-only volume, warned about once per volume
+    let mut warned = vec![];
+    roots.retain(|(path, _)| match volume_of(path) {
+        Ok(volume) if skips_read_only(&volume, config) => {
+            if !warned.contains(&volume.serial) {
+                warn!(
+                    "Skipping the directories on the read-only volume {} \
+                    (see --include-readonly-volumes)",
+                    volume.name()
+                );
+                warned.push(volume.serial);
             }
+            debug!("Skipping {}, on a read-only volume", path.display());
+            false
+        }
+        _ => true,
+    });
+
+    // Each directory is cleaned once, skipping the files in use if any source asks to
+    let mut unique: Vec<(PathBuf, bool)> = vec![];
+    for (path, skip_in_use) in roots {
+        match unique.iter_mut().find(|(dir, _)| *dir == path) {
+            Some((_, skip)) => *skip |= skip_in_use,
+            None => unique.push((path, skip_in_use)),
         }
     }
 
-    Ok(stats)
+    Ok(unique)
 }
 
-fn remove_entry(entry: &fs::DirEntry, metadata: &fs::Metadata, config: &Config) -> Result<()> {
-    let dry_run_tag = if config.dry_run { " (dry run)" } else { "" };
-    let path = entry.path();
+/// Returns true if the directories of the volume are skipped, it's read-only and
+/// --include-readonly-volumes wasn't given
+fn skips_read_only(volume: &Volume, config: &Config) -> bool {
+    volume.is_read_only() && !config.include_readonly_volumes
+}
 
-    debug!("Removing{} {}", dry_run_tag, path.display());
+/// Fails if any directory is a system directory or within one, following the links
+/// to check where they lead. --force doesn't apply.
+fn check_system_dirs(roots: &[(PathBuf, bool)], config: &Config) -> Result<()> {
+    let root = config.root.as_deref();
 
-    let mut perms = metadata.permissions();
+    for (path, _) in roots {
+        let mut paths = vec![path.clone()];
+        if let Ok(target) = fs::canonicalize(path) {
+            // Canonical paths have the verbatim prefix
+            let target = target.to_string_lossy().replacen(r"\\?\", "", 1);
+            paths.push(PathBuf::from(target));
+        }
 
-    // Unset readonly flag if present
-    let perms_changed = if perms.readonly() {
-        debug!("Disabling readonly flag");
-        perms.set_readonly(false);
-        true
-    } else {
-        false
-    };
+        let blocked = paths.iter().any(|path| {
+            let is_dir = |relative: &str| same_path(path, &system_path(root, relative));
 
-    if !config.dry_run {
-        // Apply changed permissions
-        if perms_changed {
-            if let Err(err) = fs::set_permissions(&path, perms)
-                .with_context(|| format!("failed to unset readonly permission {}", path.display()))
-            {
-                bail!(err)
-            }
+            BLOCKED_DIRS.iter().any(|relative| is_dir(relative))
+                || BLOCKED_PATHS.iter().any(|relative| {
+                    is_dir(relative) || is_within(path, &system_path(root, relative))
+                })
+        });
+
+        if blocked {
+            bail!(
+                "{} is a system directory, refusing to clean it",
+                path.display()
+            );
         }
-        return if path.is_dir() {
-            // Remove dir and return
-            fs::remove_dir(&path)
-                .with_context(|| format!("failed to remove directory {}", path.display()))
-        } else {
-            // Remove file and return
-            fs::remove_file(&path)
-                .with_context(|| format!("failed to remove file {}", path.display()))
-        };
     }
 
     Ok(())
 }
 
-fn create_date_older_than_duration(meta: &fs::Metadata, duration: Duration) -> bool {
-    let elapsed = (|| -> Result<Duration> { Ok(meta.created()?.elapsed()?) })();
+/// Cleans the Windows Update download cache, stopping the Windows Update service
+/// meanwhile so it doesn't hold the files. The service is started again if it was
+/// running. The service of an offline installation given with --root is left alone.
+fn clean_update_cache(path: &Path, config: &Config, state: &mut WalkState) -> Stats {
+    let stop = !config.keep_update_service && !config.dry_run && config.root.is_none();
 
-    match elapsed {
-        Ok(elapsed) => elapsed >= duration,
-        Err(err) => {
-            // Warn and return false
-            print_err(err);
-            false
+    let stopped = stop
+        && match stop_service(UPDATE_SERVICE) {
+            Ok(was_running) => {
+                if was_running {
+                    info!("Stopped the Windows Update service");
+                }
+                was_running
+            }
+            Err(err) => {
+                warn!(
+                    "Unable to stop the Windows Update service, the files it's using will be skipped: {}",
+                    err
+                );
+                false
+            }
+        };
+
+    let stats = clean_directory(path, config, state);
+
+    if stopped {
+        match start_service(UPDATE_SERVICE) {
+            Ok(()) => info!("Restarted the Windows Update service"),
+            Err(err) => error!("Unable to restart the Windows Update service: {}", err),
         }
     }
+
+    stats
 }
 
-// https://www.sqlservercentral.com/blogs/powershell-using-exponents-and-logs-to-format-byte-sizes
-fn format_bytes(bytes: f64) -> String {
-    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
-    let negative_sign = if bytes.is_sign_negative() { "-" } else { "" };
-    let bytes = bytes.abs();
+/// Cleans all the directories, returning the total stats
+fn begin_cleaning(config: &Config) -> Result<Stats> {
+    let started = Instant::now();
+    let mut roots = get_roots(config)?;
 
-    if bytes < 1_f64 {
-        return format!("{}{} {}", negative_sign, bytes, "B");
+    if config.abort_on_system_dir {
+        check_system_dirs(&roots, config)?;
     }
 
-    let pow2 = (bytes.ln() / 2_f64.ln()).floor();
-    let idx = (pow2 / 10.0).floor().min((units.len() - 1) as f64) as i32;
-    let scaled = bytes / 2_f64.powi(idx * 10);
-    let unit = units[idx as usize];
+    let mut state = WalkState::new();
+    state.timings.discovery = started.elapsed();
+
+    let free_space = if config.report_free_space {
+        free_space_before(&roots)
+    } else {
+        vec![]
+    };
+    let mut total = Stats::new();
+    let mut volume_totals = vec![];
+
+    let dirs = roots
+        .iter()
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    if let Some(path) = &config.scan_before {
+        write_snapshot(path, &take_snapshot(&dirs))?;
+    }
+
+    if let Some(path) = &config.continue_from {
+        state.checkpoint = Some(Checkpoint::open(path)?);
+    }
+
+    // Nothing is removed in a dry run
+    if let Some(path) = config.audit.as_ref().filter(|_| !config.dry_run) {
+        state.audit = Some(AuditLog::open(
+            path,
+            config.flush_every.unwrap_or(1),
+            format_tags(&config.tags, ","),
+        )?);
+    }
+
+    if let (Some(dir), Some(ttl)) = (&config.quarantine_dir, config.quarantine_ttl) {
+        if !config.dry_run {
+            match purge_expired(dir, ttl) {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} expired files from the quarantine", purged),
+                Err(err) => warn!("Unable to purge the quarantine: {:#}", err),
+            }
+        }
+    }
+
+    // The listed paths replace the walk of the roots
+    if let Some(list) = &config.delete_list {
+        let started = Instant::now();
+        total += remove_listed(list, &roots, config, &mut state)?;
+        state.timings.walking += started.elapsed();
+        roots.clear();
+    }
+
+    // The profile directories can be cleaned concurrently, the others stay serial
+    let users = system_path(config.root.as_deref(), "Users");
+    let (user_roots, roots): (Vec<_>, Vec<_>) = match config.parallel_users {
+        Some(_) => roots
+            .into_iter()
+            .partition(|(path, _)| is_within(path, &users)),
+        None => (vec![], roots),
+    };
 
-    format!("{}{:.2} {}", negative_sign, scaled, unit)
+    for (path, skip_in_use) in roots {
+        if state.stopped(config) {
+            break;
+        }
+
+        if state.is_done(&path) {
+            info!("Skipping {}: completed by the previous run", path.display());
+            continue;
+        }
+
+        state.skip_in_use = skip_in_use;
+
+        let started = Instant::now();
+        let deletion = state.timings.deletion;
+
+        let stats = if config.clean_update_cache
+            && same_path(
+                &path,
+                &system_path(config.root.as_deref(), UPDATE_CACHE_DIR),
+            ) {
+            clean_update_cache(&path, config, &mut state)
+        } else {
+            clean_directory(&path, config, &mut state)
+        };
+
+        if config.timings {
+            debug!(
+                "Spent {} on {}, {} removing entries",
+                format_seconds(started.elapsed()),
+                path.display(),
+                format_seconds(state.timings.deletion - deletion)
+            );
+        }
+        state.timings.walking += started.elapsed();
+        add_volume_stats(
+            &mut volume_totals,
+            &path,
+            stats.removed_bytes,
+            stats.removed_count,
+        );
+        total += stats;
+        debug!("Running total after {}: {}", path.display(), total);
+
+        state.mark_done(&path, config);
+    }
+
+    if let Some(threads) = config.parallel_users {
+        let started = Instant::now();
+
+        for (path, stats) in clean_parallel(user_roots, threads, config) {
+            add_volume_stats(
+                &mut volume_totals,
+                &path,
+                stats.removed_bytes,
+                stats.removed_count,
+            );
+            total += stats;
+        }
+
+        state.timings.walking += started.elapsed();
+    }
+
+    // The next run starts over, unless this one was a stopped preview
+    if let Some(checkpoint) = state.checkpoint.take() {
+        if !state.stopped(config) {
+            checkpoint.finish();
+        }
+    }
+
+    if SHUTDOWN.load(Ordering::SeqCst) {
+        warn!("Stopped early, Windows is shutting down or the user is logging off");
+    } else if state.stopped(config) {
+        info!("Preview stopped after {} entries", state.candidates);
+    }
+
+    if let Some(path) = &config.scan_after {
+        write_snapshot(path, &take_snapshot(&dirs))?;
+    }
+
+    // Measured on the volume of the first directory
+    let sample_dir = dirs.iter().find(|dir| dir.is_dir());
+    if let Some(dir) = sample_dir.filter(|_| config.estimate_time) {
+        match estimate_deletion_throughput(dir) {
+            Ok(rate) => {
+                let seconds = (total.removed_count as f64 / rate).round() as u64;
+                info!(
+                    "Estimated real-run time: {}",
+                    format_duration(Duration::from_secs(seconds))
+                );
+            }
+            Err(err) => warn!("Unable to estimate the real-run time: {:#}", err),
+        }
+    }
+
+    for volume in &volume_totals {
+        let line = format!(
+            "Volume {} freed {} across {} directories",
+            volume.volume.name(),
+            format_bytes(volume.freed_bytes as f64, config.bytes_format),
+            volume.dirs
+        );
+        info!("{}", line);
+        total.summary_lines.push(line);
+    }
+
+    if total.dirs_failed > 0 {
+        warn!(
+            "{} directories couldn't be read and weren't cleaned",
+            total.dirs_failed
+        );
+    }
+
+    print_free_space_after(&free_space, &volume_totals, config);
+
+    let line = format!(
+        "Removed {} entries ({}) with {} errors from all paths",
+        total.removed_count,
+        format_bytes(total.removed_bytes as f64, config.bytes_format),
+        total.errors_total
+    );
+    print_summary(&line, summary_severity(&total), config);
+
+    if let Some(path) = &config.summary_file {
+        if !config.tags.is_empty() {
+            let tags = format!("Tags: {}", format_tags(&config.tags, ", "));
+            total.summary_lines.insert(0, tags);
+        }
+        total.summary_lines.push(line);
+        write_summary_file(path, &total.summary_lines);
+    }
+
+    if let Some(path) = config.history.as_ref().filter(|_| !config.dry_run) {
+        let row = format!(
+            "{},{},{},{},{:.3}",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            total.removed_count,
+            total.removed_bytes,
+            total.errors_total,
+            started.elapsed().as_secs_f64()
+        );
+        append_history(path, &row);
+    }
+
+    if let Some(path) = config.metrics_file.as_ref().filter(|_| !config.dry_run) {
+        write_metrics_file(path, &total, &config.tags);
+    }
+
+    if config.timings {
+        state.timings.print();
+    }
+
+    match config.histogram {
+        Some(OutputFormat::Human) if config.age_histogram => {
+            total.histogram.print_chart(config.bytes_format)
+        }
+        Some(OutputFormat::Human) => total.histogram.print(config.bytes_format),
+        Some(OutputFormat::Json) => println!("{}", json_report(&total, &volume_totals, config)),
+        None => {}
+    }
+
+    Ok(total)
+}
+
+/// Returns the histogram, the volumes and the errors as a JSON object, with the tags of
+/// the run if given
+fn json_report(total: &Stats, volumes: &[VolumeStats], config: &Config) -> String {
+    let volumes = volumes.iter().map(|x| x.to_json()).collect::<Vec<_>>();
+    let mut report = format!(
+        "{{\"histogram\":{},\"volumes\":[{}],\"errors\":[{}]",
+        total.histogram.to_json(),
+        volumes.join(","),
+        total.errors.join(",")
+    );
+
+    if !config.tags.is_empty() {
+        let tags = config
+            .tags
+            .iter()
+            .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+            .collect::<Vec<_>>();
+        report.push_str(&format!(",\"tags\":{{{}}}", tags.join(",")));
+    }
+
+    report.push('}');
+    report
+}
+
+/// Green without errors, yellow if more entries were removed than failed, red otherwise
+fn summary_severity(stats: &Stats) -> Severity {
+    if stats.errors_total == 0 {
+        Severity::Ok
+    } else if stats.removed_count > stats.errors_total {
+        Severity::Warning
+    } else {
+        Severity::Failed
+    }
+}
+
+/// Cleans the directories with the given number of threads, each with its own walk state.
+/// Returns the stats of every directory.
+fn clean_parallel(
+    roots: Vec<(PathBuf, bool)>,
+    threads: usize,
+    config: &Config,
+) -> Vec<(PathBuf, Stats)> {
+    let queue = Mutex::new(roots);
+    let results = Mutex::new(vec![]);
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                let mut state = WalkState::new();
+
+                loop {
+                    let next = queue.lock().unwrap().pop();
+                    let Some((path, skip_in_use)) = next else {
+                        break;
+                    };
+
+                    state.skip_in_use = skip_in_use;
+
+                    let stats = clean_directory(&path, config, &mut state);
+                    results.lock().unwrap().push((path, stats));
+                }
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+fn clean_directory(path: &Path, config: &Config, state: &mut WalkState) -> Stats {
+    let mut stats = Stats::new();
+
+    if !path.exists()
+        || !volume_usage_above_threshold(path, config, &mut stats)
+        || free_space_satisfied(path, config, &mut stats)
+    {
+        return stats;
+    }
+
+    debug!("Cleaning: {:?}", path);
+
+    state.enter(path, false, config);
+    state.protected = config
+        .protect_dirs
+        .iter()
+        .map(|dir| path.join(dir))
+        .collect();
+
+    // A failure on the directory itself doesn't stop the other directories
+    let mut stats = match remove_dir_contents(path, config, false, state) {
+        Ok(walked) => stats + walked,
+        Err(err) => {
+            warn!("Unable to clean {}, skipping it", path.display());
+            stats.dirs_failed += 1;
+            record_error(err, path, config, &mut stats);
+            return stats;
+        }
+    };
+
+    if let Some(max_size) = config.dir_max_size {
+        cap_dir_size(path, max_size, config, state, &mut stats);
+    }
+
+    let line = format!(
+        "Removed {} entries ({} files, {} directories, {} links) ({}) with {} errors from path {}",
+        stats.removed_count,
+        stats.removed_files,
+        stats.removed_dirs,
+        stats.removed_symlinks,
+        format_bytes(stats.removed_bytes as f64, config.bytes_format),
+        stats.errors_total,
+        path.display()
+    );
+    info!("{}", line);
+
+    if config.summary_file.is_some() {
+        stats.summary_lines.push(line);
+    }
+
+    if config.limit_depth_report {
+        print_subtotals(path, &stats, config);
+    }
+
+    if config.report_skipped {
+        info!(
+            "Skipped {} entries: {} by age, {} by pattern, {} by permission",
+            stats.skipped_by_age + stats.skipped_by_pattern + stats.skipped_by_permission,
+            stats.skipped_by_age,
+            stats.skipped_by_pattern,
+            stats.skipped_by_permission
+        );
+    }
+
+    // Many failures usually mean missing privileges or an antivirus blocking removals
+    let attempted = stats.removed_count + stats.errors_total;
+    if attempted > 0 {
+        let failed_percent = stats.errors_total as f64 * 100.0 / attempted as f64;
+        if failed_percent > config.failure_warning {
+            warn!(
+                "{:.0}% of the removals failed in path {}, check the privileges \
+                or whether an antivirus is blocking them",
+                failed_percent,
+                path.display()
+            );
+        }
+    }
+
+    if stats.deduped_count > 0 {
+        info!(
+            "Removed {} duplicate files ({}) from path {}",
+            stats.deduped_count,
+            format_bytes(stats.deduped_bytes as f64, config.bytes_format),
+            path.display()
+        );
+    }
+
+    if stats.shared_bytes > 0 {
+        info!(
+            "Removed {} of hard linked files without freeing space from path {}",
+            format_bytes(stats.shared_bytes as f64, config.bytes_format),
+            path.display()
+        );
+    }
+
+    if stats.trimmed_count > 0 {
+        info!(
+            "Trimmed {} sparse files ({}) from path {}",
+            stats.trimmed_count,
+            format_bytes(stats.trimmed_bytes as f64, config.bytes_format),
+            path.display()
+        );
+    }
+
+    if stats.busy_dirs_skipped > 0 {
+        info!(
+            "Skipped {} directories in use from path {}",
+            stats.busy_dirs_skipped,
+            path.display()
+        );
+    }
+
+    if stats.timed_out > 0 {
+        warn!(
+            "{} operations timed out from path {}",
+            stats.timed_out,
+            path.display()
+        );
+    }
+
+    if stats.cloud_skipped > 0 {
+        info!(
+            "Skipped {} cloud files from path {}",
+            stats.cloud_skipped,
+            path.display()
+        );
+    }
+
+    if stats.running_skipped > 0 {
+        info!(
+            "Skipped {} files of running processes from path {}",
+            stats.running_skipped,
+            path.display()
+        );
+    }
+
+    if stats.scheduled_for_reboot > 0 {
+        info!(
+            "Scheduled {} entries for deletion at the next reboot from path {}",
+            stats.scheduled_for_reboot,
+            path.display()
+        );
+    }
+
+    stats
+}
+
+/// Removes the oldest files within the directory until the remaining ones fit in the size
+fn cap_dir_size(
+    path: &Path,
+    max_size: u64,
+    config: &Config,
+    state: &mut WalkState,
+    stats: &mut Stats,
+) {
+    let mut paths = vec![];
+    if let Err(err) = collect_files(path, &mut paths) {
+        let err = err.context(format!("can't list the files of {}", path.display()));
+        record_error(err, path, config, stats);
+        return;
+    }
+
+    // The entries a dry run removed are still there but no longer count
+    let mut files = paths
+        .into_iter()
+        .filter(|file| !state.dry_run_removed.contains(file))
+        .filter_map(|file| {
+            let meta = fs::symlink_metadata(&file).ok()?;
+            let time = config.age_basis.time_of(&meta).ok()?;
+            Some((file, meta, time))
+        })
+        .collect::<Vec<_>>();
+
+    let mut size = files.iter().map(|(_, meta, _)| meta.len()).sum::<u64>();
+    if size <= max_size {
+        return;
+    }
+
+    debug!(
+        "{} holds {}, removing its oldest files",
+        path.display(),
+        format_bytes(size as f64, config.bytes_format)
+    );
+
+    files.sort_by_key(|(_, _, time)| *time);
+
+    let retained = if config.retention.is_empty() {
+        HashSet::new()
+    } else {
+        retained_entries(path, config)
+    };
+
+    for (file, meta, _) in files {
+        if size <= max_size || state.stopped(config) {
+            break;
+        }
+
+        // The kept files still count in the size
+        if retained.contains(&file)
+            || is_kept_outside_walk(&file, &meta, path, config, state, stats)
+        {
+            continue;
+        }
+
+        let errors = stats.errors_total;
+        let is_link = meta.file_type().is_symlink();
+        remove_candidate(&file, &meta, meta.len(), is_link, config, state, stats);
+
+        if stats.errors_total == errors {
+            size = size.saturating_sub(meta.len());
+        }
+    }
+}
+
+/// Logs the removals by top level subdirectory of the path, largest first
+fn print_subtotals(path: &Path, stats: &Stats, config: &Config) {
+    let mut subtotals = stats.subtotals.iter().collect::<Vec<_>>();
+    subtotals.sort_by(|(a_dir, (_, a_bytes)), (b_dir, (_, b_bytes))| {
+        b_bytes.cmp(a_bytes).then_with(|| a_dir.cmp(b_dir))
+    });
+
+    for (dir, (count, bytes)) in subtotals {
+        let name = match dir.strip_prefix(path) {
+            Ok(relative) if relative.as_os_str().is_empty() => String::from("(files)"),
+            Ok(relative) => relative.display().to_string(),
+            Err(_) => dir.display().to_string(),
+        };
+
+        info!(
+            "  {}: {} ({} entries)",
+            name,
+            format_bytes(*bytes as f64, config.bytes_format),
+            count
+        );
+    }
+}
+
+/// Checks the --when-above threshold against the volume containing the path.
+/// Directories whose usage can't be determined aren't cleaned.
+fn volume_usage_above_threshold(path: &Path, config: &Config, stats: &mut Stats) -> bool {
+    let threshold = match config.when_above {
+        Some(threshold) => threshold,
+        None => return true,
+    };
+
+    let space = disk_space(path)
+        .with_context(|| format!("can't read the volume usage of {}", path.display()));
+
+    match space {
+        Ok(space) if !space.is_used_above(threshold) => {
+            info!(
+                "Skipping {}: volume usage {:.1}% is below {}%",
+                path.display(),
+                space.used_percent(),
+                threshold
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(err) => {
+            record_error(err, path, config, stats);
+            false
+        }
+    }
+}
+
+/// Checks the --gc-threshold against the volume containing the path, it's checked
+/// again before every directory so cleaning stops as soon as there's enough free space.
+/// Directories whose free space can't be determined are cleaned.
+fn free_space_satisfied(path: &Path, config: &Config, stats: &mut Stats) -> bool {
+    let threshold = match config.gc_threshold {
+        Some(threshold) => threshold,
+        None => return false,
+    };
+
+    let space = disk_space(path)
+        .with_context(|| format!("can't read the free space of {}", path.display()));
+
+    match space {
+        Ok(space) if space.free_percent() >= threshold => {
+            info!(
+                "Skipping {}: volume free space {:.1}% is above {}%",
+                path.display(),
+                space.free_percent(),
+                threshold
+            );
+            true
+        }
+        Ok(_) => false,
+        Err(err) => {
+            record_error(err, path, config, stats);
+            false
+        }
+    }
+}
+
+/// Returns the path within the Windows installation, the running one unless a root is given
+fn system_path(root: Option<&Path>, relative: &str) -> PathBuf {
+    root.unwrap_or_else(|| Path::new(r"C:\")).join(relative)
+}
+
+/// Returns the system temporary directories, and those of the profiles unless
+/// --no-user-profiles skips listing them
+fn get_temp_directories(root: Option<&Path>, include_users: bool) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![
+        system_path(root, r"Windows\Temp"),
+        system_path(root, r"ProgramData\Temp"),
+    ];
+
+    if !include_users {
+        return Ok(dirs);
+    }
+
+    // The system directories are cleaned even if the profiles can't be listed
+    match get_user_directories(root, "AppData\\Local\\Temp\\") {
+        Ok(users_dirs) => dirs.extend(users_dirs),
+        Err(err) => print_err(err),
+    }
+
+    Ok(dirs)
+}
+
+fn get_webcache_directories(root: Option<&Path>) -> Result<Vec<PathBuf>> {
+    get_user_directories(root, "AppData\\Local\\Microsoft\\Windows\\WebCache\\")
+}
+
+fn get_vs_directories(root: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![];
+
+    for relative in [
+        "AppData\\Local\\Temp\\vs-setup\\",
+        "AppData\\Local\\Temp\\MSBuildTemp\\",
+        "AppData\\Local\\Temp\\VisualStudio\\",
+    ] {
+        dirs.extend(get_user_directories(root, relative)?);
+    }
+
+    // Build outputs of the projects in the default repository location
+    for source in get_user_directories(root, "source\\")? {
+        find_obj_directories(&source, 3, &mut dirs);
+    }
+
+    Ok(dirs)
+}
+
+/// Adds the obj directories found within the given depth, links aren't followed
+fn find_obj_directories(path: &Path, depth: usize, dirs: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            continue;
+        }
+
+        if entry.file_name().eq_ignore_ascii_case("obj") {
+            dirs.push(entry.path());
+        } else if depth > 1 {
+            find_obj_directories(&entry.path(), depth - 1, dirs);
+        }
+    }
+}
+
+/// Returns the path relative to every user profile directory
+fn get_user_directories(root: Option<&Path>, relative: &str) -> Result<Vec<PathBuf>> {
+    let users_dirs = fs::read_dir(system_path(root, "Users"))
+        .context("can't read the user profiles")?
+        .map(|x| x.map(|entry| entry.path().join(relative)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(users_dirs)
+}
+
+fn remove_dir_contents(
+    path: &Path,
+    config: &Config,
+    skip_date_check: bool,
+    state: &mut WalkState,
+) -> Result<Stats> {
+    let mut stats = Stats::new();
+
+    // Files kept by the retention policy, which like the date filters applies only
+    // to the top level of the directory
+    let retained = if skip_date_check || config.retention.is_empty() {
+        HashSet::new()
+    } else {
+        retained_entries(path, config)
+    };
+
+    // Duplicates are removed first, the walk below skips them even in a dry run
+    let deduped = if config.dedupe {
+        remove_duplicates(path, &retained, config, state, &mut stats)
+    } else {
+        HashSet::new()
+    };
+
+    let mut entries = fs::read_dir(path)
+        .with_context(|| format!("can't read dir {}", path.display()))?
+        .collect::<Vec<_>>();
+
+    // Subdirectories are walked and removed before the files of the directory, the sort
+    // keeps the enumeration order within each group
+    entries.sort_by_key(|entry| {
+        !entry
+            .as_ref()
+            .is_ok_and(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+    });
+
+    // Loop every entry
+    for entry in entries {
+        if state.stopped(config) {
+            break;
+        }
+
+        let entry = entry?;
+
+        let meta = read_metadata(&entry.path(), config);
+
+        // Read metadata or report error
+        let meta = match meta {
+            Ok(result) => result,
+            Err(err) => {
+                count_error(err, &entry.path(), config, &mut stats);
+                continue;
+            }
+        };
+
+        if deduped.contains(&entry.path()) {
+            continue;
+        }
+
+        if config.limit_depth_report && !skip_date_check {
+            state.top_dir = meta.is_dir().then(|| entry.path());
+        }
+
+        if retained.contains(&entry.path()) {
+            debug!("Retaining {}", entry.path().display());
+            continue;
+        }
+
+        if state.is_protected(&entry.path()) {
+            debug!("Skipping protected directory {}", entry.path().display());
+            stats.skipped_by_pattern += 1;
+            continue;
+        }
+
+        // Reading the streams or the contents of a cloud file would download it
+        if config.skip_cloud && is_cloud_file(&meta) {
+            debug!("Skipping cloud file {}", entry.path().display());
+            stats.cloud_skipped += 1;
+            continue;
+        }
+
+        // Portable applications may run from a temp directory
+        if config.protect_running && meta.is_file() && is_running_module(&entry.path()) {
+            debug!(
+                "Skipping module of a running process {}",
+                entry.path().display()
+            );
+            stats.running_skipped += 1;
+            continue;
+        }
+
+        // Store size for later
+        let size = if config.count_ads {
+            meta.len() + streams_size(&entry.path())
+        } else {
+            meta.len()
+        };
+
+        // Don't mind create date if subdir, no date filter given or only empty entries removed
+        if skip_date_check
+            || config.empty_only
+            || passes_date_filters(&entry.path(), &meta, config, &mut stats)
+            || (meta.is_dir() && is_pending_delete(&entry.path()))
+        {
+            // Recurse into subdir and sum stats, links are followed
+            // only when allowed by --follow-reparse-path
+            let is_link = entry.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+
+            // Directories protected by their owner are left alone with their subtree
+            // and so are their parents, like the --protect-dir subdirectories
+            if meta.is_dir() && entry.path().join(&config.sentinel).exists() {
+                debug!("Skipping protected directory {}", entry.path().display());
+                state.protected.push(entry.path());
+                continue;
+            }
+
+            // Top level directories are removed only if their name matches
+            if let Some(pattern) = &config.dir_name_pattern {
+                if meta.is_dir()
+                    && !skip_date_check
+                    && !pattern.is_match(&entry.file_name().to_string_lossy())
+                {
+                    debug!("Skipping unmatched directory {}", entry.path().display());
+                    stats.skipped_by_pattern += 1;
+                    continue;
+                }
+            }
+
+            // Small top level directories aren't worth walking
+            if let Some(min_size) = config.min_dir_size {
+                if meta.is_dir() && !is_link && !skip_date_check {
+                    match estimate_dir_size_fast(&entry.path()) {
+                        Ok(estimate) if estimate < min_size => {
+                            debug!(
+                                "Skipping small directory {} ({})",
+                                entry.path().display(),
+                                format_bytes(estimate as f64, config.bytes_format)
+                            );
+                            continue;
+                        }
+                        Ok(_) => {}
+                        Err(err) => debug!(
+                            "Can't estimate the size of {}: {}",
+                            entry.path().display(),
+                            err
+                        ),
+                    }
+                }
+            }
+
+            // Directories with files in use are skipped along with their whole subtree
+            if meta.is_dir()
+                && config.exclude_busy_dirs
+                && !state.idle
+                && is_dir_busy(&entry.path(), config, &mut stats)
+            {
+                debug!("Skipping directory in use {}", entry.path().display());
+                stats.busy_dirs_skipped += 1;
+                stats.skipped_by_permission += 1;
+                continue;
+            }
+
+            // Top level directories completed by the interrupted run aren't walked again
+            if meta.is_dir() && !skip_date_check && state.is_done(&entry.path()) {
+                debug!("Skipping completed directory {}", entry.path().display());
+                continue;
+            }
+
+            // The filter runs before the walk, a directory it keeps is kept whole
+            if meta.is_dir() && !filter_exec_allows(&entry.path(), &meta, config, &mut stats) {
+                continue;
+            }
+
+            if meta.is_dir() && state.enter(&entry.path(), is_link, config) {
+                // Subdirectories of an idle directory don't need to be checked again
+                let idle = std::mem::replace(&mut state.idle, config.exclude_busy_dirs);
+
+                // Try remove sub contents
+                let result = remove_dir_contents(&entry.path(), config, true, state);
+
+                state.idle = idle;
+
+                if is_link {
+                    state.link_depth -= 1;
+                }
+
+                match result {
+                    Ok(sub_stats) => {
+                        // Sum stats
+                        stats += sub_stats;
+
+                        if !skip_date_check {
+                            state.mark_done(&entry.path(), config);
+                        }
+                    }
+                    Err(err) => {
+                        // Error: return early
+                        record_error(err, &entry.path(), config, &mut stats);
+                        return Ok(stats);
+                    }
+                };
+            }
+
+            // Links to directories are left in place, followed or not
+            if is_link && meta.is_dir() {
+                continue;
+            }
+
+            // The parents of the protected directories are kept with them
+            if meta.is_dir() && state.holds_protected(&entry.path()) {
+                continue;
+            }
+
+            // Directories are checked after their contents were removed
+            if config.empty_only && !is_empty_entry(&entry.path(), &meta) {
+                continue;
+            }
+
+            // Files locked by a running application are left alone
+            if state.skip_in_use && !meta.is_dir() && is_file_in_use(&entry.path()) {
+                debug!("Skipping file in use {}", entry.path().display());
+                stats.skipped_by_permission += 1;
+                continue;
+            }
+
+            if config.preserve_inetcache_index && is_inetcache_index(&entry.path()) {
+                debug!("Preserving cache index {}", entry.path().display());
+                stats.skipped_by_pattern += 1;
+                continue;
+            }
+
+            if !meta.is_dir() && !filter_exec_allows(&entry.path(), &meta, config, &mut stats) {
+                continue;
+            }
+
+            if meta.is_file() && has_kept_extension(&entry.path(), config) {
+                debug!("Keeping {}", entry.path().display());
+                stats.skipped_by_pattern += 1;
+                continue;
+            }
+
+            // Large files are trimmed in place instead of being removed
+            if let Some(min_size) = config.trim_sparse {
+                if meta.is_file() && size >= min_size {
+                    trim_entry(&entry.path(), config, &mut stats);
+                    continue;
+                }
+            }
+
+            remove_candidate(
+                &entry.path(),
+                &meta,
+                size,
+                is_link,
+                config,
+                state,
+                &mut stats,
+            );
+        } else {
+            stats.skipped_by_age += 1;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Removes the paths listed in the file, one per line. Only the paths within the
+/// directories that would be cleaned are removed, directories with their contents.
+fn remove_listed(
+    list: &Path,
+    roots: &[(PathBuf, bool)],
+    config: &Config,
+    state: &mut WalkState,
+) -> Result<Stats> {
+    let list = fs::read_to_string(list)
+        .with_context(|| format!("Unable to read the delete list {}", list.display()))?;
+
+    let mut stats = Stats::new();
+
+    for line in list.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if state.stopped(config) {
+            break;
+        }
+
+        let path = Path::new(line);
+
+        let (root, skip_in_use) = match roots.iter().find(|(root, _)| is_within(path, root)) {
+            Some(root) => root,
+            None => {
+                warn!("Skipping {}: not within a temporary directory", line);
+                stats.errors_total += 1;
+                continue;
+            }
+        };
+
+        let meta = match fs::symlink_metadata(path) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                debug!("Already removed {}", line);
+                continue;
+            }
+            Err(err) => {
+                let err = anyhow::Error::new(err).context(format!("can't read metadata {}", line));
+                record_error(err, path, config, &mut stats);
+                continue;
+            }
+        };
+
+        let is_link = meta.file_type().is_symlink();
+        state.skip_in_use = *skip_in_use;
+        state.protected = config
+            .protect_dirs
+            .iter()
+            .map(|dir| root.join(dir))
+            .collect();
+
+        // The listed entries are kept like the walk of their root would keep them
+        if is_kept_outside_walk(path, &meta, root, config, state, &mut stats) {
+            continue;
+        }
+
+        if meta.is_dir() && !is_link {
+            match remove_dir_contents(path, config, true, state) {
+                Ok(sub_stats) => stats += sub_stats,
+                Err(err) => {
+                    record_error(err, path, config, &mut stats);
+                    continue;
+                }
+            }
+        }
+
+        remove_candidate(path, &meta, meta.len(), is_link, config, state, &mut stats);
+    }
+
+    info!(
+        "Removed {} listed entries ({}) with {} errors",
+        stats.removed_count,
+        format_bytes(stats.removed_bytes as f64, config.bytes_format),
+        stats.errors_total
+    );
+
+    Ok(stats)
+}
+
+/// Returns true if the name matches the pattern, where * matches any characters and ?
+/// a single one, ignoring case
+fn matches_wildcard(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let name = name.to_lowercase().chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Pattern position after the last * and the name position it's matched up to
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // The * takes one more character and the rest is matched again
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns true if both paths name the same entry, ignoring case and trailing separators
+fn same_path(a: &Path, b: &Path) -> bool {
+    a.to_string_lossy().trim_end_matches('\\').to_lowercase()
+        == b.to_string_lossy().trim_end_matches('\\').to_lowercase()
+}
+
+/// Returns true if the absolute path is strictly inside the directory, the comparison
+/// ignores case like Windows paths do. Paths with .. components are never inside.
+fn is_within(path: &Path, dir: &Path) -> bool {
+    if !path.is_absolute()
+        || path
+            .components()
+            .any(|component| component == std::path::Component::ParentDir)
+    {
+        return false;
+    }
+
+    let path = path.to_string_lossy().to_lowercase();
+    let dir = dir.to_string_lossy().trim_end_matches('\\').to_lowercase();
+
+    path.strip_prefix(&dir)
+        .is_some_and(|rest| rest.starts_with('\\') && rest.len() > 1)
+}
+
+/// Returns true if the entry, reached without walking to it, is kept by the checks the
+/// walk applies to each entry. The directories up to the root are checked for a sentinel.
+fn is_kept_outside_walk(
+    path: &Path,
+    meta: &fs::Metadata,
+    root: &Path,
+    config: &Config,
+    state: &WalkState,
+    stats: &mut Stats,
+) -> bool {
+    if state
+        .protected
+        .iter()
+        .any(|dir| same_path(path, dir) || is_within(path, dir))
+    {
+        debug!("Skipping protected entry {}", path.display());
+        return true;
+    }
+
+    let sentinel_dir = path
+        .ancestors()
+        .take_while(|dir| is_within(dir, root))
+        .find(|dir| dir.join(&config.sentinel).exists());
+    if let Some(dir) = sentinel_dir {
+        debug!("Skipping protected directory {}", dir.display());
+        return true;
+    }
+
+    if config.skip_cloud && is_cloud_file(meta) {
+        debug!("Skipping cloud file {}", path.display());
+        return true;
+    }
+
+    if config.protect_running && meta.is_file() && is_running_module(path) {
+        debug!("Skipping module of a running process {}", path.display());
+        return true;
+    }
+
+    if state.skip_in_use && !meta.is_dir() && is_file_in_use(path) {
+        debug!("Skipping file in use {}", path.display());
+        return true;
+    }
+
+    if config.preserve_inetcache_index && is_inetcache_index(path) {
+        debug!("Preserving cache index {}", path.display());
+        return true;
+    }
+
+    if meta.is_file() && has_kept_extension(path, config) {
+        debug!("Keeping {}", path.display());
+        return true;
+    }
+
+    !filter_exec_allows(path, meta, config, stats)
+}
+
+/// Removes an entry that passed all the filters and updates the stats
+fn remove_candidate(
+    path: &Path,
+    meta: &fs::Metadata,
+    size: u64,
+    is_link: bool,
+    config: &Config,
+    state: &mut WalkState,
+    stats: &mut Stats,
+) {
+    state.candidates += 1;
+
+    if config.preview.is_some() {
+        info!("Would remove {}", path.display());
+    }
+
+    // Removing a hard link doesn't free space while other links remain
+    let shared = meta.is_file() && has_other_links(path);
+
+    let started = Instant::now();
+    let removed = remove_entry(path, meta, config);
+    state.timings.deletion += started.elapsed();
+
+    // Remove entry or report error
+    if let Err(err) = removed {
+        if config.delete_on_reboot && is_locked(&err) {
+            schedule_reboot_deletion(path, config, stats);
+        } else {
+            count_error(err, path, config, stats);
+        }
+    } else {
+        if let Some(audit) = &mut state.audit {
+            audit.record(path, size);
+        }
+
+        if config.dry_run && config.dir_max_size.is_some() {
+            state.dry_run_removed.insert(path.to_path_buf());
+        }
+
+        if shared {
+            stats.shared_bytes += size;
+        } else {
+            stats.removed_bytes += size;
+        }
+        stats.removed_count += 1;
+
+        if config.limit_depth_report {
+            let dir = state
+                .top_dir
+                .clone()
+                .or_else(|| path.parent().map(Path::to_path_buf))
+                .unwrap_or_default();
+            let subtotal = stats.subtotals.entry(dir).or_default();
+            subtotal.0 += 1;
+            subtotal.1 += size;
+        }
+
+        // Links are counted apart even when they point to a directory
+        if is_link {
+            stats.removed_symlinks += 1;
+        } else if meta.is_dir() {
+            stats.removed_dirs += 1;
+        } else {
+            stats.removed_files += 1;
+
+            if config.histogram.is_some() {
+                // Dates in the future count as just now
+                if let Ok(time) = config.age_basis.time_of(meta) {
+                    let age = now(config).duration_since(time).unwrap_or_default();
+                    stats.histogram.record(age, size);
+                }
+            }
+        }
+    }
+}
+
+/// Removes all but one copy of the identical files in the directory, regardless of their age.
+/// Returns the duplicates that were removed.
+fn remove_duplicates(
+    path: &Path,
+    retained: &HashSet<PathBuf>,
+    config: &Config,
+    state: &mut WalkState,
+    stats: &mut Stats,
+) -> HashSet<PathBuf> {
+    let files = match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok().filter(|meta| meta.is_file())?;
+                Some((entry, meta))
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => return HashSet::new(),
+    };
+
+    // Hashing a cloud file would download it, it's counted when the directory is walked
+    let files = files
+        .into_iter()
+        .filter(|(_, meta)| !(config.skip_cloud && is_cloud_file(meta)))
+        .collect::<Vec<_>>();
+
+    let mut removed = HashSet::new();
+    let sizes = files
+        .iter()
+        .map(|(entry, meta)| (entry.path(), meta.len()))
+        .collect::<Vec<_>>();
+    let duplicates = duplicate_files(&sizes);
+
+    for (entry, meta) in files {
+        if state.stopped(config) {
+            break;
+        }
+
+        if !duplicates.contains(&entry.path()) || retained.contains(&entry.path()) {
+            continue;
+        }
+
+        if is_kept_outside_walk(&entry.path(), &meta, path, config, state, stats) {
+            continue;
+        }
+
+        state.candidates += 1;
+
+        if config.preview.is_some() {
+            info!("Would remove duplicate {}", entry.path().display());
+        }
+
+        let started = Instant::now();
+        let result = remove_entry(&entry.path(), &meta, config);
+        state.timings.deletion += started.elapsed();
+
+        match result {
+            Ok(()) => {
+                if let Some(audit) = &mut state.audit {
+                    audit.record(&entry.path(), meta.len());
+                }
+                if config.dry_run && config.dir_max_size.is_some() {
+                    state.dry_run_removed.insert(entry.path());
+                }
+                stats.deduped_bytes += meta.len();
+                stats.deduped_count += 1;
+                removed.insert(entry.path());
+            }
+            Err(err) => count_error(err, &entry.path(), config, stats),
+        }
+    }
+
+    removed
+}
+
+/// Returns the files in the directory kept by the --retention policy
+fn retained_entries(path: &Path, config: &Config) -> HashSet<PathBuf> {
+    let files = match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok().filter(|meta| meta.is_file())?;
+                let time = config.age_basis.time_of(&meta).ok()?;
+                Some((entry.path(), time))
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => return HashSet::new(),
+    };
+
+    retained_files(&files, &config.retention, now(config))
+}
+
+/// Runs the --filter-exec command with the path as its last argument, a zero exit code
+/// allows the removal. The path and the metadata are passed in the WINTEMPCLEAN_*
+/// variables, times are in seconds since the Unix epoch. Commands that can't be run
+/// keep the entry.
+fn filter_exec_allows(
+    path: &Path,
+    meta: &fs::Metadata,
+    config: &Config,
+    stats: &mut Stats,
+) -> bool {
+    let command = match &config.filter_exec {
+        Some(command) => command,
+        None => return true,
+    };
+
+    let epoch_secs = |time: std::io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs().to_string())
+            .unwrap_or_default()
+    };
+
+    // The path is expanded from a variable, cmd would expand the variables in names
+    // spliced into its command line
+    let status = std::process::Command::new("cmd.exe")
+        .arg("/C")
+        .raw_arg(format!("{} \"%WINTEMPCLEAN_PATH%\"", command))
+        .env("WINTEMPCLEAN_PATH", path)
+        .env("WINTEMPCLEAN_IS_DIR", if meta.is_dir() { "1" } else { "0" })
+        .env("WINTEMPCLEAN_SIZE", meta.len().to_string())
+        .env("WINTEMPCLEAN_CREATED", epoch_secs(meta.created()))
+        .env("WINTEMPCLEAN_MODIFIED", epoch_secs(meta.modified()))
+        .env("WINTEMPCLEAN_ACCESSED", epoch_secs(meta.accessed()))
+        // Don't create a window for every entry
+        .creation_flags(CREATE_NO_WINDOW)
+        .status()
+        .with_context(|| format!("can't run the filter command for {}", path.display()));
+
+    match status {
+        Ok(status) if status.success() => true,
+        Ok(_) => {
+            debug!("Kept by the filter command {}", path.display());
+            false
+        }
+        Err(err) => {
+            record_error(err, path, config, stats);
+            false
+        }
+    }
+}
+
+/// Sums the sizes of the files directly within the directory, without recursing
+fn estimate_dir_size_fast(path: &Path) -> Result<u64> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        let meta = entry?.metadata()?;
+        if meta.is_file() {
+            size += meta.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Returns true for empty directories and zero-byte files
+fn is_empty_entry(path: &Path, meta: &fs::Metadata) -> bool {
+    if meta.is_dir() {
+        fs::read_dir(path)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false)
+    } else {
+        meta.len() == 0
+    }
+}
+
+/// Returns true if the file has other hard links, errors count as no links
+fn has_other_links(path: &Path) -> bool {
+    match link_count(path) {
+        Ok(count) => count > 1,
+        Err(err) => {
+            debug!("Can't read the links of {}: {}", path.display(), err);
+            false
+        }
+    }
+}
+
+/// Returns the size of the alternate data streams, errors count as no streams
+fn streams_size(path: &Path) -> u64 {
+    alternate_streams_size(path).unwrap_or_else(|err| {
+        debug!("Can't read the streams of {}: {}", path.display(), err);
+        0
+    })
+}
+
+/// Returns true if the extension of the file is one of --keep-extensions
+fn has_kept_extension(path: &Path, config: &Config) -> bool {
+    match path.extension() {
+        Some(extension) => config
+            .keep_extensions
+            .iter()
+            .any(|kept| extension.eq_ignore_ascii_case(kept)),
+        None => false,
+    }
+}
+
+/// Returns true for the files whose data is stored in the cloud, like OneDrive files
+/// available online only, which reading would download
+fn is_cloud_file(meta: &fs::Metadata) -> bool {
+    meta.file_attributes()
+        & (FILE_ATTRIBUTE_OFFLINE
+            | FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS
+            | FILE_ATTRIBUTE_RECALL_ON_OPEN)
+        != 0
+}
+
+/// Names and ids of the executables and DLLs loaded by the running processes
+struct RunningModules {
+    /// Lowercase file names, checked first to avoid opening every file
+    names: HashSet<String>,
+    ids: HashSet<(u32, u64)>,
+}
+
+/// Returns true if the file is loaded by a running process. The files are compared by
+/// id since the processes may have loaded them through a short 8.3 path.
+fn is_running_module(path: &Path) -> bool {
+    let modules = RUNNING_MODULES.get_or_init(|| {
+        let paths = loaded_module_paths().unwrap_or_else(|err| {
+            warn!("Unable to list the running processes: {}", err);
+            vec![]
+        });
+
+        RunningModules {
+            names: paths
+                .iter()
+                .filter_map(|path| path.file_name())
+                .map(|name| name.to_string_lossy().to_lowercase())
+                .collect(),
+            ids: paths.iter().filter_map(|path| file_id(path).ok()).collect(),
+        }
+    });
+
+    let named = path.file_name().is_some_and(|name| {
+        modules
+            .names
+            .contains(&name.to_string_lossy().to_lowercase())
+    });
+
+    named && file_id(path).is_ok_and(|id| modules.ids.contains(&id))
+}
+
+/// Returns true for the index files of a WinINet cache directory
+fn is_inetcache_index(path: &Path) -> bool {
+    let is_index = path.file_name().is_some_and(|name| {
+        name.eq_ignore_ascii_case("index.dat") || name.eq_ignore_ascii_case("desktop.ini")
+    });
+
+    is_index
+        && path
+            .ancestors()
+            .filter_map(|dir| dir.file_name())
+            .any(|name| name.eq_ignore_ascii_case("INetCache"))
+}
+
+/// Returns true if any process is using a file within the directory.
+/// Directories that can't be checked are considered in use.
+fn is_dir_busy(path: &Path, config: &Config, stats: &mut Stats) -> bool {
+    let busy = (|| -> Result<bool> {
+        let mut files = vec![];
+        collect_files(path, &mut files)?;
+
+        if files.is_empty() {
+            return Ok(false);
+        }
+
+        Ok(RestartManagerSession::start()?.count_users(&files)? > 0)
+    })();
+
+    match busy {
+        Ok(busy) => busy,
+        Err(err) => {
+            let err = err.context(format!(
+                "can't check if directory is in use {}",
+                path.display()
+            ));
+            record_error(err, path, config, stats);
+            true
+        }
+    }
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if the removal failed because the entry is locked or protected
+fn is_locked(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(is_locked_error)
+}
+
+fn schedule_reboot_deletion(path: &Path, config: &Config, stats: &mut Stats) {
+    let scheduled = delete_on_reboot(path)
+        .with_context(|| format!("failed to schedule reboot deletion {}", path.display()));
+
+    if let Err(err) = scheduled {
+        record_error(err, path, config, stats);
+    } else {
+        info!("{} scheduled for reboot deletion", path.display());
+        stats.scheduled_for_reboot += 1;
+    }
+}
+
+fn trim_entry(path: &Path, config: &Config, stats: &mut Stats) {
+    let dry_run_tag = if config.dry_run { " (dry run)" } else { "" };
+
+    debug!("Trimming{} {}", dry_run_tag, path.display());
+
+    if config.dry_run {
+        return;
+    }
+
+    let trimmed =
+        trim_sparse(path).with_context(|| format!("failed to trim file {}", path.display()));
+
+    match trimmed {
+        Ok(bytes) => {
+            stats.trimmed_bytes += bytes;
+            stats.trimmed_count += 1;
+        }
+        Err(err) => record_error(err, path, config, stats),
+    }
+}
+
+fn remove_entry(path: &Path, metadata: &fs::Metadata, config: &Config) -> Result<()> {
+    let dry_run_tag = if config.dry_run { " (dry run)" } else { "" };
+
+    debug!("Removing{} {}", dry_run_tag, path.display());
+
+    let mut perms = metadata.permissions();
+
+    // Unset readonly flag if present
+    let perms_changed = if perms.readonly() {
+        debug!("Disabling readonly flag");
+        // Only clears FILE_ATTRIBUTE_READONLY on Windows
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+        true
+    } else {
+        false
+    };
+
+    if !config.dry_run {
+        let owned_path = path.to_path_buf();
+        let perms = perms_changed.then_some(perms);
+        let rename_pending = config.rename_pending;
+
+        if let Some(dir) = quarantine_target(metadata, config) {
+            let dir = dir.to_path_buf();
+
+            return run_with_timeout(config.op_timeout, move || {
+                if let Some(perms) = perms {
+                    fs::set_permissions(&owned_path, perms).with_context(|| {
+                        format!(
+                            "failed to unset readonly permission {}",
+                            owned_path.display()
+                        )
+                    })?;
+                }
+
+                let compressed = quarantine_file(&owned_path, &dir)?;
+                debug!(
+                    "Quarantined {} compressed to {} bytes",
+                    owned_path.display(),
+                    compressed
+                );
+                Ok(())
+            })
+            .unwrap_or_else(|| Err(timed_out_error(path, "quarantining").into()));
+        }
+
+        return run_with_timeout(config.op_timeout, move || {
+            delete_path(&owned_path, perms, rename_pending)
+        })
+        .unwrap_or_else(|| Err(timed_out_error(path, "removing").into()));
+    }
+
+    if config.simulate_permissions {
+        match can_delete(path) {
+            Ok(()) => info!("Would delete (OK) {}", path.display()),
+            Err(err) => {
+                info!("Would delete (PERMISSION DENIED) {}", path.display());
+                // Not an io::Error, so it's never scheduled for deletion at reboot
+                bail!("removal would fail {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the quarantine directory if the file is large enough for --compress-before-delete
+fn quarantine_target<'a>(metadata: &fs::Metadata, config: &'a Config) -> Option<&'a Path> {
+    match (config.compress_before_delete, &config.quarantine_dir) {
+        (Some(min_size), Some(dir)) if metadata.is_file() && metadata.len() >= min_size => {
+            Some(dir)
+        }
+        _ => None,
+    }
+}
+
+/// Removes the file or directory, applying the changed permissions first
+fn delete_path(path: &Path, perms: Option<fs::Permissions>, rename_pending: bool) -> Result<()> {
+    // Apply changed permissions
+    if let Some(perms) = perms {
+        if let Err(err) = fs::set_permissions(path, perms)
+            .with_context(|| format!("failed to unset readonly permission {}", path.display()))
+        {
+            bail!(err)
+        }
+    }
+
+    if path.is_dir() {
+        // Remove dir and return
+        remove_dir_retrying(path, rename_pending)
+            .with_context(|| format!("failed to remove directory {}", path.display()))
+    } else {
+        // Remove file and return
+        fs::remove_file(path).with_context(|| format!("failed to remove file {}", path.display()))
+    }
+}
+
+fn read_metadata(path: &Path, config: &Config) -> Result<fs::Metadata> {
+    let owned_path = path.to_path_buf();
+
+    run_with_timeout(config.op_timeout, move || fs::metadata(owned_path))
+        .unwrap_or_else(|| Err(timed_out_error(path, "reading the metadata of")))
+        .with_context(|| format!("can't read metadata {}", path.display()))
+}
+
+/// Runs the filesystem operation on another thread with --op-timeout, returning None if
+/// it didn't complete in time. The thread of a hung operation is left behind.
+fn run_with_timeout<T: Send + 'static>(
+    timeout: Option<Duration>,
+    op: impl FnOnce() -> T + Send + 'static,
+) -> Option<T> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Some(op()),
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // The receiver is gone if the operation timed out
+        let _ = sender.send(op());
+    });
+
+    receiver.recv_timeout(timeout).ok()
+}
+
+fn timed_out_error(path: &Path, operation: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("timed out {} {}", operation, path.display()),
+    )
+}
+
+/// Counts the error, the operations that timed out are only warned about
+fn count_error(err: anyhow::Error, path: &Path, config: &Config, stats: &mut Stats) {
+    let timed_out = err
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|err| err.kind() == std::io::ErrorKind::TimedOut);
+
+    if timed_out {
+        warn!("{}, skipping it", err.root_cause());
+        stats.timed_out += 1;
+    } else {
+        record_error(err, path, config, stats);
+    }
+}
+
+/// Counts the error and logs it, or keeps it for the report of --histogram json so the
+/// errors of the entries end up in the JSON document
+fn record_error(err: anyhow::Error, path: &Path, config: &Config, stats: &mut Stats) {
+    stats.errors_total += 1;
+
+    if matches!(config.histogram, Some(OutputFormat::Json)) {
+        stats.errors.push(error_json(&err, Some(path)));
+    } else {
+        print_err(err);
+    }
+}
+
+/// Removes the directory, retrying once when it still has entries: the files just
+/// removed may linger until another process closes its handles to them. With
+/// --rename-pending a directory that can't be removed yet is renamed, so a later run
+/// removes it regardless of the date filters.
+fn remove_dir_retrying(path: &Path, rename_pending: bool) -> std::io::Result<()> {
+    match fs::remove_dir(path) {
+        Err(err) if is_dir_not_empty_error(&err) => {}
+        result => return result,
+    }
+
+    thread::sleep(DIR_RETRY_DELAY);
+
+    let remaining = fs::read_dir(path)
+        .map(|entries| entries.count())
+        .unwrap_or(0);
+    debug!(
+        "Retrying removal of {} with {} entries left",
+        path.display(),
+        remaining
+    );
+
+    let err = match fs::remove_dir(path) {
+        Err(retry_err) if is_dir_not_empty_error(&retry_err) => retry_err,
+        result => return result,
+    };
+
+    if !rename_pending {
+        return Err(err);
+    }
+
+    let mut pending = path.as_os_str().to_os_string();
+    pending.push(PENDING_DELETE_SUFFIX);
+
+    fs::rename(path, &pending).map_err(|_| err)?;
+    info!(
+        "Renamed {} to {} to remove it later",
+        path.display(),
+        Path::new(&pending).display()
+    );
+
+    Ok(())
+}
+
+/// Returns true for the directories renamed by --rename-pending
+fn is_pending_delete(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().ends_with(PENDING_DELETE_SUFFIX))
+        .unwrap_or(false)
+}
+
+fn passes_date_filters(
+    path: &Path,
+    meta: &fs::Metadata,
+    config: &Config,
+    stats: &mut Stats,
+) -> bool {
+    match check_date_filters(path, meta, config) {
+        Ok(passes) => passes,
+        Err(err) => {
+            let err = err.context(format!("can't read the age of {}", path.display()));
+            record_error(err, path, config, stats);
+            false
+        }
+    }
+}
+
+/// Returns true if the entry passes the date filters, failing if a timestamp they need
+/// can't be read
+fn check_date_filters(path: &Path, meta: &fs::Metadata, config: &Config) -> Result<bool> {
+    if config.since.is_some() || config.created_after.is_some() {
+        // A date in the name is trusted over the timestamps
+        let age = match name_age(path, config) {
+            Some(age) => age,
+            None => entry_age(meta, config)?,
+        };
+
+        if config.since.is_some_and(|since| age < since) {
+            return Ok(false);
+        }
+
+        if config.created_after.is_some_and(|max_age| age > max_age) {
+            return Ok(false);
+        }
+    }
+
+    if let Some(idle) = config.idle {
+        if idle_time(meta, config)? < idle {
+            return Ok(false);
+        }
+    }
+
+    if let Some(time) = config.task_created {
+        if !create_date_not_after(meta, time)? {
+            return Ok(false);
+        }
+    }
+
+    // The rule of the extension can only make the file wait longer
+    if let Some(min_age) = extension_rule_age(path, meta, config) {
+        let age = match name_age(path, config) {
+            Some(age) => age,
+            None => entry_age(meta, config)?,
+        };
+        if age < min_age {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns the minimum age of the --extension-rule with the longest extension matching
+/// the file name, ignoring case
+fn extension_rule_age(path: &Path, meta: &fs::Metadata, config: &Config) -> Option<Duration> {
+    if config.extension_rules.is_empty() || !meta.is_file() {
+        return None;
+    }
+
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+
+    config
+        .extension_rules
+        .iter()
+        .flat_map(|rule| {
+            rule.extensions
+                .iter()
+                .map(move |extension| (extension, rule.created_before))
+        })
+        .filter(|(extension, _)| {
+            name.strip_suffix(extension.as_str())
+                .is_some_and(|stem| stem.len() > 1 && stem.ends_with('.'))
+        })
+        .max_by_key(|(extension, _)| extension.len())
+        .map(|(_, created_before)| created_before)
+}
+
+fn create_date_not_after(meta: &fs::Metadata, time: SystemTime) -> Result<bool> {
+    Ok(meta.created()? <= time)
+}
+
+/// Returns the time elapsed since the latest of the creation, modification and access
+/// times, timestamps in the future count as just now
+fn idle_time(meta: &fs::Metadata, config: &Config) -> Result<Duration> {
+    let latest = meta.created()?.max(meta.modified()?).max(meta.accessed()?);
+
+    Ok(now(config).duration_since(latest).unwrap_or_default())
+}
+
+/// Returns the time elapsed since the date in the name matching --age-from-name, if any
+fn name_age(path: &Path, config: &Config) -> Option<Duration> {
+    let pattern = config.age_from_name.as_ref()?;
+    let date = pattern.date_in(&path.file_name()?.to_string_lossy())?;
+
+    // Dates in the future count as just now
+    Some(now(config).duration_since(date).unwrap_or_default())
+}
+
+/// Returns the time elapsed since the timestamp selected by the age basis
+fn entry_age(meta: &fs::Metadata, config: &Config) -> Result<Duration> {
+    Ok(now(config).duration_since(config.age_basis.time_of(meta)?)?)
+}
+
+/// Returns the time the ages are measured from, fixed by --now
+fn now(config: &Config) -> SystemTime {
+    config.now.unwrap_or_else(SystemTime::now)
+}
+
+fn format_bytes(bytes: f64, format: BytesFormat) -> String {
+    let (thousands, decimal) = number_separators();
+    format_bytes_with(bytes, format, (&thousands, &decimal))
+}
+
+/// Formats like format_bytes with the given thousands and decimal separators
+fn format_bytes_with(bytes: f64, format: BytesFormat, separators: (&str, &str)) -> String {
+    // NaN and infinity have no unit to scale to
+    if !bytes.is_finite() {
+        return format!("{} B", bytes);
+    }
+
+    match format {
+        BytesFormat::Iec => format_bytes_iec(bytes, separators),
+        BytesFormat::Si => format_bytes_si(bytes, separators),
+    }
+}
+
+// https://www.sqlservercentral.com/blogs/powershell-using-exponents-and-logs-to-format-byte-sizes
+fn format_bytes_iec(bytes: f64, separators: (&str, &str)) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let negative_sign = if bytes.is_sign_negative() { "-" } else { "" };
+    let bytes = bytes.abs();
+
+    if bytes < 1_f64 {
+        return format!("{}{} {}", negative_sign, bytes, "B");
+    }
+
+    let pow2 = (bytes.ln() / 2_f64.ln()).floor();
+    let idx = (pow2 / 10.0).floor().min((units.len() - 1) as f64) as i32;
+    let scaled = bytes / 2_f64.powi(idx * 10);
+    let unit = units[idx as usize];
+
+    format!(
+        "{}{} {}",
+        negative_sign,
+        format_number(scaled, separators),
+        unit
+    )
+}
+
+fn format_bytes_si(bytes: f64, separators: (&str, &str)) -> String {
+    let units = ["B", "kB", "MB", "GB", "TB", "PB"];
+    let negative_sign = if bytes.is_sign_negative() { "-" } else { "" };
+    let bytes = bytes.abs();
+
+    if bytes < 1_f64 {
+        return format!("{}{} {}", negative_sign, bytes, "B");
+    }
+
+    let pow10 = bytes.log10().floor();
+    let idx = (pow10 / 3.0).floor().min((units.len() - 1) as f64) as i32;
+    let scaled = bytes / 10_f64.powi(idx * 3);
+    let unit = units[idx as usize];
+
+    format!(
+        "{}{} {}",
+        negative_sign,
+        format_number(scaled, separators),
+        unit
+    )
+}
+
+/// Parses a size like 1024, 10MiB or 1.5 GB into bytes, both IEC and SI units are accepted
+fn parse_bytes(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let unit_start = value
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+
+    let number = number
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("Invalid size {}", value))?;
+
+    if !number.is_finite() || number < 0.0 {
+        bail!("Invalid size {}", value);
+    }
+
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        "pib" => 1 << 50,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "pb" => 1_000_000_000_000_000,
+        _ => bail!("Invalid size unit {} in {}", unit, value),
+    };
+
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Formats the positive number with two decimals and the thousands and decimal separators
+fn format_number(value: f64, (thousands, decimal): (&str, &str)) -> String {
+    let formatted = format!("{:.2}", value);
+    let (integer, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+
+    let mut grouped = String::new();
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push_str(thousands);
+        }
+        grouped.push(digit);
+    }
+
+    format!("{}{}{}", grouped, decimal, fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, create_junction, set_file_ages, temp_dir};
+    use winapi::um::winnt::{FILE_CASE_SENSITIVE_SEARCH, FILE_READ_ONLY_VOLUME};
+
+    #[test]
+    fn hard_linked_files_free_no_space() {
+        let dir = temp_dir("hard-links");
+        let config = config(&[]);
+        let mut state = WalkState::new();
+        let mut stats = Stats::new();
+
+        let linked = dir.join("linked.tmp");
+        fs::write(&linked, [0; 100]).unwrap();
+        fs::hard_link(&linked, dir.join("other.tmp")).unwrap();
+        let single = dir.join("single.tmp");
+        fs::write(&single, [0; 30]).unwrap();
+
+        for path in [&linked, &single] {
+            let meta = fs::metadata(path).unwrap();
+            remove_candidate(
+                path,
+                &meta,
+                meta.len(),
+                false,
+                &config,
+                &mut state,
+                &mut stats,
+            );
+        }
+
+        assert!(!linked.exists() && !single.exists());
+        assert_eq!(stats.removed_count, 2);
+        assert_eq!(stats.shared_bytes, 100);
+        assert_eq!(stats.removed_bytes, 30);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn allowed_link_ignores_case_and_separators() {
+        let config = config(&["--follow-reparse-path", r"C:\Temp\Cache\"]);
+        assert!(is_allowed_link(Path::new(r"c:\temp\cache"), &config));
+        assert!(!is_allowed_link(Path::new(r"C:\Temp\Cache2"), &config));
+        assert!(!is_allowed_link(Path::new(r"C:\Temp"), &config));
+    }
+
+    #[test]
+    fn only_allowed_junctions_are_followed() {
+        let dir = temp_dir("junctions");
+        let clean = dir.join("clean");
+        fs::create_dir(&clean).unwrap();
+        for name in ["allowed", "other"] {
+            let target = dir.join(format!("{}-target", name));
+            fs::create_dir(&target).unwrap();
+            fs::write(target.join("old.tmp"), b"content").unwrap();
+            create_junction(&clean.join(name), &target);
+        }
+
+        let allowed = clean.join("allowed");
+        let config = config(&["--follow-reparse-path", &allowed.to_string_lossy()]);
+        remove_dir_contents(&clean, &config, false, &mut WalkState::new()).unwrap();
+
+        // The junctions themselves are left in place
+        assert!(allowed.exists() && clean.join("other").exists());
+        assert!(!dir.join("allowed-target").join("old.tmp").exists());
+        assert!(dir.join("other-target").join("old.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_root_does_not_stop_the_others() {
+        let dir = temp_dir("unreadable-root");
+        let first = dir.join("first");
+        let second = dir.join("second");
+        // Reading a file as a directory fails
+        let unreadable = dir.join("file.tmp");
+        for root in [&first, &second] {
+            fs::create_dir(root).unwrap();
+            fs::write(root.join("old.tmp"), b"content").unwrap();
+        }
+        fs::write(&unreadable, b"content").unwrap();
+
+        let roots = vec![
+            (first.clone(), false),
+            (unreadable.clone(), false),
+            (second.clone(), false),
+        ];
+        let results = clean_parallel(roots, 1, &config(&[]));
+
+        assert_eq!(results.len(), 3);
+        let total = results
+            .into_iter()
+            .fold(Stats::new(), |total, (_, stats)| total + stats);
+        assert_eq!(total.removed_files, 2);
+        assert_eq!(total.dirs_failed, 1);
+        assert_eq!(total.errors_total, 1);
+        assert!(!first.join("old.tmp").exists() && !second.join("old.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const SEPARATORS: (&str, &str) = (",", ".");
+
+    #[test]
+    fn format_bytes_iec_units() {
+        let cases = [
+            (0.0, "0 B"),
+            (0.5, "0.5 B"),
+            (1.0, "1.00 B"),
+            (1023.0, "1,023.00 B"),
+            (1024.0, "1.00 KiB"),
+            (1536.0, "1.50 KiB"),
+            (1048576.0, "1.00 MiB"),
+            (5.5 * (1u64 << 30) as f64, "5.50 GiB"),
+            ((1u64 << 50) as f64, "1.00 PiB"),
+            ((1u64 << 60) as f64, "1,024.00 PiB"),
+            (-2048.0, "-2.00 KiB"),
+            (f64::NAN, "NaN B"),
+            (f64::INFINITY, "inf B"),
+        ];
+
+        for (bytes, expected) in cases {
+            assert_eq!(
+                format_bytes_with(bytes, BytesFormat::Iec, SEPARATORS),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn format_bytes_si_units() {
+        let cases = [
+            (999.0, "999.00 B"),
+            (1000.0, "1.00 kB"),
+            (1_500_000.0, "1.50 MB"),
+            (2_250_000_000.0, "2.25 GB"),
+            (1e18, "1,000.00 PB"),
+        ];
+
+        for (bytes, expected) in cases {
+            assert_eq!(
+                format_bytes_with(bytes, BytesFormat::Si, SEPARATORS),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn format_number_separators() {
+        assert_eq!(format_number(1234567.891, SEPARATORS), "1,234,567.89");
+        assert_eq!(format_number(1234567.891, (".", ",")), "1.234.567,89");
+        assert_eq!(
+            format_number(1234567.891, ("\u{a0}", ",")),
+            "1\u{a0}234\u{a0}567,89"
+        );
+        assert_eq!(format_number(123.0, (".", ",")), "123,00");
+        assert_eq!(
+            format_bytes_with(1_234_567.0, BytesFormat::Iec, (".", ",")),
+            "1,18 MiB"
+        );
+    }
+
+    #[test]
+    fn parse_bytes_units() {
+        let cases = [
+            ("0", 0),
+            ("1024", 1024),
+            ("1.5", 2),
+            ("10MiB", 10 << 20),
+            (" 2 kib ", 2048),
+            ("1.5 GB", 1_500_000_000),
+            ("3TiB", 3 << 40),
+            ("1PB", 1_000_000_000_000_000),
+            ("512 b", 512),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(parse_bytes(value).unwrap(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_bytes_invalid() {
+        for value in ["", "MB", "-1MB", "10XB", "1e3", "inf", "1,5 GB"] {
+            assert!(parse_bytes(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_bytes_reads_format_bytes() {
+        let values = [
+            0,
+            1,
+            512,
+            1023,
+            1024,
+            1536,
+            10 << 20,
+            123_456_789,
+            5_000_000_000_000,
+        ];
+
+        for format in [BytesFormat::Iec, BytesFormat::Si] {
+            for bytes in values {
+                let formatted = format_bytes_with(bytes as f64, format, ("", "."));
+                let parsed = parse_bytes(&formatted).unwrap();
+                // Two decimals of the largest unit below the size
+                assert!(
+                    parsed.abs_diff(bytes) <= bytes / 200,
+                    "{} parsed as {}",
+                    formatted,
+                    parsed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dedupe_keeps_the_first_copy_and_protected_files() {
+        let dir = temp_dir("dedupe").join("INetCache");
+        fs::create_dir(&dir).unwrap();
+        let config = config(&[
+            "--dedupe",
+            "--preserve-inetcache-index",
+            "--created-before",
+            "1d",
+        ]);
+        for name in ["a.dat", "b.dat", "c.tmp", "index.dat"] {
+            fs::write(dir.join(name), b"content").unwrap();
+        }
+        fs::write(dir.join("d.tmp"), b"other content").unwrap();
+
+        let stats = remove_dir_contents(&dir, &config, false, &mut WalkState::new()).unwrap();
+
+        assert_eq!(stats.deduped_count, 2);
+        assert!(dir.join("a.dat").exists());
+        assert!(!dir.join("b.dat").exists());
+        assert!(!dir.join("c.tmp").exists());
+        assert!(dir.join("d.tmp").exists());
+        // The cache index is a copy too but is preserved
+        assert!(dir.join("index.dat").exists());
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn resumed_walk_skips_completed_directories() {
+        let dir = temp_dir("resume");
+        let clean = dir.join("clean");
+        for name in ["done", "left"] {
+            fs::create_dir_all(clean.join(name)).unwrap();
+            fs::write(clean.join(name).join("old.tmp"), b"content").unwrap();
+        }
+        // The interrupted run completed the first directory
+        let state_file = dir.join("checkpoint.txt");
+        fs::write(&state_file, format!("{}\n", clean.join("done").display())).unwrap();
+
+        let config = config(&[]);
+        let mut state = WalkState::new();
+        state.checkpoint = Some(Checkpoint::open(&state_file).unwrap());
+        let stats = remove_dir_contents(&clean, &config, false, &mut state).unwrap();
+
+        assert!(clean.join("done").join("old.tmp").exists());
+        assert!(!clean.join("left").exists());
+        assert_eq!(stats.removed_files, 1);
+        assert!(state.is_done(&clean.join("left")));
+
+        drop(state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn summary_severity_by_errors() {
+        let severity = |removed_count, errors_total| {
+            let mut stats = Stats::new();
+            stats.removed_count = removed_count;
+            stats.errors_total = errors_total;
+            summary_severity(&stats)
+        };
+
+        assert!(severity(0, 0) == Severity::Ok);
+        assert!(severity(10, 0) == Severity::Ok);
+        assert!(severity(10, 1) == Severity::Warning);
+        assert!(severity(10, 9) == Severity::Warning);
+        assert!(severity(10, 10) == Severity::Failed);
+        assert!(severity(0, 1) == Severity::Failed);
+    }
+
+    #[test]
+    fn idle_keeps_recently_accessed_files() {
+        let dir = temp_dir("idle");
+        let day = Duration::from_secs(24 * 60 * 60);
+        let config = config(&["--idle", "7d"]);
+
+        let accessed = dir.join("accessed.tmp");
+        fs::write(&accessed, b"content").unwrap();
+        set_file_ages(&accessed, 30 * day, 30 * day, day);
+
+        let modified = dir.join("modified.tmp");
+        fs::write(&modified, b"content").unwrap();
+        set_file_ages(&modified, 30 * day, 2 * day, 30 * day);
+
+        let idle = dir.join("idle.tmp");
+        fs::write(&idle, b"content").unwrap();
+        set_file_ages(&idle, 30 * day, 20 * day, 10 * day);
+
+        let passes = |path: &Path| {
+            passes_date_filters(
+                path,
+                &fs::metadata(path).unwrap(),
+                &config,
+                &mut Stats::new(),
+            )
+        };
+        assert!(!passes(&accessed));
+        assert!(!passes(&modified));
+        assert!(passes(&idle));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_dir_retrying_fails_on_non_empty_directory() {
+        let dir = temp_dir("remove-dir-retrying");
+        let path = dir.join("busy");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("left.tmp"), b"content").unwrap();
+
+        let err = remove_dir_retrying(&path, false).unwrap_err();
+        assert!(is_dir_not_empty_error(&err));
+        assert!(path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_dir_retrying_renames_with_rename_pending() {
+        let dir = temp_dir("remove-dir-renaming");
+        let path = dir.join("busy");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("left.tmp"), b"content").unwrap();
+
+        remove_dir_retrying(&path, true).unwrap();
+
+        let pending = dir.join(format!("busy{}", PENDING_DELETE_SUFFIX));
+        assert!(!path.exists());
+        assert!(pending.join("left.tmp").exists());
+        assert!(is_pending_delete(&pending));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_dir_retrying_removes_empty_directory() {
+        let dir = temp_dir("remove-dir-empty");
+        let path = dir.join("empty");
+        fs::create_dir(&path).unwrap();
+
+        remove_dir_retrying(&path, false).unwrap();
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn filter_exec_keeps_matching_entries() {
+        let dir = temp_dir("filter-exec");
+        let script = dir.join("filter.cmd");
+        // The variable is expanded once, unlike the arguments of call
+        fs::write(
+            &script,
+            "@set \"entry=%WINTEMPCLEAN_PATH%\"\r\n\
+            @if not \"%entry:keep=%\"==\"%entry%\" exit /b 1\r\n\
+            @exit /b 0\r\n",
+        )
+        .unwrap();
+        // Expanded in the name if it were spliced in the command line
+        env::set_var("WINTEMPCLEAN_TEST_NAME", "keep");
+
+        let clean = dir.join("clean");
+        fs::create_dir_all(clean.join("keepdir")).unwrap();
+        fs::write(clean.join("keepdir").join("old.tmp"), b"content").unwrap();
+        fs::write(clean.join("keep.tmp"), b"content").unwrap();
+        fs::write(clean.join("old.tmp"), b"content").unwrap();
+        fs::write(clean.join("%WINTEMPCLEAN_TEST_NAME%.tmp"), b"content").unwrap();
+
+        let command = format!("call \"{}\"", script.display());
+        let config = config(&["--filter-exec", &command]);
+        remove_dir_contents(&clean, &config, false, &mut WalkState::new()).unwrap();
+
+        assert!(clean.join("keep.tmp").exists());
+        assert!(clean.join("keepdir").join("old.tmp").exists());
+        assert!(!clean.join("old.tmp").exists());
+        assert!(!clean.join("%WINTEMPCLEAN_TEST_NAME%.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stats_display_and_sum() {
+        let mut first = Stats::new();
+        first.removed_files = 3;
+        first.removed_bytes = 1 << 20;
+        first.errors_total = 1;
+        first.subtotals.insert(PathBuf::from("a"), (3, 1 << 20));
+
+        let mut second = Stats::new();
+        second.removed_files = 2;
+        second.removed_bytes = 1 << 20;
+        second.subtotals.insert(PathBuf::from("a"), (2, 1 << 20));
+
+        let total = first + second;
+        assert_eq!(total.removed_files, 5);
+        assert_eq!(total.subtotals[Path::new("a")], (5, 2 << 20));
+        // The decimal separator is the one of the user locale
+        assert_eq!(
+            format!("{}", total).replace(&number_separators().1, "."),
+            "5 files (2.00 MiB), 1 errors"
+        );
+    }
+
+    #[test]
+    fn walking_time_includes_the_deletions() {
+        let dir = temp_dir("timings");
+        let config = config(&[]);
+        for name in ["1.tmp", "2.tmp", "3.tmp"] {
+            fs::write(dir.join(name), b"content").unwrap();
+        }
+        let mut state = WalkState::new();
+
+        let started = Instant::now();
+        clean_directory(&dir, &config, &mut state);
+        let walking = started.elapsed();
+
+        // The deletions are timed within the walk, which must be corrected for them
+        assert!(state.timings.deletion > Duration::ZERO);
+        assert!(state.timings.deletion <= walking);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sentinel_protects_directory() {
+        let dir = temp_dir("sentinel");
+        let config = config(&[]);
+        fs::create_dir(dir.join("app")).unwrap();
+        fs::write(dir.join("app").join(".keep"), b"").unwrap();
+        fs::write(dir.join("app").join("state.dat"), b"content").unwrap();
+        fs::write(dir.join("old.tmp"), b"content").unwrap();
+
+        remove_dir_contents(&dir, &config, false, &mut WalkState::new()).unwrap();
+
+        assert!(dir.join("app").join("state.dat").exists());
+        assert!(!dir.join("old.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_sentinel_keeps_parents() {
+        let dir = temp_dir("nested-sentinel");
+        let config = config(&["--rename-pending"]);
+        let nested = dir.join("app").join("cache").join("keep");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(nested.join(".keep"), b"").unwrap();
+        fs::write(nested.join("state.dat"), b"content").unwrap();
+        fs::write(dir.join("app").join("old.tmp"), b"content").unwrap();
+        fs::create_dir(dir.join("other")).unwrap();
+
+        let mut state = WalkState::new();
+        remove_dir_contents(&dir, &config, false, &mut state).unwrap();
+
+        assert!(nested.join("state.dat").exists());
+        assert!(!dir.join("app").join("old.tmp").exists());
+        assert!(!dir.join("other").exists());
+        assert!(state.holds_protected(&dir.join("app")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delete_list_keeps_sentinel_directories() {
+        let dir = temp_dir("delete-list-sentinel");
+        let config = config(&[]);
+        let app = dir.join("app");
+        fs::create_dir(&app).unwrap();
+        fs::write(app.join(".keep"), b"").unwrap();
+        fs::write(app.join("state.dat"), b"content").unwrap();
+        fs::write(dir.join("old.tmp"), b"content").unwrap();
+        let list = temp_dir("delete-list-sentinel-list").join("list.txt");
+        let listed = [&app, &app.join("state.dat"), &dir.join("old.tmp")];
+        let lines = listed
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>();
+        fs::write(&list, lines.join("\r\n")).unwrap();
+
+        let stats = remove_listed(
+            &list,
+            &[(dir.clone(), false)],
+            &config,
+            &mut WalkState::new(),
+        )
+        .unwrap();
+
+        assert_eq!(stats.removed_count, 1);
+        assert!(app.join("state.dat").exists());
+        assert!(!dir.join("old.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(list.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn dir_max_size_keeps_sentinel_directories() {
+        let dir = temp_dir("dir-max-size-sentinel");
+        let config = config(&["--dir-max-size", "250"]);
+        let day = Duration::from_secs(24 * 60 * 60);
+        let app = dir.join("app");
+        fs::create_dir(&app).unwrap();
+        fs::write(app.join(".keep"), b"").unwrap();
+        // The protected file is the oldest
+        let files = [
+            dir.join("new.tmp"),
+            dir.join("old.tmp"),
+            app.join("state.dat"),
+        ];
+        for (age, file) in (1..).zip(&files) {
+            fs::write(file, [0; 100]).unwrap();
+            set_file_ages(file, age * day, age * day, age * day);
+        }
+
+        let mut stats = Stats::new();
+        cap_dir_size(&dir, 250, &config, &mut WalkState::new(), &mut stats);
+
+        assert_eq!(stats.removed_count, 1);
+        assert!(dir.join("new.tmp").exists());
+        assert!(!dir.join("old.tmp").exists());
+        assert!(app.join("state.dat").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_counts_the_capped_files_once() {
+        let dir = temp_dir("dir-max-size-dry-run");
+        let config = config(&["--dry-run", "--dir-max-size", "150"]);
+        for name in ["1.tmp", "2.tmp", "3.tmp"] {
+            fs::write(dir.join(name), [0; 100]).unwrap();
+        }
+
+        let stats = clean_directory(&dir, &config, &mut WalkState::new());
+
+        assert_eq!(stats.removed_count, 3);
+        assert!(dir.join("1.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn free_space_delta() {
+        let gib = 1u64 << 30;
+        assert_eq!(
+            describe_free_space(10 * gib, 12 * gib, 2 * gib, BytesFormat::Iec, SEPARATORS),
+            "10.00 GiB before, 12.00 GiB after, 2.00 GiB gained (2.00 GiB removed)"
+        );
+        // Other processes wrote more than was removed
+        assert_eq!(
+            describe_free_space(10 * gib, 9 * gib, gib / 2, BytesFormat::Iec, SEPARATORS),
+            "10.00 GiB before, 9.00 GiB after, -1.00 GiB gained (512.00 MiB removed)"
+        );
+        assert_eq!(
+            describe_free_space(
+                1_800_000_000,
+                3_600_000_000,
+                2_000_000_000,
+                BytesFormat::Si,
+                SEPARATORS
+            ),
+            "1.80 GB before, 3.60 GB after, 1.80 GB gained (2.00 GB removed)"
+        );
+    }
+
+    #[test]
+    fn dir_name_pattern_removes_only_matching_directories() {
+        let dir = temp_dir("dir-name-pattern");
+        let guid = "{3F2504E0-4F89-11D3-9A0C-0305E82C3301}";
+        for name in [guid, "0B5E7A4C-1D2E-4F60-8A9B-C0D1E2F3A4B5", "AppName"] {
+            fs::create_dir_all(dir.join(name).join("sub")).unwrap();
+            fs::write(dir.join(name).join("sub").join("old.tmp"), b"content").unwrap();
+        }
+
+        let config = config(&[
+            "--dir-name-pattern",
+            r"^\{?[0-9A-Fa-f]{8}(-[0-9A-Fa-f]{4}){3}-[0-9A-Fa-f]{12}\}?$",
+        ]);
+        remove_dir_contents(&dir, &config, false, &mut WalkState::new()).unwrap();
+
+        assert!(!dir.join(guid).exists());
+        assert!(!dir.join("0B5E7A4C-1D2E-4F60-8A9B-C0D1E2F3A4B5").exists());
+        // Named application directories are kept with their contents
+        assert!(dir.join("AppName").join("sub").join("old.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn age_from_name_overrides_timestamps() {
+        let dir = temp_dir("age-from-name");
+        let config = config(&["--created-before", "30d", "--age-from-name", "log_%Y%m%d"]);
+
+        // Written today, but the name says it's from 2020
+        let dated = dir.join("log_20200101.tmp");
+        fs::write(&dated, b"content").unwrap();
+        let undated = dir.join("log.tmp");
+        fs::write(&undated, b"content").unwrap();
+
+        let passes = |path: &Path| {
+            passes_date_filters(
+                path,
+                &fs::metadata(path).unwrap(),
+                &config,
+                &mut Stats::new(),
+            )
+        };
+        assert!(passes(&dated));
+        assert!(!passes(&undated));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Creates an offline installation with the system temporary directory and the
+    /// temporary directories of the given profiles
+    fn offline_root(name: &str, profiles: &[&str]) -> PathBuf {
+        let root = temp_dir(name);
+        fs::create_dir_all(root.join(r"Windows\Temp")).unwrap();
+        for profile in profiles {
+            fs::create_dir_all(root.join("Users").join(profile).join(r"AppData\Local\Temp"))
+                .unwrap();
+        }
+        root
+    }
+
+    /// Returns the sorted directories to clean, relative to the offline root
+    fn relative_roots(root: &Path, args: &[&str]) -> Vec<String> {
+        let mut args = args.to_vec();
+        let root_arg = root.to_string_lossy();
+        args.extend(["--root", &root_arg]);
+
+        let mut roots = get_roots(&config(&args))
+            .unwrap()
+            .iter()
+            .map(|(path, _)| {
+                let relative = path.strip_prefix(root).unwrap().display().to_string();
+                relative.trim_end_matches('\\').to_string()
+            })
+            .collect::<Vec<_>>();
+        roots.sort();
+        roots
+    }
+
+    #[test]
+    fn roots_of_offline_installation() {
+        let root = offline_root("roots", &["alice", "bob"]);
+
+        assert_eq!(
+            relative_roots(&root, &[]),
+            [
+                r"ProgramData\Temp",
+                r"Users\alice\AppData\Local\Temp",
+                r"Users\bob\AppData\Local\Temp",
+                r"Windows\Temp",
+            ]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sequential_cleaning_continues_after_an_unreadable_root() {
+        let root = offline_root("sequential-roots", &["alice", "bob"]);
+        // Reading a file as a directory fails
+        let unreadable = root.join(r"Users\alice\AppData\Local\Temp");
+        fs::remove_dir(&unreadable).unwrap();
+        fs::write(&unreadable, b"content").unwrap();
+        let readable = [r"Windows\Temp", r"Users\bob\AppData\Local\Temp"].map(|dir| root.join(dir));
+        for dir in &readable {
+            fs::write(dir.join("old.tmp"), b"content").unwrap();
+        }
+
+        let root_arg = root.to_string_lossy();
+        let total = begin_cleaning(&config(&["--root", &root_arg])).unwrap();
+
+        assert_eq!(total.removed_files, 2);
+        assert_eq!(total.dirs_failed, 1);
+        assert_eq!(total.errors_total, 1);
+        assert!(readable.iter().all(|dir| !dir.join("old.tmp").exists()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dir_max_size_keeps_the_newest_files() {
+        let dir = temp_dir("dir-max-size");
+        let config = config(&["--dir-max-size", "250"]);
+        let day = Duration::from_secs(24 * 60 * 60);
+        fs::create_dir(dir.join("sub")).unwrap();
+        let files = [
+            dir.join("1.tmp"),
+            dir.join("sub").join("2.tmp"),
+            dir.join("3.tmp"),
+            dir.join("sub").join("4.tmp"),
+            dir.join("5.tmp"),
+        ];
+        // The first file is the newest
+        for (age, file) in (1..).zip(&files) {
+            fs::write(file, [0; 100]).unwrap();
+            set_file_ages(file, age * day, age * day, age * day);
+        }
+
+        let mut stats = Stats::new();
+        cap_dir_size(&dir, 250, &config, &mut WalkState::new(), &mut stats);
+
+        assert_eq!(stats.removed_count, 3);
+        assert!(files[..2].iter().all(|file| file.exists()));
+        assert!(files[2..].iter().all(|file| !file.exists()));
+
+        // A directory within the cap is left alone
+        let mut stats = Stats::new();
+        cap_dir_size(&dir, 250, &config, &mut WalkState::new(), &mut stats);
+        assert_eq!(stats.removed_count, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn system_dirs_are_refused() {
+        let config = config(&["--abort-on-system-dir"]);
+        let check = |path: &str| check_system_dirs(&[(PathBuf::from(path), false)], &config);
+
+        for path in [
+            r"C:\Windows\System32",
+            r"c:\windows\system32\",
+            r"C:\Windows\System32\drivers",
+            r"C:\Program Files\App\Temp",
+            r"C:\Windows",
+            r"C:\Users",
+            r"C:\",
+        ] {
+            assert!(check(path).is_err(), "{}", path);
+        }
+
+        for path in [
+            r"C:\Windows\Temp",
+            r"C:\Users\alice\AppData\Local\Temp",
+            r"C:\ProgramData\Temp",
+            r"C:\Windows\System32Backup",
+        ] {
+            assert!(check(path).is_ok(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn protect_dir_keeps_the_subtree() {
+        let dir = temp_dir("protect-dir");
+        let config = config(&["--protect-dir", r"Vendor\Cache"]);
+        let protected = dir.join("Vendor").join("Cache");
+        fs::create_dir_all(protected.join("data")).unwrap();
+        fs::write(protected.join("data").join("state.dat"), b"content").unwrap();
+        fs::write(dir.join("Vendor").join("old.tmp"), b"content").unwrap();
+        fs::create_dir(dir.join("Other")).unwrap();
+        fs::write(dir.join("Other").join("old.tmp"), b"content").unwrap();
+
+        clean_directory(&dir, &config, &mut WalkState::new());
+
+        assert!(protected.join("data").join("state.dat").exists());
+        assert!(!dir.join("Vendor").join("old.tmp").exists());
+        assert!(!dir.join("Other").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn within_ignores_case_and_parent_components() {
+        let dir = Path::new(r"C:\Users\alice\AppData\Local\Temp");
+
+        assert!(is_within(
+            Path::new(r"C:\Users\alice\AppData\Local\Temp\a"),
+            dir
+        ));
+        assert!(is_within(
+            Path::new(r"c:\users\ALICE\appdata\local\temp\a\b"),
+            dir
+        ));
+        assert!(is_within(
+            Path::new(r"C:\Windows\Temp\a"),
+            Path::new(r"C:\Windows\Temp\")
+        ));
+        assert!(!is_within(dir, dir));
+        assert!(!is_within(
+            Path::new(r"C:\Users\alice\AppData\Local\Temp2"),
+            dir
+        ));
+        assert!(!is_within(
+            Path::new(r"C:\Users\alice\AppData\Local\Temp\..\Microsoft"),
+            dir
+        ));
+        assert!(!is_within(Path::new(r"Temp\a"), Path::new("Temp")));
+    }
+
+    #[test]
+    fn mixed_directory_is_removed_without_errors() {
+        let dir = temp_dir("mixed-directory");
+        let config = config(&["--empty-only"]);
+        for name in ["a", "c", "e"] {
+            fs::write(dir.join(format!("{}.tmp", name)), b"").unwrap();
+        }
+        for name in ["b", "d"] {
+            let sub = dir.join(name);
+            fs::create_dir_all(sub.join("nested")).unwrap();
+            fs::write(sub.join("f.tmp"), b"").unwrap();
+            fs::write(sub.join("nested").join("g.tmp"), b"").unwrap();
+        }
+
+        let stats = remove_dir_contents(&dir, &config, false, &mut WalkState::new()).unwrap();
+
+        assert_eq!(stats.errors_total, 0);
+        assert_eq!(stats.dirs_failed, 0);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn no_user_profiles_skips_the_profiles() {
+        let root = offline_root("no-user-profiles", &["alice", "bob"]);
+
+        assert_eq!(
+            relative_roots(&root, &["--no-user-profiles"]),
+            [r"ProgramData\Temp", r"Windows\Temp"]
+        );
+
+        // Listing the profiles would fail without a Users directory
+        fs::remove_dir_all(root.join("Users")).unwrap();
+        assert!(get_user_directories(Some(&root), r"AppData\Local\Temp\").is_err());
+        assert_eq!(get_temp_directories(Some(&root), false).unwrap().len(), 2);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(matches_wildcard("svc_*", "svc_sql"));
+        assert!(matches_wildcard("svc_*", "SVC_Backup"));
+        assert!(matches_wildcard("svc_*", "svc_"));
+        assert!(!matches_wildcard("svc_*", "admin_svc_sql"));
+        assert!(matches_wildcard("*sql*", "svc_sql_2019"));
+        assert!(matches_wildcard("user?", "user1"));
+        assert!(!matches_wildcard("user?", "user12"));
+        assert!(matches_wildcard("a*b*c", "aXXbYYbc"));
+        assert!(!matches_wildcard("a*b*c", "aXXbYY"));
+        assert!(matches_wildcard("*", ""));
+        assert!(!matches_wildcard("", "alice"));
+    }
+
+    #[test]
+    fn profile_wildcard_selects_profiles() {
+        let root = offline_root("profiles", &["alice", "svc_sql", "SVC_web", "admin_svc"]);
+
+        assert_eq!(
+            relative_roots(&root, &["--profile", "svc_*"]),
+            [
+                r"ProgramData\Temp",
+                r"Users\SVC_web\AppData\Local\Temp",
+                r"Users\svc_sql\AppData\Local\Temp",
+                r"Windows\Temp",
+            ]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn read_only_volumes_are_skipped() {
+        let volume = |flags| Volume {
+            root: PathBuf::from(r"D:\"),
+            serial: 1,
+            flags,
+        };
+        let read_only = volume(FILE_READ_ONLY_VOLUME | FILE_CASE_SENSITIVE_SEARCH);
+        let writable = volume(FILE_CASE_SENSITIVE_SEARCH);
+
+        assert!(read_only.is_read_only());
+        assert!(!writable.is_read_only());
+
+        assert!(skips_read_only(&read_only, &config(&[])));
+        assert!(!skips_read_only(&writable, &config(&[])));
+        assert!(!skips_read_only(
+            &read_only,
+            &config(&["--include-readonly-volumes"])
+        ));
+    }
 }
@@ -0,0 +1,222 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Year,
+    ShortYear,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+}
+
+impl Field {
+    /// Number of digits, the fields are zero padded
+    fn width(self) -> usize {
+        match self {
+            Field::Year => 4,
+            _ => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+    Literal(char),
+    Field(Field),
+}
+
+/// Date embedded in file names, described by a strftime-like pattern like log_%Y%m%d.
+/// Supports %Y, %y (20xx), %m, %d, %H, %M, %S and %%, the date is read as UTC.
+#[derive(Clone)]
+pub struct NameDatePattern {
+    #[cfg(feature = "task-scheduler")]
+    pattern: String,
+    tokens: Vec<Token>,
+}
+
+impl NameDatePattern {
+    pub fn parse(pattern: &str) -> Result<NameDatePattern> {
+        let mut tokens = vec![];
+        let mut chars = pattern.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                tokens.push(Token::Literal(c));
+                continue;
+            }
+
+            let field = match chars.next() {
+                Some('Y') => Field::Year,
+                Some('y') => Field::ShortYear,
+                Some('m') => Field::Month,
+                Some('d') => Field::Day,
+                Some('H') => Field::Hour,
+                Some('M') => Field::Minute,
+                Some('S') => Field::Second,
+                Some('%') => {
+                    tokens.push(Token::Literal('%'));
+                    continue;
+                }
+                Some(other) => bail!(
+                    "Unsupported %{} in the date pattern {}, expected %Y, %y, %m, %d, %H, %M, %S or %%",
+                    other,
+                    pattern
+                ),
+                None => bail!("The date pattern {} ends with a lone %", pattern),
+            };
+
+            tokens.push(Token::Field(field));
+        }
+
+        let has = |field| {
+            tokens
+                .iter()
+                .any(|token| matches!(token, Token::Field(f) if *f == field))
+        };
+        if !(has(Field::Year) || has(Field::ShortYear)) || !has(Field::Month) || !has(Field::Day) {
+            bail!(
+                "The date pattern {} needs a year (%Y or %y), a month (%m) and a day (%d)",
+                pattern
+            );
+        }
+
+        Ok(NameDatePattern {
+            #[cfg(feature = "task-scheduler")]
+            pattern: pattern.to_string(),
+            tokens,
+        })
+    }
+
+    #[cfg(feature = "task-scheduler")]
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns the first valid date matching the pattern anywhere in the name
+    pub fn date_in(&self, name: &str) -> Option<SystemTime> {
+        let chars = name.chars().collect::<Vec<_>>();
+        (0..chars.len()).find_map(|start| self.date_at(&chars[start..]))
+    }
+
+    fn date_at(&self, chars: &[char]) -> Option<SystemTime> {
+        let (mut year, mut month, mut day) = (0, 0, 0);
+        let (mut hour, mut minute, mut second) = (0, 0, 0);
+        let mut pos = 0;
+
+        for token in &self.tokens {
+            match *token {
+                Token::Literal(c) => {
+                    if chars.get(pos) != Some(&c) {
+                        return None;
+                    }
+                    pos += 1;
+                }
+                Token::Field(field) => {
+                    let digits = chars.get(pos..pos + field.width())?;
+                    let mut value = 0;
+                    for digit in digits {
+                        value = value * 10 + digit.to_digit(10)? as u64;
+                    }
+                    pos += field.width();
+
+                    match field {
+                        Field::Year => year = value,
+                        Field::ShortYear => year = 2000 + value,
+                        Field::Month => month = value,
+                        Field::Day => day = value,
+                        Field::Hour => hour = value,
+                        Field::Minute => minute = value,
+                        Field::Second => second = value,
+                    }
+                }
+            }
+        }
+
+        if year < 1970
+            || !(1..=12).contains(&month)
+            || !(1..=days_in_month(year, month)).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return None;
+        }
+
+        let days = (1970..year).map(days_in_year).sum::<u64>()
+            + (1..month).map(|m| days_in_month(year, m)).sum::<u64>()
+            + day
+            - 1;
+
+        Some(UNIX_EPOCH + Duration::from_secs(days * DAY + hour * 3600 + minute * 60 + second))
+    }
+}
+
+fn is_leap_year(year: u64) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+fn days_in_year(year: u64) -> u64 {
+    if is_leap_year(year) {
+        366
+    } else {
+        365
+    }
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date_in(pattern: &str, name: &str) -> Option<u64> {
+        NameDatePattern::parse(pattern)
+            .unwrap()
+            .date_in(name)
+            .map(|time| time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[test]
+    fn date_in_name() {
+        assert_eq!(date_in("log_%Y%m%d", "log_20240101.tmp"), Some(1704067200));
+        assert_eq!(
+            date_in("%Y-%m-%d", "backup 2000-02-29 full.bak"),
+            Some(951782400)
+        );
+        assert_eq!(
+            date_in("%y%m%d-%H%M%S", "dump-220131-235959.dmp"),
+            Some(1643673599)
+        );
+        assert_eq!(date_in("%d.%m.%Y", "25.12.2023"), Some(1703462400));
+    }
+
+    #[test]
+    fn invalid_dates_are_ignored() {
+        assert_eq!(date_in("log_%Y%m%d", "log_20230229.tmp"), None);
+        assert_eq!(date_in("log_%Y%m%d", "log_20241301.tmp"), None);
+        assert_eq!(date_in("log_%Y%m%d", "log_2024011.tmp"), None);
+        assert_eq!(date_in("log_%Y%m%d", "data_20240101.tmp"), None);
+        assert_eq!(date_in("%Y%m%d%H", "2024010125"), None);
+    }
+
+    #[test]
+    fn parse_rejects_incomplete_patterns() {
+        assert!(NameDatePattern::parse("%Y%m").is_err());
+        assert!(NameDatePattern::parse("%Y%m%d%").is_err());
+        assert!(NameDatePattern::parse("%Y%m%d%j").is_err());
+        assert!(NameDatePattern::parse("100%%_%Y%m%d").is_ok());
+    }
+}
@@ -1,24 +1,291 @@
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::io::{self, BufWriter, Write};
+use std::os::windows::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context, Result};
-use simplelog::{CombinedLogger, LevelFilter, SimpleLogger, WriteLogger};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{Log, Metadata, Record};
+use simplelog::{CombinedLogger, LevelFilter, SharedLogger, SimpleLogger, WriteLogger};
 
-use crate::Config;
+use crate::config::format_tags;
+use crate::windows::{enable_ansi_colors, is_locked_error, local_timestamp};
+use crate::{Config, Stats};
+
+const HISTORY_HEADER: &str = "timestamp,removed_count,removed_bytes,errors,elapsed_seconds";
+/// Attempts to open the history file while another run holds it
+const HISTORY_OPEN_ATTEMPTS: u32 = 50;
+const HISTORY_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Rotated --log segments kept, the oldest is removed
+const LOG_SEGMENTS: u32 = 5;
+
+/// Log target of the colored summary line, which the terminal logger leaves out since
+/// the line is printed there apart
+const SUMMARY_TARGET: &str = "summary";
+
+/// Handle to the --log file kept to flush it on shutdown
+static LOG_FILE: OnceLock<fs::File> = OnceLock::new();
+
+/// Health of a run, shown by the color of the summary
+#[derive(Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// No errors
+    Ok,
+    /// Some errors, but most of the work was done
+    Warning,
+    /// Errors dominated or nothing could be removed
+    Failed,
+}
+
+impl Severity {
+    fn color(&self) -> &'static str {
+        match self {
+            Severity::Ok => "32",
+            Severity::Warning => "33",
+            Severity::Failed => "31",
+        }
+    }
+}
 
 pub fn print_err(err: anyhow::Error) {
-    error!("Error: {}", err);
+    match os_error(&err) {
+        Some((code, message)) => error!("Error: {} (OS error {}: {})", err, code, message),
+        None => error!("Error: {}", err),
+    }
     err.chain()
         .skip(1)
         .for_each(|cause| error!("  Cause: {}", cause));
     error!("");
 }
 
-pub fn init_logger(config: &Config) -> Result<()> {
-    let filter = if config.verbose {
-        LevelFilter::Debug
+/// Returns the Win32 error code and message of the underlying OS error, if any
+fn os_error(err: &anyhow::Error) -> Option<(i32, String)> {
+    let io_err = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())?;
+    let code = io_err.raw_os_error()?;
+
+    // The message is followed by the code, which is already shown
+    let message = io_err.to_string();
+    let message = message
+        .trim_end_matches(&format!(" (os error {})", code))
+        .trim_end()
+        .to_string();
+
+    Some((code, message))
+}
+
+/// Prints the error and its causes as a single JSON object on stderr
+pub fn print_err_json(err: &anyhow::Error) {
+    eprintln!("{}", error_json(err, None));
+}
+
+/// Returns the error and its causes as a JSON object, with the path of the entry if given
+pub fn error_json(err: &anyhow::Error, path: Option<&Path>) -> String {
+    let causes = err
+        .chain()
+        .skip(1)
+        .map(|cause| json_string(&cause.to_string()))
+        .collect::<Vec<_>>();
+
+    let path = path
+        .map(|path| format!("\"path\":{},", json_string(&path.display().to_string())))
+        .unwrap_or_default();
+
+    format!(
+        "{{{}\"error\":{},\"causes\":[{}]}}",
+        path,
+        json_string(&err.to_string()),
+        causes.join(",")
+    )
+}
+
+/// Quotes and escapes the value as a JSON string
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+/// Logs the summary line, with --color-summary-by-severity it's printed on the terminal
+/// colored by the severity and logged only to the log file. Colors are dropped if the
+/// console doesn't support them.
+pub fn print_summary(line: &str, severity: Severity, config: &Config) {
+    if config.color_summary && !config.quiet && enable_ansi_colors().is_ok() {
+        println!("\x1b[{}m{}\x1b[0m", severity.color(), line);
+        info!(target: SUMMARY_TARGET, "{}", line);
     } else {
-        LevelFilter::Info
+        info!("{}", line);
+    }
+}
+
+/// Replaces the --summary-file with the given lines, a failure is only logged
+pub fn write_summary_file(path: &Path, lines: &[String]) {
+    let mut contents = lines.join("\r\n");
+    contents.push_str("\r\n");
+
+    if let Err(err) = fs::write(path, contents) {
+        error!(
+            "Unable to write the summary file {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// Appends the row to the --history file, preceded by the header if the file is new.
+/// A failure is only logged.
+pub fn append_history(path: &Path, row: &str) {
+    if let Err(err) = write_history(path, row) {
+        error!(
+            "Unable to append to the history file {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+fn write_history(path: &Path, row: &str) -> io::Result<()> {
+    // Not sharing the file keeps concurrent runs from interleaving their rows
+    let mut attempts = 1;
+    let mut file = loop {
+        let opened = fs::File::options()
+            .append(true)
+            .create(true)
+            .share_mode(0)
+            .open(path);
+
+        match opened {
+            Err(err) if is_locked_error(&err) && attempts < HISTORY_OPEN_ATTEMPTS => {
+                attempts += 1;
+                thread::sleep(HISTORY_RETRY_DELAY);
+            }
+            opened => break opened?,
+        }
+    };
+
+    let mut contents = String::new();
+    if file.metadata()?.len() == 0 {
+        contents.push_str(HISTORY_HEADER);
+        contents.push_str("\r\n");
+    }
+    contents.push_str(row);
+    contents.push_str("\r\n");
+
+    // A single write appends the whole row at once
+    file.write_all(contents.as_bytes())
+}
+
+/// Replaces the --metrics-file with the totals of the run in the Prometheus text format,
+/// for the textfile collector of node_exporter, labeled with the tags. A failure is only
+/// logged.
+pub fn write_metrics_file(path: &Path, total: &Stats, tags: &BTreeMap<String, String>) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let metrics = [
+        (
+            "wtc_removed_bytes",
+            "Bytes removed by the last run",
+            total.removed_bytes,
+        ),
+        (
+            "wtc_removed_count",
+            "Entries removed by the last run",
+            total.removed_count,
+        ),
+        (
+            "wtc_errors_total",
+            "Errors of the last run",
+            total.errors_total,
+        ),
+        (
+            "wtc_last_run_timestamp",
+            "Unix time the last run ended",
+            timestamp,
+        ),
+    ];
+
+    let labels = metric_labels(tags);
+
+    let mut contents = String::new();
+    for (name, help, value) in metrics {
+        contents.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} gauge\n{}{} {}\n",
+            name, help, name, name, labels, value
+        ));
+    }
+
+    // The collector must never read a partially written file
+    let temp_path = path.with_extension("prom.tmp");
+    let written = fs::write(&temp_path, contents).and_then(|_| fs::rename(&temp_path, path));
+
+    if let Err(err) = written {
+        error!(
+            "Unable to write the metrics file {}: {}",
+            path.display(),
+            err
+        );
+        let _ = fs::remove_file(&temp_path);
+    }
+}
+
+/// Returns the tags as Prometheus labels like {environment="production"}, the keys are
+/// turned into valid label names and the values are escaped
+fn metric_labels(tags: &BTreeMap<String, String>) -> String {
+    if tags.is_empty() {
+        return String::new();
+    }
+
+    let labels = tags
+        .iter()
+        .map(|(key, value)| {
+            let mut name = key
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect::<String>();
+            if name.starts_with(|c: char| c.is_ascii_digit()) {
+                name.insert(0, '_');
+            }
+
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+pub fn init_logger(config: &Config) -> Result<()> {
+    let filter = match config.log_level {
+        Some(level) => level,
+        None if config.verbose => LevelFilter::Debug,
+        None => LevelFilter::Info,
     };
 
     let mut log_config = simplelog::ConfigBuilder::default();
@@ -27,25 +294,219 @@ pub fn init_logger(config: &Config) -> Result<()> {
     log_config.set_thread_level(LevelFilter::Off);
     log_config.set_time_to_local(true);
 
-    let mut loggers: Vec<Box<(dyn simplelog::SharedLogger + 'static)>> = vec![];
+    let file_config = log_config.build();
+    log_config.add_filter_ignore_str(SUMMARY_TARGET);
+
+    let mut loggers: Vec<Box<dyn SharedLogger + 'static>> = vec![];
 
-    if !config.quiet || config.install_task {
+    if !config.quiet || config.log_level.is_some() || config.install_task {
         loggers.push(SimpleLogger::new(filter, log_config.build()));
     }
 
+    // Logged once the logger is ready
+    let mut rotation = Ok(());
+    let mut pruning = Ok(());
+
     if !config.install_task {
         if let Some(log_path) = &config.log_path {
+            if let Some(max_size) = config.log_max_size {
+                rotation = rotate_log(Path::new(log_path), max_size, config.log_compress);
+            }
+
             // Open or create file for writing (append)
             let log_file = open_log_file(Path::new(log_path))?;
+            if let Ok(handle) = log_file.try_clone() {
+                let _ = LOG_FILE.set(handle);
+            }
+
+            // The new log is already counted
+            if let Some(keep) = config.log_keep.filter(|_| Path::new(log_path).is_dir()) {
+                pruning = prune_run_logs(Path::new(log_path), keep);
+            }
+
+            let file_filter = config.log_file_level.unwrap_or(filter);
+
+            loggers.push(WriteLogger::new(file_filter, file_config, log_file));
+        }
+    }
+
+    if !config.tags.is_empty() {
+        let tags = format_tags(&config.tags, ", ");
+        loggers = loggers
+            .into_iter()
+            .map(|logger| -> Box<dyn SharedLogger> {
+                Box::new(TaggedLogger {
+                    logger,
+                    tags: tags.clone(),
+                })
+            })
+            .collect();
+    }
+
+    CombinedLogger::init(loggers)?;
+
+    if let Err(err) = rotation {
+        warn!("Unable to rotate the log file: {:#}", err);
+    }
 
-            loggers.push(WriteLogger::new(filter, log_config.build(), log_file));
+    if let Err(err) = pruning {
+        warn!("Unable to remove the old log files: {:#}", err);
+    }
+
+    Ok(())
+}
+
+/// Prefixes every line of the wrapped logger with the --tag pairs
+struct TaggedLogger {
+    logger: Box<dyn SharedLogger>,
+    tags: String,
+}
+
+impl Log for TaggedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.logger.log(
+            &Record::builder()
+                .args(format_args!("[{}] {}", self.tags, record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+impl SharedLogger for TaggedLogger {
+    fn level(&self) -> LevelFilter {
+        self.logger.level()
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        self.logger.config()
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+/// Renames the log file to .1 once it reaches the size, shifting the older segments up
+/// to LOG_SEGMENTS, then compresses it to .1.gz with --log-compress
+fn rotate_log(log_path: &Path, max_size: u64, compress: bool) -> Result<()> {
+    match fs::metadata(log_path) {
+        Ok(meta) if meta.is_file() && meta.len() >= max_size => {}
+        _ => return Ok(()),
+    }
+
+    let extension = if compress { ".gz" } else { "" };
+    let segment = |n: u32| {
+        let mut name = log_path.as_os_str().to_owned();
+        name.push(format!(".{}{}", n, extension));
+        PathBuf::from(name)
+    };
+
+    // The oldest segment is replaced by the one before it
+    for n in (1..LOG_SEGMENTS).rev() {
+        let from = segment(n);
+        if from.exists() {
+            let to = segment(n + 1);
+            fs::rename(&from, &to).with_context(|| {
+                format!("Unable to rename {} to {}", from.display(), to.display())
+            })?;
         }
     }
 
-    Ok(CombinedLogger::init(loggers)?)
+    let mut rotated = log_path.as_os_str().to_owned();
+    rotated.push(".1");
+    let rotated = PathBuf::from(rotated);
+
+    fs::rename(log_path, &rotated).with_context(|| {
+        format!(
+            "Unable to rename {} to {}",
+            log_path.display(),
+            rotated.display()
+        )
+    })?;
+
+    if compress {
+        gzip_file(&rotated, &segment(1))?;
+        fs::remove_file(&rotated)
+            .with_context(|| format!("Unable to remove {}", rotated.display()))?;
+    }
+
+    Ok(())
+}
+
+fn gzip_file(path: &Path, gz_path: &Path) -> Result<()> {
+    let mut input = fs::File::open(path)
+        .with_context(|| format!("Unable to open the file {}", path.display()))?;
+    let output = fs::File::create(gz_path)
+        .with_context(|| format!("Unable to create the file {}", gz_path.display()))?;
+
+    let mut encoder = GzEncoder::new(BufWriter::new(output), Compression::default());
+    io::copy(&mut input, &mut encoder)
+        .and_then(|_| encoder.finish())
+        .and_then(|mut output| output.flush())
+        .with_context(|| format!("Unable to compress {}", path.display()))
+}
+
+/// Writes the --log file to disk, sync_all is FlushFileBuffers
+pub fn flush_log_file() {
+    if let Some(file) = LOG_FILE.get() {
+        let _ = file.sync_all();
+    }
+}
+
+/// Returns the path of the log file, a directory gets a new file for every run named
+/// after the local time, like wintempclean-20220131-235959.log
+pub fn resolve_log_path(log_path: &Path) -> PathBuf {
+    if log_path.is_dir() {
+        log_path.join(format!("{}-{}.log", clap::crate_name!(), local_timestamp()))
+    } else {
+        log_path.to_path_buf()
+    }
+}
+
+/// Removes the oldest per-run log files of the directory beyond the count
+fn prune_run_logs(dir: &Path, keep: usize) -> Result<()> {
+    let prefix = format!("{}-", clap::crate_name!());
+
+    let mut logs = fs::read_dir(dir)
+        .with_context(|| format!("Unable to read the log directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            name.starts_with(&prefix) && name.ends_with(".log")
+        })
+        .collect::<Vec<_>>();
+
+    // The timestamps in the names sort by age, oldest first
+    logs.sort();
+
+    for path in logs.iter().take(logs.len().saturating_sub(keep)) {
+        debug!("Removing old log file {}", path.display());
+        fs::remove_file(path).with_context(|| format!("Unable to remove {}", path.display()))?;
+    }
+
+    Ok(())
 }
 
 pub fn open_log_file(log_path: &Path) -> Result<fs::File> {
+    let log_path = &resolve_log_path(log_path);
+
     // If the path exists it may be a directory
     if log_path.exists() && !log_path.is_file() {
         bail!("Invalid path specified for log file");
@@ -54,7 +515,7 @@ pub fn open_log_file(log_path: &Path) -> Result<fs::File> {
     fs::File::options()
         .append(true)
         .create(true)
-        .open(&log_path)
+        .open(log_path)
         .with_context(|| {
             format!(
                 "Unable to create or open the log file {}",
@@ -62,3 +523,164 @@ pub fn open_log_file(log_path: &Path) -> Result<fs::File> {
             )
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use anyhow::Context;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("C:\\Temp\\\"a\"\n\t\u{1}"),
+            "\"C:\\\\Temp\\\\\\\"a\\\"\\n\\t\\u0001\""
+        );
+    }
+
+    #[test]
+    fn error_json_lists_causes() {
+        let err = Err::<(), _>(std::io::Error::other("access denied"))
+            .context("can't read dir C:\\Temp")
+            .unwrap_err();
+
+        assert_eq!(
+            error_json(&err, None),
+            "{\"error\":\"can't read dir C:\\\\Temp\",\"causes\":[\"access denied\"]}"
+        );
+        assert_eq!(
+            error_json(&err, Some(Path::new("C:\\Temp"))),
+            "{\"path\":\"C:\\\\Temp\",\"error\":\"can't read dir C:\\\\Temp\",\
+            \"causes\":[\"access denied\"]}"
+        );
+    }
+
+    #[test]
+    fn severity_colors() {
+        assert_eq!(Severity::Ok.color(), "32");
+        assert_eq!(Severity::Warning.color(), "33");
+        assert_eq!(Severity::Failed.color(), "31");
+    }
+
+    #[test]
+    fn rotated_segment_is_gzip() {
+        let dir = temp_dir("rotate-log");
+        let log_path = dir.join("wintempclean.log");
+        fs::write(&log_path, "first run\n").unwrap();
+
+        // Below the size nothing is rotated
+        rotate_log(&log_path, 1024, true).unwrap();
+        assert!(log_path.exists());
+
+        rotate_log(&log_path, 1, true).unwrap();
+        fs::write(&log_path, "second run\n").unwrap();
+        rotate_log(&log_path, 1, true).unwrap();
+
+        let read_segment = |n: u32| {
+            let file = fs::File::open(dir.join(format!("wintempclean.log.{}.gz", n))).unwrap();
+            let mut contents = String::new();
+            GzDecoder::new(file).read_to_string(&mut contents).unwrap();
+            contents
+        };
+
+        assert!(!log_path.exists());
+        assert!(!dir.join("wintempclean.log.1").exists());
+        assert_eq!(read_segment(1), "second run\n");
+        assert_eq!(read_segment(2), "first run\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tagged_logger_prefixes_the_lines() {
+        let dir = temp_dir("tagged-logger");
+        let path = dir.join("wintempclean.log");
+        let logger = TaggedLogger {
+            logger: WriteLogger::new(
+                LevelFilter::Info,
+                simplelog::Config::default(),
+                fs::File::create(&path).unwrap(),
+            ),
+            tags: String::from("environment=production, ticket=JIRA-1234"),
+        };
+
+        logger.log(
+            &Record::builder()
+                .args(format_args!("Removed 3 entries"))
+                .level(log::Level::Info)
+                .build(),
+        );
+        logger.flush();
+        drop(logger);
+
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("[environment=production, ticket=JIRA-1234] Removed 3 entries"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metrics_file_is_parsable() {
+        let dir = temp_dir("metrics-file");
+        let path = dir.join("wintempclean.prom");
+        let mut total = Stats::new();
+        total.removed_bytes = 4096;
+        total.removed_count = 3;
+        total.errors_total = 1;
+
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let tags = BTreeMap::from([
+            (String::from("environment"), String::from("production")),
+            (
+                String::from("ticket-id"),
+                String::from(r#"JIRA "1234" \ a"#),
+            ),
+        ]);
+        write_metrics_file(&path, &total, &tags);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let mut values = std::collections::HashMap::new();
+        for line in contents.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                assert!(comment.starts_with("HELP wtc_") || comment.starts_with("TYPE wtc_"));
+                continue;
+            }
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            let (name, labels) = series.split_once('{').unwrap();
+            assert_eq!(
+                labels,
+                r#"environment="production",ticket_id="JIRA \"1234\" \\ a"}"#
+            );
+            values.insert(name.to_string(), value.parse::<u64>().unwrap());
+        }
+
+        assert_eq!(values.len(), 4);
+        assert_eq!(values["wtc_removed_bytes"], 4096);
+        assert_eq!(values["wtc_removed_count"], 3);
+        assert_eq!(values["wtc_errors_total"], 1);
+        assert!(values["wtc_last_run_timestamp"] >= before);
+        // The temporary file was renamed over the metrics file
+        assert!(!path.with_extension("prom.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metric_labels_without_tags() {
+        assert_eq!(metric_labels(&BTreeMap::new()), "");
+        assert_eq!(
+            metric_labels(&BTreeMap::from([(
+                String::from("1st.run"),
+                String::from("yes")
+            )])),
+            r#"{_1st_run="yes"}"#
+        );
+    }
+}
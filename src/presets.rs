@@ -0,0 +1,171 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+/// Names and descriptions of the directory presets
+pub const PRESETS: [(&str, &str); 4] = [
+    (
+        "aspnet",
+        "ASP.NET temporary files of every .NET Framework version",
+    ),
+    ("docker", "Docker for Windows temporary files"),
+    ("iis", "IIS temporary compressed files"),
+    ("wer", "Windows Error Reporting queued and archived reports"),
+];
+
+/// Fails if the name is not a known preset
+pub fn check_preset(name: &str) -> Result<()> {
+    if !PRESETS.iter().any(|(preset, _)| *preset == name) {
+        let names = PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        bail!(
+            "Unknown preset {}, expected one of {} (see --list-presets)",
+            name,
+            names.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+pub fn print_presets() {
+    for (name, description) in PRESETS {
+        println!("{:<8} {}", name, description);
+    }
+}
+
+/// Returns the existing directories of the preset, the applications may not be installed.
+/// The environment of an offline root isn't known, the default locations are used.
+pub fn preset_directories(name: &str, root: Option<&Path>) -> Vec<PathBuf> {
+    let (system_drive, windir, program_data) = match root {
+        Some(root) => (
+            root.display()
+                .to_string()
+                .trim_end_matches('\\')
+                .to_string(),
+            root.join("Windows").display().to_string(),
+            root.join("ProgramData").display().to_string(),
+        ),
+        None => (
+            env::var("SystemDrive").unwrap_or_else(|_| String::from("C:")),
+            env::var("windir").unwrap_or_else(|_| String::from(r"C:\Windows")),
+            env::var("ProgramData").unwrap_or_else(|_| String::from(r"C:\ProgramData")),
+        ),
+    };
+
+    let dirs = match name {
+        "aspnet" => framework_directories(&windir, "Temporary ASP.NET Files"),
+        "docker" => vec![PathBuf::from(program_data).join(r"Docker\tmp")],
+        "iis" => vec![PathBuf::from(format!(
+            r"{}\inetpub\temp\IIS Temporary Compressed Files",
+            system_drive
+        ))],
+        "wer" => {
+            let wer = PathBuf::from(program_data).join(r"Microsoft\Windows\WER");
+            vec![wer.join("ReportArchive"), wer.join("ReportQueue")]
+        }
+        _ => vec![],
+    };
+
+    dirs.into_iter()
+        .filter(|dir| {
+            let exists = dir.is_dir();
+            if !exists {
+                debug!("Skipping missing preset directory {}", dir.display());
+            }
+            exists
+        })
+        .collect()
+}
+
+/// Returns the path relative to every installed .NET Framework version, 32 and 64 bit
+fn framework_directories(windir: &str, relative: &str) -> Vec<PathBuf> {
+    ["Framework", "Framework64"]
+        .iter()
+        .map(|framework| PathBuf::from(windir).join("Microsoft.NET").join(framework))
+        .filter_map(|framework| fs::read_dir(framework).ok())
+        .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with('v'))
+        .map(|entry| entry.path().join(relative))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    /// Returns the sorted directories of the preset, relative to the root
+    fn relative_directories(name: &str, root: &Path) -> Vec<String> {
+        let mut dirs = preset_directories(name, Some(root))
+            .iter()
+            .map(|dir| dir.strip_prefix(root).unwrap().display().to_string())
+            .collect::<Vec<_>>();
+        dirs.sort();
+        dirs
+    }
+
+    #[test]
+    fn preset_directories_of_offline_root() {
+        let root = temp_dir("presets");
+        for dir in [
+            r"inetpub\temp\IIS Temporary Compressed Files",
+            r"ProgramData\Docker\tmp",
+            r"ProgramData\Microsoft\Windows\WER\ReportArchive",
+            r"ProgramData\Microsoft\Windows\WER\ReportQueue",
+            r"Windows\Microsoft.NET\Framework\v4.0.30319\Temporary ASP.NET Files",
+            r"Windows\Microsoft.NET\Framework64\v2.0.50727\Temporary ASP.NET Files",
+            r"Windows\Microsoft.NET\Framework64\v4.0.30319\Temporary ASP.NET Files",
+            // Not a version directory
+            r"Windows\Microsoft.NET\Framework\Config\Temporary ASP.NET Files",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        assert_eq!(
+            relative_directories("aspnet", &root),
+            [
+                r"Windows\Microsoft.NET\Framework64\v2.0.50727\Temporary ASP.NET Files",
+                r"Windows\Microsoft.NET\Framework64\v4.0.30319\Temporary ASP.NET Files",
+                r"Windows\Microsoft.NET\Framework\v4.0.30319\Temporary ASP.NET Files",
+            ]
+        );
+        assert_eq!(
+            relative_directories("docker", &root),
+            [r"ProgramData\Docker\tmp"]
+        );
+        assert_eq!(
+            relative_directories("iis", &root),
+            [r"inetpub\temp\IIS Temporary Compressed Files"]
+        );
+        assert_eq!(
+            relative_directories("wer", &root),
+            [
+                r"ProgramData\Microsoft\Windows\WER\ReportArchive",
+                r"ProgramData\Microsoft\Windows\WER\ReportQueue",
+            ]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn missing_preset_directories_are_skipped() {
+        let root = temp_dir("presets-missing");
+        for (name, _) in PRESETS {
+            assert!(preset_directories(name, Some(&root)).is_empty(), "{}", name);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn check_preset_names() {
+        for (name, _) in PRESETS {
+            assert!(check_preset(name).is_ok());
+        }
+        assert!(check_preset("sqlserver").is_err());
+        assert!(check_preset("IIS").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Compressed copies of the large files removed with --compress-before-delete.
+//!
+//! Every file is LZMA compressed to `<quarantine dir>\<timestamp>-<name>.xz` next to a
+//! `<timestamp>-<name>.meta.json` sidecar like:
+//!
+//! ```text
+//! {"path":"C:\\Windows\\Temp\\dump.dmp","size":1048576,"deleted":"2022-01-01T00:00:00Z"}
+//! ```
+//!
+//! so it can be recovered until --quarantine-ttl expires and the next run purges it.
+
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::output::json_string;
+
+const ARCHIVE_EXTENSION: &str = "xz";
+const META_EXTENSION: &str = "meta.json";
+
+/// Compresses the file to the quarantine directory, writes its sidecar and removes the
+/// original. Returns the size of the compressed copy.
+pub fn quarantine_file(path: &Path, quarantine_dir: &Path) -> Result<u64> {
+    fs::create_dir_all(quarantine_dir).with_context(|| {
+        format!(
+            "Unable to create the quarantine directory {}",
+            quarantine_dir.display()
+        )
+    })?;
+
+    let size = fs::metadata(path)
+        .with_context(|| format!("can't read metadata {}", path.display()))?
+        .len();
+
+    let (archive_path, meta_path) = quarantine_paths(path, quarantine_dir);
+
+    if let Err(err) = compress(path, &archive_path) {
+        // Don't leave a truncated copy behind
+        let _ = fs::remove_file(&archive_path);
+        return Err(err);
+    }
+
+    let meta = format!(
+        "{{\"path\":{},\"size\":{},\"deleted\":\"{}\"}}\n",
+        json_string(&path.to_string_lossy()),
+        size,
+        humantime::format_rfc3339_seconds(SystemTime::now())
+    );
+
+    if let Err(err) = fs::write(&meta_path, meta) {
+        let _ = fs::remove_file(&archive_path);
+        return Err(err)
+            .with_context(|| format!("Unable to write the sidecar {}", meta_path.display()));
+    }
+
+    // The original is removed only once its copy is complete
+    fs::remove_file(path).with_context(|| format!("failed to remove file {}", path.display()))?;
+
+    let compressed = fs::metadata(&archive_path)
+        .with_context(|| format!("can't read metadata {}", archive_path.display()))?
+        .len();
+
+    Ok(compressed)
+}
+
+/// Removes the copies and sidecars older than the ttl, returning how many files were
+/// removed from the quarantine directory
+pub fn purge_expired(quarantine_dir: &Path, ttl: Duration) -> Result<u64> {
+    let entries = match fs::read_dir(quarantine_dir) {
+        Ok(entries) => entries,
+        // Nothing was quarantined yet
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!(
+                    "Unable to read the quarantine directory {}",
+                    quarantine_dir.display()
+                )
+            })
+        }
+    };
+
+    let now = SystemTime::now();
+    let mut purged = 0;
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+        if !name.ends_with(&format!(".{}", ARCHIVE_EXTENSION))
+            && !name.ends_with(&format!(".{}", META_EXTENSION))
+        {
+            continue;
+        }
+
+        let expired = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| now.duration_since(modified).unwrap_or_default() > ttl)
+            .unwrap_or(false);
+
+        if expired {
+            debug!("Purging quarantined {}", path.display());
+            fs::remove_file(&path)
+                .with_context(|| format!("failed to remove file {}", path.display()))?;
+            purged += 1;
+        }
+    }
+
+    Ok(purged)
+}
+
+fn compress(path: &Path, archive_path: &Path) -> Result<()> {
+    let input = fs::File::open(path)
+        .with_context(|| format!("Unable to open the file {}", path.display()))?;
+    let output = fs::File::create(archive_path)
+        .with_context(|| format!("Unable to create the file {}", archive_path.display()))?;
+
+    let mut reader = BufReader::new(input);
+    let mut writer = BufWriter::new(output);
+
+    lzma_rs::xz_compress(&mut reader, &mut writer)
+        .and_then(|_| writer.flush())
+        .with_context(|| format!("Unable to compress {}", path.display()))
+}
+
+/// Returns the paths of the copy and the sidecar, prefixed with the time so files with
+/// the same name from different directories don't collide
+fn quarantine_paths(path: &Path, quarantine_dir: &Path) -> (PathBuf, PathBuf) {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let mut counter = 0;
+    loop {
+        let stem = match counter {
+            0 => format!("{}-{}", nanos, name),
+            _ => format!("{}-{}-{}", nanos, counter, name),
+        };
+        let archive_path = quarantine_dir.join(format!("{}.{}", stem, ARCHIVE_EXTENSION));
+        let meta_path = quarantine_dir.join(format!("{}.{}", stem, META_EXTENSION));
+
+        if !archive_path.exists() && !meta_path.exists() {
+            return (archive_path, meta_path);
+        }
+
+        counter += 1;
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// Keeps the newest file of each period for the latest `count` periods
+#[derive(Clone, Copy)]
+pub struct RetentionTier {
+    pub count: u32,
+    /// Period unit: h, d, w, m or y
+    pub unit: char,
+}
+
+impl RetentionTier {
+    pub fn period(&self) -> Duration {
+        let secs = match self.unit {
+            'h' => HOUR,
+            'd' => DAY,
+            'w' => 7 * DAY,
+            'm' => 30 * DAY,
+            _ => 365 * DAY,
+        };
+
+        Duration::from_secs(secs)
+    }
+}
+
+/// Parses a grandfather-father-son policy like 24h,7d,4w,12m: one file per hour for
+/// the last 24 hours, one per day for the last 7 days, one per week for the last
+/// 4 weeks and one per month (30 days) for the last 12 months.
+pub fn parse_retention(spec: &str) -> Result<Vec<RetentionTier>> {
+    spec.split(',')
+        .map(|tier| {
+            let tier = tier.trim();
+            let unit = tier.chars().last().unwrap_or_default();
+            if !"hdwmy".contains(unit) {
+                bail!(
+                    "Invalid retention tier {}, expected a count followed by h, d, w, m or y",
+                    tier
+                );
+            }
+
+            let count = tier[..tier.len() - 1]
+                .parse::<u32>()
+                .with_context(|| format!("Invalid retention tier {}", tier))?;
+
+            Ok(RetentionTier { count, unit })
+        })
+        .collect()
+}
+
+/// Returns the files retained by the policy, given their timestamps
+pub fn retained_files(
+    files: &[(PathBuf, SystemTime)],
+    tiers: &[RetentionTier],
+    now: SystemTime,
+) -> HashSet<PathBuf> {
+    let mut newest_first = files.iter().collect::<Vec<_>>();
+    newest_first.sort_by_key(|(_, time)| std::cmp::Reverse(*time));
+
+    let mut retained = HashSet::new();
+
+    for tier in tiers {
+        let period = tier.period();
+        let oldest = now.checked_sub(period * tier.count);
+        let mut buckets = HashSet::new();
+
+        for (path, time) in &newest_first {
+            if oldest.is_some_and(|oldest| *time < oldest) {
+                break;
+            }
+
+            // The newest file of each period is the one kept
+            let secs = time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            if buckets.insert(secs / period.as_secs()) {
+                retained.insert(path.clone());
+            }
+        }
+    }
+
+    retained
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn ago(now: SystemTime, secs: u64) -> SystemTime {
+        now - Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn parse_retention_tiers() {
+        let tiers = parse_retention("24h, 7d,4w,12m,1y").unwrap();
+        let tiers = tiers
+            .iter()
+            .map(|tier| (tier.count, tier.unit))
+            .collect::<Vec<_>>();
+        assert_eq!(tiers, [(24, 'h'), (7, 'd'), (4, 'w'), (12, 'm'), (1, 'y')]);
+
+        for spec in ["", "7", "d", "7x", "-1d", "7d,"] {
+            assert!(parse_retention(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn retained_files_keeps_newest_per_period() {
+        // At a day boundary so the periods are easy to tell apart
+        let now = UNIX_EPOCH + Duration::from_secs(1000 * DAY);
+        let files = [
+            ("today-new", ago(now, HOUR)),
+            ("today-old", ago(now, 2 * HOUR)),
+            ("yesterday-new", ago(now, DAY + HOUR)),
+            ("yesterday-old", ago(now, DAY + 5 * HOUR)),
+            ("two-days", ago(now, 2 * DAY + HOUR)),
+            ("expired", ago(now, 3 * DAY + HOUR)),
+        ]
+        .map(|(name, time)| (PathBuf::from(name), time));
+
+        let tiers = parse_retention("3d").unwrap();
+        let mut retained = retained_files(&files, &tiers, now)
+            .into_iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        retained.sort();
+
+        assert_eq!(retained, ["today-new", "two-days", "yesterday-new"]);
+    }
+
+    #[test]
+    fn retained_files_combines_tiers() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000 * DAY);
+        let files = [
+            ("hour-1", ago(now, 10)),
+            ("hour-2", ago(now, HOUR + 10)),
+            ("hour-3", ago(now, 2 * HOUR + 10)),
+            ("week-old", ago(now, 6 * DAY)),
+        ]
+        .map(|(name, time)| (PathBuf::from(name), time));
+
+        let tiers = parse_retention("2h,7d").unwrap();
+        let retained = retained_files(&files, &tiers, now);
+
+        assert!(retained.contains(Path::new("hour-1")));
+        assert!(retained.contains(Path::new("hour-2")));
+        assert!(!retained.contains(Path::new("hour-3")));
+        assert!(retained.contains(Path::new("week-old")));
+    }
+}
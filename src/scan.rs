@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fs;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::BytesFormat;
+use crate::format_bytes;
+use crate::output::json_string;
+
+/// Size of every file within the scanned directories
+pub type Snapshot = HashMap<PathBuf, u64>;
+
+/// Lists the files within the directories, links aren't followed
+pub fn take_snapshot(dirs: &[PathBuf]) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    for dir in dirs {
+        add_files(dir, &mut snapshot);
+    }
+    snapshot
+}
+
+fn add_files(dir: &Path, snapshot: &mut Snapshot) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("Can't scan {}: {}", dir.display(), err);
+            return;
+        }
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let meta = match fs::symlink_metadata(entry.path()) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+
+        if meta.is_dir() {
+            add_files(&entry.path(), snapshot);
+        } else if meta.is_file() {
+            snapshot.insert(entry.path(), meta.len());
+        }
+    }
+}
+
+/// Writes the snapshot as a JSON object of paths and sizes, sorted by path
+pub fn write_snapshot(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let mut files = snapshot.iter().collect::<Vec<_>>();
+    files.sort();
+
+    let fields = files
+        .iter()
+        .map(|(file, size)| format!("{}:{}", json_string(&file.display().to_string()), size))
+        .collect::<Vec<_>>();
+
+    fs::write(path, format!("{{{}}}\n", fields.join(",\n")))
+        .with_context(|| format!("Unable to write the scan file {}", path.display()))
+}
+
+/// Reads a snapshot written by write_snapshot
+pub fn read_snapshot(path: &Path) -> Result<Snapshot> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read the scan file {}", path.display()))?;
+
+    SnapshotParser {
+        chars: text.chars().peekable(),
+    }
+    .parse()
+    .with_context(|| format!("Invalid scan file {}", path.display()))
+}
+
+/// Prints the files that appeared, disappeared or changed size between the snapshots
+pub fn print_diff(before: &Snapshot, after: &Snapshot, bytes_format: BytesFormat) {
+    let size = |bytes: u64| format_bytes(bytes as f64, bytes_format);
+
+    let mut paths = before.keys().chain(after.keys()).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+
+    let (mut appeared, mut disappeared, mut changed) = (0, 0, 0);
+
+    for path in paths {
+        match (before.get(path), after.get(path)) {
+            (None, Some(new)) => {
+                println!("+ {} ({})", path.display(), size(*new));
+                appeared += 1;
+            }
+            (Some(old), None) => {
+                println!("- {} ({})", path.display(), size(*old));
+                disappeared += 1;
+            }
+            (Some(old), Some(new)) if old != new => {
+                println!("~ {} ({} -> {})", path.display(), size(*old), size(*new));
+                changed += 1;
+            }
+            _ => {}
+        }
+    }
+
+    println!(
+        "{} files appeared, {} disappeared, {} changed size",
+        appeared, disappeared, changed
+    );
+}
+
+/// Space used by the files directly within a directory in both snapshots
+pub struct DirChange {
+    pub dir: PathBuf,
+    pub before: u64,
+    pub after: u64,
+}
+
+impl DirChange {
+    fn change(&self) -> i128 {
+        self.after as i128 - self.before as i128
+    }
+}
+
+/// Directories whose space used changed between two snapshots, with the totals
+pub struct StatsDiff {
+    /// Largest decrease first
+    pub dirs: Vec<DirChange>,
+    pub before: u64,
+    pub after: u64,
+}
+
+/// Compares the space used by every directory of the snapshots
+pub fn compare_stats(before: &Snapshot, after: &Snapshot) -> StatsDiff {
+    let mut dirs: HashMap<&Path, (u64, u64)> = HashMap::new();
+
+    for (file, size) in before {
+        dirs.entry(file.parent().unwrap_or(file)).or_default().0 += size;
+    }
+    for (file, size) in after {
+        dirs.entry(file.parent().unwrap_or(file)).or_default().1 += size;
+    }
+
+    let mut dirs = dirs
+        .into_iter()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(dir, (before, after))| DirChange {
+            dir: dir.to_path_buf(),
+            before,
+            after,
+        })
+        .collect::<Vec<_>>();
+    dirs.sort_by(|a, b| a.change().cmp(&b.change()).then(a.dir.cmp(&b.dir)));
+
+    StatsDiff {
+        dirs,
+        before: before.values().sum(),
+        after: after.values().sum(),
+    }
+}
+
+impl StatsDiff {
+    /// Prints a table of the directories that improved, then of those that got worse,
+    /// followed by the totals
+    pub fn print(&self, bytes_format: BytesFormat) {
+        let size = |bytes: f64| format_bytes(bytes, bytes_format);
+
+        let improved = self.dirs.iter().filter(|x| x.change() < 0);
+        let worse = self.dirs.iter().filter(|x| x.change() > 0).rev();
+
+        for (title, dirs) in [
+            ("Improved", improved.collect::<Vec<_>>()),
+            ("Worse", worse.collect::<Vec<_>>()),
+        ] {
+            println!("{} ({} directories):", title, dirs.len());
+            for dir in dirs {
+                println!(
+                    "  {:>12} {:>12} {:>12}  {}",
+                    size(dir.before as f64),
+                    size(dir.after as f64),
+                    size(dir.change() as f64),
+                    dir.dir.display()
+                );
+            }
+        }
+
+        println!(
+            "Total: {} -> {} ({})",
+            size(self.before as f64),
+            size(self.after as f64),
+            size(self.after as f64 - self.before as f64)
+        );
+    }
+
+    /// Returns the totals and the changed directories as a JSON object
+    pub fn to_json(&self) -> String {
+        let dirs = self
+            .dirs
+            .iter()
+            .map(|x| {
+                format!(
+                    "{{\"dir\":{},\"before\":{},\"after\":{}}}",
+                    json_string(&x.dir.display().to_string()),
+                    x.before,
+                    x.after
+                )
+            })
+            .collect::<Vec<_>>();
+
+        format!(
+            "{{\"before\":{},\"after\":{},\"dirs\":[{}]}}",
+            self.before,
+            self.after,
+            dirs.join(",")
+        )
+    }
+}
+
+/// Parses the flat JSON object of paths and sizes written by write_snapshot
+struct SnapshotParser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl SnapshotParser<'_> {
+    fn parse(&mut self) -> Result<Snapshot> {
+        let mut snapshot = Snapshot::new();
+
+        self.expect('{')?;
+        if self.peek() == Some('}') {
+            self.chars.next();
+            return Ok(snapshot);
+        }
+
+        loop {
+            let path = self.string()?;
+            self.expect(':')?;
+            let size = self.number()?;
+            snapshot.insert(PathBuf::from(path), size);
+
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(snapshot),
+                _ => bail!("expected , or }} after the size"),
+            }
+        }
+    }
+
+    /// Returns the next character that isn't whitespace
+    fn next(&mut self) -> Option<char> {
+        self.peek()?;
+        self.chars.next()
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => bail!("expected {}", expected),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut value = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('u') => {
+                        let hex = (0..4).filter_map(|_| self.chars.next()).collect::<String>();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .context("invalid \\u escape")?;
+                        value.push(c);
+                    }
+                    Some(c) => value.push(c),
+                    None => bail!("unterminated string"),
+                },
+                Some(c) => value.push(c),
+                None => bail!("unterminated string"),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        self.peek();
+        let mut digits = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+
+        digits.parse().context("expected a size")
+    }
+}
@@ -4,33 +4,511 @@ use std::os::windows::process::CommandExt;
 use std::process::Stdio;
 use std::{io, thread};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 
-use crate::output::open_log_file;
-use crate::windows::is_app_elevated;
+use crate::config::{
+    AgeBasis, BytesFormat, DEFAULT_DEREFERENCE_COUNT, DEFAULT_FAILURE_WARNING, DEFAULT_SENTINEL,
+};
+use crate::output::{open_log_file, resolve_log_path};
+use crate::windows::{is_app_elevated, JobObject};
 
 use super::*;
 
+/// Minimum --created-before accepted for the scheduled task unless --force is given
+const MIN_TASK_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub fn install_task(config: &Config) -> Result<()> {
-    if !is_app_elevated() {
+    if !is_app_elevated() && !config.show_task_script {
         bail!("--install-task required administrator privileges");
     }
 
     let task_name = clap::crate_name!();
-    let args = parse_args(config)?;
+    // The definition of the task can't be read without elevation, the script is then
+    // shown as if it wasn't installed
+    let installed_args = match get_task_args(task_name) {
+        Err(_) if config.show_task_script && !is_app_elevated() => None,
+        result => result?,
+    }
+    .map(|args| split_args(&args));
+    let update = config.update_task && installed_args.is_some();
 
-    run_script(task_name, task_name, &args)?;
+    check_task_age(config, update)?;
+
+    let mut args = parse_args(config)?;
+
+    if update {
+        if let Some(installed_args) = &installed_args {
+            args = merge_args(installed_args, &args);
+        }
+    }
+
+    let script = build_script(task_name, task_name, &args, update)?;
+
+    if config.show_task_script {
+        println!("Arguments: {}", args.join(" "));
+        println!("{}", script);
+        return Ok(());
+    }
+
+    if installed_args.as_ref() == Some(&args) && !config.force_reinstall {
+        println!("Task already installed with correct configuration");
+        return Ok(());
+    }
+
+    run_script(&script, update, config)?;
 
     Ok(())
 }
 
+/// Returns the time the scheduled task was installed, taken from the creation time
+/// of the definition file the Task Scheduler keeps for it.
+pub fn get_task_creation_time(task_name: &str) -> Result<SystemTime> {
+    // The task is registered in a folder with its own name
+    let task_file = Path::new(r"C:\Windows\System32\Tasks")
+        .join(task_name)
+        .join(task_name);
+
+    let created = fs::metadata(&task_file)
+        .and_then(|meta| meta.created())
+        .with_context(|| {
+            format!(
+                "Unable to read the installed task {}, is it installed?",
+                task_file.display()
+            )
+        })?;
+
+    Ok(created)
+}
+
+/// Returns the arguments of the installed task, read from its definition file,
+/// or None if the task is not installed.
+pub fn get_task_args(task_name: &str) -> Result<Option<String>> {
+    let task_file = Path::new(r"C:\Windows\System32\Tasks")
+        .join(task_name)
+        .join(task_name);
+
+    let bytes = match fs::read(&task_file) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!("Unable to read the installed task {}", task_file.display())
+            })
+        }
+    };
+
+    // The definition is an UTF-16 XML document
+    let xml = match bytes.strip_prefix(&[0xff, 0xfe]) {
+        Some(utf16) => {
+            let units = utf16
+                .chunks_exact(2)
+                .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                .collect::<Vec<_>>();
+            String::from_utf16_lossy(&units)
+        }
+        None => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+
+    let args = xml
+        .split_once("<Arguments>")
+        .and_then(|(_, rest)| rest.split_once("</Arguments>"))
+        .map(|(args, _)| args)
+        .unwrap_or_default();
+
+    let args = args
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&");
+
+    Ok(Some(args))
+}
+
+/// Splits a command line on the spaces outside double quotes, escaping the
+/// quotes for the PowerShell script like parse_args does
+fn split_args(args: &str) -> Vec<String> {
+    let mut split = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in args.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push_str("`\"");
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    split.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    if !current.is_empty() {
+        split.push(current);
+    }
+
+    split
+}
+
+/// Keeps the installed options that are not given again, followed by the new ones
+fn merge_args(installed: &[String], args: &[String]) -> Vec<String> {
+    let given = args
+        .iter()
+        .filter(|arg| arg.starts_with("--"))
+        .collect::<HashSet<_>>();
+
+    let mut merged = vec![];
+    let mut keep = false;
+
+    for arg in installed {
+        // Values follow the option they belong to
+        if arg.starts_with("--") {
+            keep = !given.contains(arg);
+        }
+
+        if keep {
+            merged.push(arg.clone());
+        }
+    }
+
+    merged.extend(args.iter().cloned());
+    merged
+}
+
+/// Refuses a task removing recent files, unless --force is given
+fn check_task_age(config: &Config, update: bool) -> Result<()> {
+    // Removing only empty entries is safe at any age, an updated task keeps the
+    // --created-before checked when it was installed unless a new one is given
+    if config.force || config.empty_only || (update && config.since.is_none()) {
+        return Ok(());
+    }
+
+    match config.since {
+        None => bail!(
+            "Refusing to install a task that removes all temporary files at every startup, \
+            specify --created-before {} or longer (or --force to install it anyway)",
+            format_duration(MIN_TASK_AGE)
+        ),
+        Some(since) if since < MIN_TASK_AGE => bail!(
+            "Refusing to install a task with --created-before shorter than {}, \
+            files in use by the previous session may be removed (use --force to install it anyway)",
+            format_duration(MIN_TASK_AGE)
+        ),
+        Some(_) => Ok(()),
+    }
+}
+
 fn parse_args(config: &Config) -> Result<Vec<String>> {
     let mut args: Vec<String> = vec![];
 
+    if config.abort_on_system_dir {
+        args.push(String::from("--abort-on-system-dir"));
+    }
+
+    if config.age_basis != AgeBasis::Created {
+        args.push(String::from("--age-basis"));
+        args.push(String::from(config.age_basis.name()));
+    }
+
+    if let Some(pattern) = &config.age_from_name {
+        args.push(String::from("--age-from-name"));
+        args.push(format!("`\"{}`\"", pattern.as_str()));
+    }
+
+    if let Some(path) = &config.audit {
+        args.push(String::from("--audit"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if config.bytes_format == BytesFormat::Si {
+        args.push(String::from("--bytes-format"));
+        args.push(String::from("si"));
+    }
+
+    if config.clean_webcache {
+        args.push(String::from("--clean-webcache"));
+    }
+
+    if config.clean_update_cache {
+        args.push(String::from("--clean-windows-update-cache"));
+    }
+
+    if let Some(bytes) = config.compress_before_delete {
+        args.push(String::from("--compress-before-delete"));
+        args.push(bytes.to_string());
+    }
+
+    if let Some(path) = &config.continue_from {
+        args.push(String::from("--continue-from"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if config.count_ads {
+        args.push(String::from("--count-ads"));
+    }
+
+    if config.dedupe {
+        args.push(String::from("--dedupe"));
+    }
+
+    if let Some(path) = &config.delete_list {
+        args.push(String::from("--delete-list"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if config.delete_on_reboot {
+        args.push(String::from("--delete-on-reboot"));
+    }
+
+    if config.dereference_count != DEFAULT_DEREFERENCE_COUNT {
+        args.push(String::from("--dereference-count"));
+        args.push(config.dereference_count.to_string());
+    }
+
+    if let Some(bytes) = config.dir_max_size {
+        args.push(String::from("--dir-max-size"));
+        args.push(bytes.to_string());
+    }
+
+    if let Some(pattern) = &config.dir_name_pattern {
+        args.push(String::from("--dir-name-pattern"));
+        args.push(format!("`\"{}`\"", pattern.as_str()));
+    }
+
     if config.dry_run {
         args.push(String::from("--dry-run"));
     }
 
+    if let Some(threshold) = config.dry_run_apply_threshold {
+        args.push(String::from("--dry-run-apply-threshold"));
+        args.push(threshold.name());
+    }
+
+    if config.empty_only {
+        args.push(String::from("--empty-only"));
+    }
+
+    if config.exclude_busy_dirs {
+        args.push(String::from("--exclude-busy-dirs"));
+    }
+
+    if config.exclude_newer_than_task {
+        args.push(String::from("--exclude-newer-than-task"));
+    }
+
+    for rule in &config.extension_rules {
+        args.push(String::from("--extension-rule"));
+        args.push(format!("`\"{}`\"", rule.name()));
+    }
+
+    if config.failure_warning != DEFAULT_FAILURE_WARNING {
+        args.push(String::from("--failure-warning"));
+        args.push(config.failure_warning.to_string());
+    }
+
+    if let Some(command) = &config.filter_exec {
+        args.push(String::from("--filter-exec"));
+        args.push(format!("`\"{}`\"", command));
+    }
+
+    if let Some(count) = config.flush_every {
+        args.push(String::from("--flush-every"));
+        args.push(count.to_string());
+    }
+
+    for path in &config.follow_reparse_paths {
+        args.push(String::from("--follow-reparse-path"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if !config.keep_extensions.is_empty() {
+        args.push(String::from("--keep-extensions"));
+        args.push(config.keep_extensions.join(","));
+    }
+
+    if config.keep_update_service {
+        args.push(String::from("--keep-update-service-running"));
+    }
+
+    if config.limit_depth_report {
+        args.push(String::from("--limit-depth-report"));
+    }
+
+    if let Some(path) = &config.metrics_file {
+        args.push(String::from("--metrics-file"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if let Some(bytes) = config.min_dir_size {
+        args.push(String::from("--min-dir-size"));
+        args.push(bytes.to_string());
+    }
+
+    if config.no_cross_volume {
+        args.push(String::from("--no-cross-volume"));
+    }
+
+    if config.no_user_profiles {
+        args.push(String::from("--no-user-profiles"));
+    }
+
+    if let Some(window) = config.only_between {
+        args.push(String::from("--only-between"));
+        args.push(window.name());
+    }
+
+    if let Some(timeout) = config.op_timeout {
+        args.push(String::from("--op-timeout"));
+        args.push(format!("`\"{}`\"", humantime::format_duration(timeout)));
+    }
+
+    if let Some(percent) = config.gc_threshold {
+        args.push(String::from("--gc-threshold"));
+        args.push(percent.to_string());
+    }
+
+    if config.include_inetcache {
+        args.push(String::from("--include-inetcache"));
+    }
+
+    if config.include_readonly_volumes {
+        args.push(String::from("--include-readonly-volumes"));
+    }
+
+    if config.include_vs_temp {
+        args.push(String::from("--include-vs-temp"));
+    }
+
+    if let Some(threads) = config.parallel_users {
+        args.push(String::from("--parallel-users"));
+        args.push(threads.to_string());
+    }
+
+    if config.preserve_inetcache_index {
+        args.push(String::from("--preserve-inetcache-index"));
+    }
+
+    for preset in &config.presets {
+        args.push(String::from("--preset"));
+        args.push(preset.clone());
+    }
+
+    for pattern in &config.profiles {
+        args.push(String::from("--profile"));
+        args.push(format!("`\"{}`\"", pattern));
+    }
+
+    for dir in &config.protect_dirs {
+        args.push(String::from("--protect-dir"));
+        args.push(format!("`\"{}`\"", dir.display()));
+    }
+
+    if config.protect_running {
+        args.push(String::from("--protect-running"));
+    }
+
+    if let Some(path) = &config.quarantine_dir {
+        args.push(String::from("--quarantine-dir"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if let Some(ttl) = config.quarantine_ttl {
+        args.push(String::from("--quarantine-ttl"));
+        args.push(format!("`\"{}`\"", format_duration(ttl)));
+    }
+
+    if config.rename_pending {
+        args.push(String::from("--rename-pending"));
+    }
+
+    if config.report_free_space {
+        args.push(String::from("--report-free-space-before-after"));
+    }
+
+    if config.report_skipped {
+        args.push(String::from("--report-skipped"));
+    }
+
+    if !config.retention.is_empty() {
+        let tiers = config
+            .retention
+            .iter()
+            .map(|tier| format!("{}{}", tier.count, tier.unit))
+            .collect::<Vec<_>>();
+
+        args.push(String::from("--retention"));
+        args.push(tiers.join(","));
+    }
+
+    if let Some(level) = config.log_level {
+        args.push(String::from("--log-level"));
+        args.push(level.to_string().to_lowercase());
+    }
+
+    if config.log_compress {
+        args.push(String::from("--log-compress"));
+    }
+
+    if let Some(keep) = config.log_keep {
+        args.push(String::from("--log-keep"));
+        args.push(keep.to_string());
+    }
+
+    if let Some(level) = config.log_file_level {
+        args.push(String::from("--log-file-level"));
+        args.push(level.to_string().to_lowercase());
+    }
+
+    if let Some(host) = &config.remote {
+        args.push(String::from("--remote"));
+        args.push(format!("`\"{}`\"", host));
+    } else if let Some(path) = &config.root {
+        args.push(String::from("--root"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if let Some(path) = &config.scan_after {
+        args.push(String::from("--scan-after"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if let Some(path) = &config.scan_before {
+        args.push(String::from("--scan-before"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if config.sentinel != DEFAULT_SENTINEL {
+        args.push(String::from("--sentinel"));
+        args.push(format!("`\"{}`\"", config.sentinel));
+    }
+
+    if config.simulate_permissions {
+        args.push(String::from("--simulate-permissions"));
+    }
+
+    for (key, value) in &config.tags {
+        args.push(String::from("--tag"));
+        args.push(format!("`\"{}={}`\"", key, value));
+    }
+
+    if config.skip_cloud {
+        args.push(String::from("--skip-cloud"));
+    }
+
+    if let Some(within) = config.skip_if_storage_sense_ran_within {
+        args.push(String::from("--skip-if-storage-sense-ran-within"));
+        args.push(format!("`\"{}`\"", humantime::format_duration(within)));
+    }
+
+    if let Some(path) = &config.summary_file {
+        args.push(String::from("--summary-file"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
     if config.quiet {
         args.push(String::from("--quiet"));
     }
@@ -39,11 +517,45 @@ fn parse_args(config: &Config) -> Result<Vec<String>> {
         args.push(String::from("--verbose"));
     }
 
-    if let Some(since) = config.since {
+    // The marker is read again at every run, its age keeps growing
+    if let Some(marker) = &config.since_marker {
+        args.push(String::from("--created-before"));
+        args.push(format!("`\"@{}`\"", marker.display()));
+    } else if let Some(since) = config.since {
         args.push(String::from("--created-before"));
         args.push(format!("`\"{}`\"", humantime::format_duration(since)));
     }
 
+    if let Some(path) = &config.history {
+        args.push(String::from("--history"));
+        args.push(format!("`\"{}`\"", path.display()));
+    }
+
+    if let Some(idle) = config.idle {
+        args.push(String::from("--idle"));
+        args.push(format!("`\"{}`\"", humantime::format_duration(idle)));
+    }
+
+    if let Some(max_age) = config.created_after {
+        args.push(String::from("--created-after"));
+        args.push(format!("`\"{}`\"", humantime::format_duration(max_age)));
+    }
+
+    if let Some(bytes) = config.trim_sparse {
+        args.push(String::from("--trim-sparse"));
+        args.push(bytes.to_string());
+    }
+
+    if let Some(percent) = config.when_above {
+        args.push(String::from("--when-above"));
+        args.push(percent.to_string());
+    }
+
+    if let Some(bytes) = config.log_max_size {
+        args.push(String::from("--log-max-size"));
+        args.push(bytes.to_string());
+    }
+
     if let Some(log_path) = &config.log_path {
         test_log(log_path)
             .with_context(|| format!("Unable to create or open the log file {}", log_path))?;
@@ -58,7 +570,7 @@ fn parse_args(config: &Config) -> Result<Vec<String>> {
 }
 
 fn test_log(log_path: &str) -> Result<()> {
-    let log_path = Path::new(log_path);
+    let log_path = &resolve_log_path(Path::new(log_path));
     let existed = log_path.exists();
     open_log_file(log_path)?;
 
@@ -69,9 +581,28 @@ fn test_log(log_path: &str) -> Result<()> {
     Ok(())
 }
 
-fn run_script(task_path: &str, task_name: &str, clean_args: &[String]) -> Result<()> {
+/// Returns the PowerShell script registering the task, or updating its action
+fn build_script(
+    task_path: &str,
+    task_name: &str,
+    clean_args: &[String],
+    update: bool,
+) -> Result<String> {
     let mut script = String::new();
 
+    // Updating replaces only the action, keeping the triggers and settings of the task
+    let register = if update {
+        format!(
+            "Set-ScheduledTask -TaskPath \"{}\" -TaskName \"{}\" -Action $action",
+            task_path, task_name
+        )
+    } else {
+        format!(
+            "Register-ScheduledTask -Force -TaskPath \"{}\" -TaskName \"{}\" -InputObject $task -User SYSTEM",
+            task_path, task_name
+        )
+    };
+
     writeln!(
         script,
         "\
@@ -86,17 +617,22 @@ $action = New-ScheduledTaskAction -Execute \"$currentExe\" -Argument \"{}\"
 $trigger = New-ScheduledTaskTrigger -AtStartup
 $settings = New-ScheduledTaskSettingsSet
 $task = New-ScheduledTask -Action $action -Trigger $trigger -Settings $settings
-Register-ScheduledTask -Force -TaskPath \"{}\" -TaskName \"{}\" -InputObject $task -User SYSTEM
+{}
     ",
         std::env::current_exe()?.display(),
         clean_args.join(" "),
-        task_path,
-        task_name
+        register
     )?;
 
+    Ok(script)
+}
+
+fn run_script(script: &str, update: bool, config: &Config) -> Result<()> {
+    let script = script.to_string();
+
     let mut process = std::process::Command::new("powershell.exe")
         // -WindowStyle Hidden not included because it makes the child process detach early
-        .args(&["-NonInteractive", "-NoProfile", "-Command", "-"])
+        .args(["-NonInteractive", "-NoProfile", "-Command", "-"])
         // Don't create a window for the spawned process
         .creation_flags(winapi::um::winbase::CREATE_NO_WINDOW)
         .stdout(Stdio::piped())
@@ -104,6 +640,22 @@ Register-ScheduledTask -Force -TaskPath \"{}\" -TaskName \"{}\" -InputObject $ta
         .stdin(Stdio::piped())
         .spawn()?;
 
+    // The limits apply from the assignment, right after the process started. The job
+    // is kept until the process exited.
+    let _job =
+        if config.task_install_cpu_limit.is_some() || config.task_install_memory_limit.is_some() {
+            let job = JobObject::with_limits(
+                config.task_install_cpu_limit,
+                config.task_install_memory_limit,
+            )
+            .context("Unable to create the job object limiting PowerShell")?;
+            job.assign(&process)
+                .context("Unable to limit the resources of PowerShell")?;
+            Some(job)
+        } else {
+            None
+        };
+
     let mut child_out = process.stdout.take().unwrap();
     let mut child_err = process.stderr.take().unwrap();
     let mut child_in = process.stdin.take().unwrap();
@@ -125,7 +677,9 @@ Register-ScheduledTask -Force -TaskPath \"{}\" -TaskName \"{}\" -InputObject $ta
     err_thread.join().unwrap();
     out_thread.join().unwrap();
 
-    if status.success() {
+    if status.success() && update {
+        println!("Task updated successfully");
+    } else if status.success() {
         println!("Task created successfully");
     } else {
         bail!("Error while creating the task");
@@ -133,3 +687,73 @@ Register-ScheduledTask -Force -TaskPath \"{}\" -TaskName \"{}\" -InputObject $ta
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::config;
+
+    #[test]
+    fn task_without_age_filter_is_refused() {
+        assert!(check_task_age(&config(&["--install-task"]), false).is_err());
+        assert!(check_task_age(
+            &config(&["--install-task", "--created-before", "1h"]),
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn task_age_filter_is_allowed() {
+        assert!(check_task_age(&config(&["--install-task", "--force"]), false).is_ok());
+        assert!(check_task_age(
+            &config(&["--install-task", "--created-before", "1d"]),
+            false
+        )
+        .is_ok());
+        assert!(check_task_age(&config(&["--install-task", "--empty-only"]), false).is_ok());
+        // The age of the installed task is kept
+        assert!(check_task_age(&config(&["--install-task", "--update-task"]), true).is_ok());
+        assert!(check_task_age(
+            &config(&["--install-task", "--update-task", "--created-before", "1h"]),
+            true
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn previewed_script_contains_the_task() {
+        let config = config(&[
+            "--install-task",
+            "--show-task-script",
+            "--created-before",
+            "7d",
+            "--abort-on-system-dir",
+        ]);
+        let args = parse_args(&config).unwrap();
+
+        assert!(args.contains(&String::from("--abort-on-system-dir")));
+        assert!(args.contains(&String::from("`\"7days`\"")));
+
+        let script = build_script("wintempclean", "wintempclean", &args, false).unwrap();
+
+        assert!(script.contains("$ErrorActionPreference = \"Stop\""));
+        assert!(script.contains(&format!(
+            "$currentExe = \"{}\"",
+            std::env::current_exe().unwrap().display()
+        )));
+        assert!(script.contains(&format!("-Argument \"{}\"", args.join(" "))));
+        assert!(script.contains("New-ScheduledTaskTrigger -AtStartup"));
+        assert!(script.contains(
+            "Register-ScheduledTask -Force -TaskPath \"wintempclean\" -TaskName \"wintempclean\""
+        ));
+        assert!(!script.contains("Set-ScheduledTask"));
+
+        let script = build_script("wintempclean", "wintempclean", &args, true).unwrap();
+
+        assert!(script.contains(
+            "Set-ScheduledTask -TaskPath \"wintempclean\" -TaskName \"wintempclean\" -Action $action"
+        ));
+        assert!(!script.contains("Register-ScheduledTask"));
+    }
+}
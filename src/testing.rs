@@ -0,0 +1,68 @@
+//! Helpers shared by the unit tests.
+
+use std::env;
+use std::fs::{self, FileTimes};
+use std::iter;
+use std::os::windows::fs::FileTimesExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use crate::app::build_app;
+use crate::config::{build_config, Config};
+
+/// Returns an empty directory under the system temporary directory, unique to the test
+/// name and the process so tests can run in parallel
+pub fn temp_dir(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!(
+        "{}-{}-{}",
+        clap::crate_name!(),
+        process::id(),
+        name
+    ));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// Returns the configuration built from the command line arguments, without the program name
+pub fn config(args: &[&str]) -> Config {
+    try_config(args).unwrap()
+}
+
+/// Builds the configuration like config, returning the validation errors
+pub fn try_config(args: &[&str]) -> Result<Config> {
+    let matches = build_app()
+        .try_get_matches_from(iter::once(clap::crate_name!()).chain(args.iter().copied()))
+        .unwrap();
+    build_config(&matches)
+}
+
+/// Creates a directory junction, which unlike symbolic links needs no privilege
+pub fn create_junction(link: &Path, target: &Path) {
+    let status = Command::new("cmd.exe")
+        .args(["/C", "mklink", "/J"])
+        .arg(link)
+        .arg(target)
+        .status()
+        .unwrap();
+    assert!(status.success(), "mklink /J {}", link.display());
+}
+
+/// Sets the creation, modification and access times of the file to the given ages
+pub fn set_file_ages(path: &Path, created: Duration, modified: Duration, accessed: Duration) {
+    let now = SystemTime::now();
+    let times = FileTimes::new()
+        .set_created(now - created)
+        .set_modified(now - modified)
+        .set_accessed(now - accessed);
+
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_times(times)
+        .unwrap();
+}
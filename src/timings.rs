@@ -0,0 +1,106 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+/// Files created and removed by estimate_deletion_throughput
+const SAMPLE_FILES: u32 = 50;
+const SAMPLE_FILE_SIZE: usize = 4096;
+
+/// Time spent in each phase of a run, printed with --timings
+#[derive(Default)]
+pub struct Timings {
+    /// Resolving the directories to clean
+    pub discovery: Duration,
+    /// Enumerating the directories and applying the filters
+    pub walking: Duration,
+    /// Removing the entries
+    pub deletion: Duration,
+}
+
+impl Timings {
+    /// Returns the walking time without the removals, which are timed within the walk
+    pub fn walking_only(&self) -> Duration {
+        self.walking.saturating_sub(self.deletion)
+    }
+
+    pub fn print(&self) {
+        info!("Timings:");
+        for (phase, duration) in [
+            ("discovery", self.discovery),
+            ("walking", self.walking_only()),
+            ("deletion", self.deletion),
+        ] {
+            info!("  {:>9}: {}", phase, format_seconds(duration));
+        }
+    }
+}
+
+/// Creates, writes and removes test files in a new subdirectory of the path, returning
+/// the files handled per second
+pub fn estimate_deletion_throughput(sample_path: &Path) -> Result<f64> {
+    let dir = sample_path.join(format!(
+        ".{}-estimate-{}",
+        clap::crate_name!(),
+        std::process::id()
+    ));
+    fs::create_dir(&dir)
+        .with_context(|| format!("Unable to create the directory {}", dir.display()))?;
+
+    let started = Instant::now();
+    let result = (|| -> std::io::Result<()> {
+        for n in 0..SAMPLE_FILES {
+            let file = dir.join(format!("{}.tmp", n));
+            fs::write(&file, [0u8; SAMPLE_FILE_SIZE])?;
+            fs::remove_file(&file)?;
+        }
+        Ok(())
+    })();
+    let elapsed = started.elapsed();
+
+    // Removes the files left by a failure too
+    let _ = fs::remove_dir_all(&dir);
+    result.with_context(|| format!("Unable to measure the throughput in {}", dir.display()))?;
+
+    Ok(SAMPLE_FILES as f64 / elapsed.as_secs_f64().max(f64::EPSILON))
+}
+
+/// Formats the duration in seconds with millisecond precision
+pub fn format_seconds(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walking_excludes_the_deletion() {
+        let timings = Timings {
+            walking: Duration::from_millis(1500),
+            deletion: Duration::from_millis(400),
+            ..Timings::default()
+        };
+
+        assert_eq!(timings.walking_only(), Duration::from_millis(1100));
+    }
+
+    #[test]
+    fn walking_never_goes_below_zero() {
+        // The separate measures can make the deletions exceed the walk
+        let timings = Timings {
+            walking: Duration::from_millis(100),
+            deletion: Duration::from_millis(400),
+            ..Timings::default()
+        };
+
+        assert_eq!(timings.walking_only(), Duration::ZERO);
+    }
+
+    #[test]
+    fn seconds_with_milliseconds() {
+        assert_eq!(format_seconds(Duration::from_millis(1500)), "1.500s");
+        assert_eq!(format_seconds(Duration::ZERO), "0.000s");
+    }
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use crate::output::json_string;
+use crate::windows::{volume_of, Volume};
+
+/// Entries removed from the cleaned directories of a single volume
+pub struct VolumeStats {
+    /// Cleaned directories of the volume with removed entries
+    pub dirs: u64,
+    pub freed_bytes: u64,
+    pub freed_count: u64,
+    pub volume: Volume,
+}
+
+impl VolumeStats {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"volume\":{},\"serial\":{},\"freed_bytes\":{},\"freed_count\":{},\"directories\":{}}}",
+            json_string(&self.volume.name()),
+            self.volume.serial,
+            self.freed_bytes,
+            self.freed_count,
+            self.dirs
+        )
+    }
+}
+
+/// Adds the entries removed from the directory to the stats of the volume containing it
+pub fn add_volume_stats(
+    volumes: &mut Vec<VolumeStats>,
+    path: &Path,
+    freed_bytes: u64,
+    freed_count: u64,
+) {
+    if freed_count == 0 {
+        return;
+    }
+
+    let volume = match volume_of(path) {
+        Ok(volume) => volume,
+        Err(err) => {
+            debug!("Can't read the volume of {}: {}", path.display(), err);
+            return;
+        }
+    };
+
+    match volumes
+        .iter_mut()
+        .find(|x| x.volume.serial == volume.serial)
+    {
+        Some(stats) => {
+            stats.dirs += 1;
+            stats.freed_bytes += freed_bytes;
+            stats.freed_count += freed_count;
+        }
+        None => volumes.push(VolumeStats {
+            dirs: 1,
+            freed_bytes,
+            freed_count,
+            volume,
+        }),
+    }
+}
@@ -3,13 +3,81 @@
 // Use std::io::Error::last_os_error for errors.
 // NOTE: For this example I'm simple passing on the OS error.
 // However, customising the error could provide more context
-use std::io::Error;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{Error, ErrorKind, Read};
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::os::windows::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use winapi::um::handleapi::CloseHandle;
-use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+use winapi::shared::minwindef::{DWORD, FALSE, HMODULE, MAX_PATH, TRUE};
+use winapi::shared::ntdef::{LARGE_INTEGER, ULARGE_INTEGER, WCHAR};
+use winapi::shared::winerror::{
+    ERROR_ACCESS_DENIED, ERROR_BAD_NETPATH, ERROR_BAD_NET_NAME, ERROR_CANCELLED,
+    ERROR_DIR_NOT_EMPTY, ERROR_HANDLE_EOF, ERROR_LOGON_FAILURE, ERROR_MORE_DATA,
+    ERROR_SERVICE_ALREADY_RUNNING, ERROR_SERVICE_NOT_ACTIVE, ERROR_SHARING_VIOLATION,
+    ERROR_SUCCESS, ERROR_TIMEOUT,
+};
+use winapi::um::consoleapi::{GetConsoleMode, SetConsoleCtrlHandler, SetConsoleMode};
+use winapi::um::fileapi::{
+    CreateFileW, FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+    GetDiskFreeSpaceExW, GetFileInformationByHandle, GetVolumeInformationW, GetVolumePathNameW,
+    BY_HANDLE_FILE_INFORMATION, OPEN_EXISTING,
+};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::DeviceIoControl;
+#[cfg(feature = "task-scheduler")]
+use winapi::um::jobapi2::{AssignProcessToJobObject, CreateJobObjectW, SetInformationJobObject};
+use winapi::um::minwinbase::SYSTEMTIME;
+use winapi::um::processenv::GetStdHandle;
+use winapi::um::processthreadsapi::{
+    GetCurrentProcess, GetExitCodeProcess, OpenProcess, OpenProcessToken,
+};
+use winapi::um::psapi::{
+    EnumProcessModulesEx, EnumProcesses, GetModuleFileNameExW, LIST_MODULES_ALL,
+};
+use winapi::um::restartmanager::{
+    RmEndSession, RmGetList, RmRegisterResources, RmStartSession, CCH_RM_SESSION_KEY,
+};
 use winapi::um::securitybaseapi::GetTokenInformation;
-use winapi::um::winnt::{TokenElevation, HANDLE, TOKEN_ELEVATION, TOKEN_QUERY};
+use winapi::um::shellapi::{
+    ShellExecuteExW, SEE_MASK_NOASYNC, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW,
+};
+use winapi::um::synchapi::WaitForSingleObject;
+use winapi::um::sysinfoapi::GetLocalTime;
+use winapi::um::tlhelp32::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
+use winapi::um::winbase::{
+    MoveFileExW, CREATE_NO_WINDOW, FILE_FLAG_BACKUP_SEMANTICS, INFINITE,
+    MOVEFILE_DELAY_UNTIL_REBOOT, STD_OUTPUT_HANDLE, WAIT_FAILED,
+};
+use winapi::um::wincon::{ENABLE_VIRTUAL_TERMINAL_PROCESSING, PHANDLER_ROUTINE};
+use winapi::um::winioctl::{FSCTL_SET_SPARSE, FSCTL_SET_ZERO_DATA};
+use winapi::um::winnls::{GetLocaleInfoEx, LCTYPE};
+#[cfg(feature = "task-scheduler")]
+use winapi::um::winnt::{
+    JobObjectExtendedLimitInformation, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE, JOB_OBJECT_LIMIT_PROCESS_MEMORY,
+    JOB_OBJECT_LIMIT_PROCESS_TIME,
+};
+use winapi::um::winnt::{
+    TokenElevation, DELETE, FILE_READ_ATTRIBUTES, FILE_READ_ONLY_VOLUME, FILE_SHARE_DELETE,
+    FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, HANDLE, LPCWSTR, PROCESS_QUERY_INFORMATION,
+    PROCESS_VM_READ, TOKEN_ELEVATION, TOKEN_QUERY,
+};
+use winapi::um::winsvc::{
+    CloseServiceHandle, ControlService, OpenSCManagerW, OpenServiceW, QueryServiceStatus,
+    StartServiceW, SC_HANDLE, SC_MANAGER_CONNECT, SERVICE_CONTROL_STOP, SERVICE_QUERY_STATUS,
+    SERVICE_START, SERVICE_STATUS, SERVICE_STOP, SERVICE_STOPPED,
+};
+use winapi::um::winuser::SW_SHOWNORMAL;
 
 /// Returns true if the current process has admin rights, otherwise false.
 pub fn is_app_elevated() -> bool {
@@ -70,3 +138,996 @@ impl Drop for QueryAccessToken {
         }
     }
 }
+
+/// Starts the executable as administrator with the arguments, after the UAC prompt, and
+/// waits for it to exit. On success returns its exit code. Otherwise returns an OS error,
+/// see is_cancelled_error for a declined prompt.
+pub fn run_elevated(exe: &Path, args: &[OsString]) -> Result<u32, Error> {
+    let file = to_wide(exe);
+    let verb = OsStr::new("runas")
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<_>>();
+
+    let mut parameters = vec![];
+    for arg in args {
+        if !parameters.is_empty() {
+            parameters.push(b' ' as u16);
+        }
+        append_quoted_arg(&mut parameters, arg);
+    }
+    parameters.push(0);
+
+    // The elevated process would start in the system directory otherwise
+    let directory = std::env::current_dir()?;
+    let directory = to_wide(&directory);
+
+    let mut info: SHELLEXECUTEINFOW = unsafe { std::mem::zeroed() };
+    info.cbSize = std::mem::size_of::<SHELLEXECUTEINFOW>() as u32;
+    info.fMask = SEE_MASK_NOCLOSEPROCESS | SEE_MASK_NOASYNC;
+    info.lpVerb = verb.as_ptr();
+    info.lpFile = file.as_ptr();
+    info.lpParameters = parameters.as_ptr();
+    info.lpDirectory = directory.as_ptr();
+    info.nShow = SW_SHOWNORMAL;
+
+    if unsafe { ShellExecuteExW(&mut info) } == 0 {
+        return Err(Error::last_os_error());
+    }
+    let process = ProcessHandle(info.hProcess);
+
+    unsafe {
+        if WaitForSingleObject(process.0, INFINITE) == WAIT_FAILED {
+            return Err(Error::last_os_error());
+        }
+
+        let mut code = 0;
+        if GetExitCodeProcess(process.0, &mut code) == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(code)
+    }
+}
+
+/// Returns true if the error means the user declined the UAC prompt.
+pub fn is_cancelled_error(err: &Error) -> bool {
+    err.raw_os_error() == Some(ERROR_CANCELLED as i32)
+}
+
+/// Appends the argument quoted so that CommandLineToArgvW parses it back unchanged.
+fn append_quoted_arg(line: &mut Vec<u16>, arg: &OsStr) {
+    let arg = arg.encode_wide().collect::<Vec<_>>();
+    let needs_quotes = arg.is_empty()
+        || arg
+            .iter()
+            .any(|c| *c == b' ' as u16 || *c == b'\t' as u16 || *c == b'"' as u16);
+
+    if !needs_quotes {
+        line.extend(arg);
+        return;
+    }
+
+    // Backslashes are literal unless followed by a quote, then they are doubled
+    line.push(b'"' as u16);
+    let mut backslashes = 0;
+    for c in arg {
+        if c == b'\\' as u16 {
+            backslashes += 1;
+        } else {
+            if c == b'"' as u16 {
+                line.extend(std::iter::repeat_n(b'\\' as u16, backslashes + 1));
+            }
+            backslashes = 0;
+        }
+        line.push(c);
+    }
+    line.extend(std::iter::repeat_n(b'\\' as u16, backslashes));
+    line.push(b'"' as u16);
+}
+
+/// A safe wrapper around process handles.
+struct ProcessHandle(HANDLE);
+impl Drop for ProcessHandle {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
+
+/// Returns the volume serial number and file index identifying the file or directory,
+/// following symbolic links and junctions to their target.
+pub fn file_id(path: &Path) -> Result<(u32, u64), Error> {
+    let handle = FileHandle::open(path, FILE_READ_ATTRIBUTES)?;
+    let info = handle.information()?;
+    let index = ((info.nFileIndexHigh as u64) << 32) | info.nFileIndexLow as u64;
+
+    Ok((info.dwVolumeSerialNumber, index))
+}
+
+/// Returns true if another process holds the file open, preventing exclusive access.
+pub fn is_file_in_use(path: &Path) -> bool {
+    match FileHandle::open_shared(path, GENERIC_READ, 0) {
+        Ok(_) => false,
+        Err(err) => err.raw_os_error() == Some(ERROR_SHARING_VIOLATION as i32),
+    }
+}
+
+/// Returns true if the error means the file is locked by another process or protected.
+pub fn is_locked_error(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(code) if code == ERROR_ACCESS_DENIED as i32 || code == ERROR_SHARING_VIOLATION as i32
+    )
+}
+
+/// Returns true if the error means the host is offline or doesn't share the path.
+pub fn is_unreachable_error(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(code) if code == ERROR_BAD_NETPATH as i32 || code == ERROR_BAD_NET_NAME as i32
+    )
+}
+
+/// Returns true if the error means the host refused the credentials of the process.
+pub fn is_logon_error(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(code) if code == ERROR_ACCESS_DENIED as i32 || code == ERROR_LOGON_FAILURE as i32
+    )
+}
+
+/// Returns true if the error means the directory still has entries.
+pub fn is_dir_not_empty_error(err: &Error) -> bool {
+    err.raw_os_error() == Some(ERROR_DIR_NOT_EMPTY as i32)
+}
+
+/// Schedules the file or empty directory for deletion at the next reboot.
+/// Requires admin rights, otherwise returns an OS error.
+pub fn delete_on_reboot(path: &Path) -> Result<(), Error> {
+    let path = to_wide(path);
+    unsafe {
+        if MoveFileExW(path.as_ptr(), ptr::null(), MOVEFILE_DELAY_UNTIL_REBOOT) != 0 {
+            Ok(())
+        } else {
+            Err(Error::last_os_error())
+        }
+    }
+}
+
+/// Size of the blocks checked for zeroes when trimming sparse files
+const TRIM_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Input of FSCTL_SET_ZERO_DATA, missing from winapi 0.3
+#[repr(C)]
+#[allow(non_snake_case)]
+struct FILE_ZERO_DATA_INFORMATION {
+    FileOffset: LARGE_INTEGER,
+    BeyondFinalZero: LARGE_INTEGER,
+}
+
+/// Marks the file as sparse and deallocates the blocks containing only zeroes,
+/// leaving the content of the file unchanged. The file can be in use by other processes.
+///
+/// On success returns the number of bytes deallocated. Otherwise returns an OS error.
+pub fn trim_sparse(path: &Path) -> Result<u64, Error> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+        .open(path)?;
+
+    let ranges = zeroed_ranges(&mut file)?;
+    if ranges.is_empty() {
+        return Ok(0);
+    }
+
+    let handle = file.as_raw_handle() as HANDLE;
+    let mut trimmed = 0;
+
+    unsafe {
+        let mut returned = 0;
+        if DeviceIoControl(
+            handle,
+            FSCTL_SET_SPARSE,
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+        ) == 0
+        {
+            return Err(Error::last_os_error());
+        }
+
+        for (start, end) in ranges {
+            let mut zero_data: FILE_ZERO_DATA_INFORMATION = std::mem::zeroed();
+            *zero_data.FileOffset.QuadPart_mut() = start as i64;
+            *zero_data.BeyondFinalZero.QuadPart_mut() = end as i64;
+
+            if DeviceIoControl(
+                handle,
+                FSCTL_SET_ZERO_DATA,
+                &mut zero_data as *mut _ as *mut _,
+                std::mem::size_of::<FILE_ZERO_DATA_INFORMATION>() as u32,
+                ptr::null_mut(),
+                0,
+                &mut returned,
+                ptr::null_mut(),
+            ) == 0
+            {
+                return Err(Error::last_os_error());
+            }
+
+            trimmed += end - start;
+        }
+    }
+
+    Ok(trimmed)
+}
+
+/// Returns the contiguous ranges of the file made only of zeroes, as (start, end) offsets.
+fn zeroed_ranges(file: &mut fs::File) -> Result<Vec<(u64, u64)>, Error> {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    let mut buffer = vec![0; TRIM_BLOCK_SIZE];
+    let mut offset = 0;
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        let end = offset + read as u64;
+        if buffer[..read].iter().all(|&byte| byte == 0) {
+            // Extend the previous range if adjacent
+            match ranges.last_mut() {
+                Some(last) if last.1 == offset => last.1 = end,
+                _ => ranges.push((offset, end)),
+            }
+        }
+
+        offset = end;
+    }
+
+    Ok(ranges)
+}
+
+/// Space figures of a volume, in bytes.
+pub struct DiskSpace {
+    pub free: u64,
+    pub total: u64,
+}
+
+impl DiskSpace {
+    /// Returns the used percentage of the volume.
+    pub fn used_percent(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+
+        (self.total - self.free) as f64 * 100.0 / self.total as f64
+    }
+
+    /// Returns the free percentage of the volume.
+    pub fn free_percent(&self) -> f64 {
+        100.0 - self.used_percent()
+    }
+
+    /// Returns true if the used percentage reached the threshold.
+    pub fn is_used_above(&self, threshold: f64) -> bool {
+        self.used_percent() >= threshold
+    }
+}
+
+/// On success returns the space figures of the volume containing the path.
+/// Otherwise returns an OS error.
+pub fn disk_space(path: &Path) -> Result<DiskSpace, Error> {
+    let path = to_wide(path);
+    unsafe {
+        let mut total: ULARGE_INTEGER = std::mem::zeroed();
+        let mut free: ULARGE_INTEGER = std::mem::zeroed();
+        if GetDiskFreeSpaceExW(path.as_ptr(), ptr::null_mut(), &mut total, &mut free) != 0 {
+            Ok(DiskSpace {
+                free: *free.QuadPart(),
+                total: *total.QuadPart(),
+            })
+        } else {
+            Err(Error::last_os_error())
+        }
+    }
+}
+
+/// Identifies the volume containing a path.
+pub struct Volume {
+    /// Root directory of the volume, like C:\
+    pub root: PathBuf,
+    pub serial: u32,
+    /// File system flags, like FILE_READ_ONLY_VOLUME
+    pub flags: u32,
+}
+
+impl Volume {
+    /// Returns the volume name without the trailing separator, like C:
+    pub fn name(&self) -> String {
+        self.root
+            .display()
+            .to_string()
+            .trim_end_matches('\\')
+            .to_string()
+    }
+
+    /// Returns true if the volume is mounted read-only, where every removal would fail
+    pub fn is_read_only(&self) -> bool {
+        self.flags & FILE_READ_ONLY_VOLUME != 0
+    }
+}
+
+/// On success returns the volume containing the path.
+/// Otherwise returns an OS error.
+pub fn volume_of(path: &Path) -> Result<Volume, Error> {
+    let path = to_wide(path);
+    unsafe {
+        let mut root = [0u16; MAX_PATH + 1];
+        if GetVolumePathNameW(path.as_ptr(), root.as_mut_ptr(), root.len() as u32) == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut serial = 0;
+        let mut flags = 0;
+        if GetVolumeInformationW(
+            root.as_ptr(),
+            ptr::null_mut(),
+            0,
+            &mut serial,
+            ptr::null_mut(),
+            &mut flags,
+            ptr::null_mut(),
+            0,
+        ) == 0
+        {
+            return Err(Error::last_os_error());
+        }
+
+        let len = root.iter().position(|&c| c == 0).unwrap_or(root.len());
+        Ok(Volume {
+            root: PathBuf::from(OsString::from_wide(&root[..len])),
+            serial,
+            flags,
+        })
+    }
+}
+
+/// Buffer filled by FindFirstStreamW and FindNextStreamW, missing from winapi 0.3
+#[repr(C)]
+#[allow(non_snake_case)]
+struct WIN32_FIND_STREAM_DATA {
+    StreamSize: LARGE_INTEGER,
+    cStreamName: [WCHAR; MAX_PATH + 36],
+}
+
+/// On success returns the total size of the alternate data streams of the file or directory,
+/// the unnamed default stream is excluded. Otherwise returns an OS error.
+pub fn alternate_streams_size(path: &Path) -> Result<u64, Error> {
+    let path = to_wide(path);
+    unsafe {
+        let mut data: WIN32_FIND_STREAM_DATA = std::mem::zeroed();
+        let handle = FindFirstStreamW(
+            path.as_ptr(),
+            FindStreamInfoStandard,
+            &mut data as *mut _ as *mut _,
+            0,
+        );
+        if handle == INVALID_HANDLE_VALUE {
+            // Directories usually have no streams at all
+            let err = Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(code) if code == ERROR_HANDLE_EOF as i32 => Ok(0),
+                _ => Err(err),
+            };
+        }
+
+        let handle = FindHandle(handle);
+        let mut size = 0;
+
+        loop {
+            let len = data
+                .cStreamName
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(data.cStreamName.len());
+            if String::from_utf16_lossy(&data.cStreamName[..len]) != "::$DATA" {
+                size += *data.StreamSize.QuadPart() as u64;
+            }
+
+            if FindNextStreamW(handle.0, &mut data as *mut _ as *mut _) == 0 {
+                let err = Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(code) if code == ERROR_HANDLE_EOF as i32 => Ok(size),
+                    _ => Err(err),
+                };
+            }
+        }
+    }
+}
+
+/// Succeeds if the file or directory can be opened for deletion, which fails like
+/// the removal would when access is denied or another process holds it without
+/// sharing. Nothing is changed. Otherwise returns an OS error.
+pub fn can_delete(path: &Path) -> Result<(), Error> {
+    FileHandle::open(path, DELETE).map(|_| ())
+}
+
+/// On success returns the number of hard links to the file.
+/// Otherwise returns an OS error.
+pub fn link_count(path: &Path) -> Result<u32, Error> {
+    let handle = FileHandle::open(path, FILE_READ_ATTRIBUTES)?;
+    Ok(handle.information()?.nNumberOfLinks)
+}
+
+/// Returns the thousands and decimal separators of the user locale, defaulting to , and .
+pub fn number_separators() -> (String, String) {
+    (
+        locale_info(LOCALE_STHOUSAND).unwrap_or_else(|| String::from(",")),
+        locale_info(LOCALE_SDECIMAL).unwrap_or_else(|| String::from(".")),
+    )
+}
+
+/// Enables the ANSI escape sequences on the standard output console.
+/// Returns an OS error if the output isn't a console or doesn't support them.
+pub fn enable_ansi_colors() -> Result<(), Error> {
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode = 0;
+        if GetConsoleMode(handle, &mut mode) == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        if SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) == 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the handler of the console control events, like CTRL_SHUTDOWN_EVENT.
+/// On success returns (). Otherwise returns an OS error.
+pub fn set_console_ctrl_handler(handler: PHANDLER_ROUTINE) -> Result<(), Error> {
+    unsafe {
+        if SetConsoleCtrlHandler(handler, TRUE) == 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Scheduled task of Storage Sense, whose last run time is recorded by the Task Scheduler
+const STORAGE_SENSE_TASK_PATH: &str = r"\Microsoft\Windows\DiskFootprint\";
+const STORAGE_SENSE_TASK_NAME: &str = "StorageSense";
+/// Last run times before this are the placeholder of the tasks that never ran, in seconds
+/// since the Unix epoch (2000-01-01)
+const NEVER_RAN_BEFORE: i64 = 946_684_800;
+
+/// On success returns the time the Storage Sense scheduled task last ran, read through
+/// PowerShell. Otherwise returns an error, of kind NotFound if it never ran.
+pub fn get_storage_sense_last_run() -> Result<SystemTime, Error> {
+    let script = format!(
+        "([DateTimeOffset](Get-ScheduledTaskInfo -TaskPath '{}' -TaskName '{}' \
+        -ErrorAction Stop).LastRunTime).ToUnixTimeSeconds()",
+        STORAGE_SENSE_TASK_PATH, STORAGE_SENSE_TASK_NAME
+    );
+
+    let output = Command::new("powershell.exe")
+        .args(["-NonInteractive", "-NoProfile", "-Command", &script])
+        // Don't create a window for the spawned process
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    let seconds = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<i64>()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    if seconds < NEVER_RAN_BEFORE {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "the Storage Sense task never ran",
+        ));
+    }
+
+    Ok(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+/// A safe wrapper around job objects, which limit the resources of the processes
+/// assigned to them. The processes are killed when the job is dropped.
+#[cfg(feature = "task-scheduler")]
+pub struct JobObject(HANDLE);
+#[cfg(feature = "task-scheduler")]
+impl JobObject {
+    /// On success returns a job limiting the CPU time and the memory of each of its
+    /// processes, a process exceeding a limit is killed. Otherwise returns an OS error.
+    pub fn with_limits(cpu_time: Option<Duration>, memory: Option<u64>) -> Result<Self, Error> {
+        let handle = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+        if handle.is_null() {
+            return Err(Error::last_os_error());
+        }
+        let job = JobObject(handle);
+
+        let mut limits = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+        limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+
+        if let Some(cpu_time) = cpu_time {
+            // The time is in intervals of 100 nanoseconds
+            let intervals = (cpu_time.as_nanos() / 100).min(i64::MAX as u128) as i64;
+            unsafe {
+                *limits
+                    .BasicLimitInformation
+                    .PerProcessUserTimeLimit
+                    .QuadPart_mut() = intervals
+            };
+            limits.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_TIME;
+        }
+
+        if let Some(memory) = memory {
+            limits.ProcessMemoryLimit = memory.min(usize::MAX as u64) as usize;
+            limits.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+        }
+
+        let set = unsafe {
+            SetInformationJobObject(
+                job.0,
+                JobObjectExtendedLimitInformation,
+                &mut limits as *mut _ as *mut _,
+                std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+            )
+        };
+        if set == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(job)
+    }
+
+    /// On success the process and the processes it starts from now on are in the job.
+    /// Otherwise returns an OS error.
+    pub fn assign(&self, process: &impl AsRawHandle) -> Result<(), Error> {
+        if unsafe { AssignProcessToJobObject(self.0, process.as_raw_handle() as HANDLE) } == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+#[cfg(feature = "task-scheduler")]
+impl Drop for JobObject {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// Time a service is given to stop
+const SERVICE_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+const SERVICE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// On success returns true if the service was running and has stopped, false if it
+/// wasn't running. Otherwise returns an OS error, ERROR_TIMEOUT if it didn't stop in time.
+pub fn stop_service(name: &str) -> Result<bool, Error> {
+    let service = ServiceHandle::open(name, SERVICE_STOP | SERVICE_QUERY_STATUS)?;
+
+    let mut status = SERVICE_STATUS::default();
+    if unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) } == 0 {
+        let err = Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_SERVICE_NOT_ACTIVE as i32) {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+
+    let started = Instant::now();
+    while status.dwCurrentState != SERVICE_STOPPED {
+        if started.elapsed() > SERVICE_STOP_TIMEOUT {
+            return Err(Error::from_raw_os_error(ERROR_TIMEOUT as i32));
+        }
+
+        thread::sleep(SERVICE_POLL_INTERVAL);
+        if unsafe { QueryServiceStatus(service.0, &mut status) } == 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(true)
+}
+
+/// On success the service is starting or already running. Otherwise returns an OS error.
+pub fn start_service(name: &str) -> Result<(), Error> {
+    let service = ServiceHandle::open(name, SERVICE_START)?;
+
+    if unsafe { StartServiceW(service.0, 0, ptr::null_mut()) } == 0 {
+        let err = Error::last_os_error();
+        if err.raw_os_error() != Some(ERROR_SERVICE_ALREADY_RUNNING as i32) {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// A safe wrapper around service and service manager handles.
+struct ServiceHandle(SC_HANDLE);
+impl ServiceHandle {
+    /// Opens the service of the local machine with the requested access.
+    fn open(name: &str, access: DWORD) -> Result<Self, Error> {
+        let manager = unsafe { OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CONNECT) };
+        if manager.is_null() {
+            return Err(Error::last_os_error());
+        }
+        // The manager handle is only needed to open the service
+        let manager = ServiceHandle(manager);
+
+        let name = to_wide(Path::new(name));
+        let service = unsafe { OpenServiceW(manager.0, name.as_ptr(), access) };
+        if service.is_null() {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(ServiceHandle(service))
+    }
+}
+impl Drop for ServiceHandle {
+    fn drop(&mut self) {
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+/// Returns the local date and time formatted like 20220131-235959, for file names.
+pub fn local_timestamp() -> String {
+    let mut time = SYSTEMTIME::default();
+    unsafe { GetLocalTime(&mut time) };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        time.wYear, time.wMonth, time.wDay, time.wHour, time.wMinute, time.wSecond
+    )
+}
+
+/// Returns the minutes elapsed since midnight in the local time zone.
+pub fn local_minute_of_day() -> u32 {
+    let mut time = SYSTEMTIME::default();
+    unsafe { GetLocalTime(&mut time) };
+
+    time.wHour as u32 * 60 + time.wMinute as u32
+}
+
+// Locale information types of winnls.h, missing from winapi 0.3
+const LOCALE_SDECIMAL: LCTYPE = 0x0E;
+const LOCALE_STHOUSAND: LCTYPE = 0x0F;
+
+fn locale_info(info: LCTYPE) -> Option<String> {
+    let mut buffer = [0u16; 16];
+    // A null locale name means the user default locale
+    let len =
+        unsafe { GetLocaleInfoEx(ptr::null(), info, buffer.as_mut_ptr(), buffer.len() as i32) };
+
+    // The returned length includes the terminating null
+    if len > 0 {
+        Some(String::from_utf16_lossy(&buffer[..len as usize - 1]))
+    } else {
+        None
+    }
+}
+
+/// Converts a path to a null terminated wide string for the W suffixed functions.
+fn to_wide(path: &Path) -> Vec<u16> {
+    path.as_os_str().encode_wide().chain(Some(0)).collect()
+}
+
+/// A safe wrapper around file and directory handles.
+pub struct FileHandle(HANDLE);
+impl FileHandle {
+    /// Opens an existing file or directory with the requested access, without locking it for others.
+    pub fn open(path: &Path, access: DWORD) -> Result<Self, Error> {
+        Self::open_shared(
+            path,
+            access,
+            FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+        )
+    }
+
+    /// Opens an existing file or directory with the requested access and share mode.
+    pub fn open_shared(path: &Path, access: DWORD, share_mode: DWORD) -> Result<Self, Error> {
+        let path = to_wide(path);
+        unsafe {
+            // FILE_FLAG_BACKUP_SEMANTICS is required to obtain directory handles
+            let handle = CreateFileW(
+                path.as_ptr(),
+                access,
+                share_mode,
+                ptr::null_mut(),
+                OPEN_EXISTING,
+                FILE_FLAG_BACKUP_SEMANTICS,
+                ptr::null_mut(),
+            );
+            if handle != INVALID_HANDLE_VALUE {
+                Ok(Self(handle))
+            } else {
+                Err(Error::last_os_error())
+            }
+        }
+    }
+
+    /// On success returns the information about the opened file.
+    /// Otherwise returns an OS error.
+    pub fn information(&self) -> Result<BY_HANDLE_FILE_INFORMATION, Error> {
+        unsafe {
+            let mut info = BY_HANDLE_FILE_INFORMATION::default();
+            if GetFileInformationByHandle(self.0, &mut info) != 0 {
+                Ok(info)
+            } else {
+                Err(Error::last_os_error())
+            }
+        }
+    }
+}
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// A safe wrapper around Restart Manager sessions, used to find the processes using files.
+pub struct RestartManagerSession(DWORD);
+impl RestartManagerSession {
+    pub fn start() -> Result<Self, Error> {
+        unsafe {
+            let mut handle = 0;
+            let mut key = [0u16; CCH_RM_SESSION_KEY + 1];
+            match RmStartSession(&mut handle, 0, key.as_mut_ptr()) {
+                ERROR_SUCCESS => Ok(Self(handle)),
+                code => Err(Error::from_raw_os_error(code as i32)),
+            }
+        }
+    }
+
+    /// On success returns the number of processes using any of the files.
+    /// Otherwise returns an OS error.
+    pub fn count_users(&self, paths: &[PathBuf]) -> Result<u32, Error> {
+        let paths = paths.iter().map(|path| to_wide(path)).collect::<Vec<_>>();
+        let mut names = paths
+            .iter()
+            .map(|path| path.as_ptr())
+            .collect::<Vec<LPCWSTR>>();
+        unsafe {
+            let code = RmRegisterResources(
+                self.0,
+                names.len() as u32,
+                names.as_mut_ptr(),
+                0,
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+            );
+            if code != ERROR_SUCCESS {
+                return Err(Error::from_raw_os_error(code as i32));
+            }
+
+            // Asking for no process info returns only the count of processes
+            let mut needed = 0;
+            let mut count = 0;
+            let mut reasons = 0;
+            match RmGetList(
+                self.0,
+                &mut needed,
+                &mut count,
+                ptr::null_mut(),
+                &mut reasons,
+            ) {
+                ERROR_SUCCESS | ERROR_MORE_DATA => Ok(needed),
+                code => Err(Error::from_raw_os_error(code as i32)),
+            }
+        }
+    }
+}
+impl Drop for RestartManagerSession {
+    fn drop(&mut self) {
+        unsafe { RmEndSession(self.0) };
+    }
+}
+
+/// On success returns true if a process with the executable name is running, the
+/// comparison ignores case. Otherwise returns an OS error.
+pub fn is_process_running(exe_name: &str) -> Result<bool, Error> {
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+    if snapshot == INVALID_HANDLE_VALUE {
+        return Err(Error::last_os_error());
+    }
+    let snapshot = SnapshotHandle(snapshot);
+
+    let mut entry = PROCESSENTRY32W {
+        dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+        ..Default::default()
+    };
+
+    let mut found = unsafe { Process32FirstW(snapshot.0, &mut entry) } != 0;
+    while found {
+        let len = entry
+            .szExeFile
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(entry.szExeFile.len());
+        let name = String::from_utf16_lossy(&entry.szExeFile[..len]);
+
+        if name.eq_ignore_ascii_case(exe_name) {
+            return Ok(true);
+        }
+
+        found = unsafe { Process32NextW(snapshot.0, &mut entry) } != 0;
+    }
+
+    Ok(false)
+}
+
+/// Returns the paths of the executables and DLLs loaded by the running processes, the
+/// processes that can't be opened, like the protected ones, are skipped. Otherwise
+/// returns an OS error if the processes can't be listed.
+pub fn loaded_module_paths() -> Result<Vec<PathBuf>, Error> {
+    let mut pids: Vec<DWORD> = vec![0; 1024];
+    loop {
+        let size = (pids.len() * std::mem::size_of::<DWORD>()) as DWORD;
+        let mut needed = 0;
+        if unsafe { EnumProcesses(pids.as_mut_ptr(), size, &mut needed) } == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // A full buffer may have left processes out
+        if needed < size {
+            pids.truncate(needed as usize / std::mem::size_of::<DWORD>());
+            break;
+        }
+        pids.resize(pids.len() * 2, 0);
+    }
+
+    let mut paths = vec![];
+    for pid in pids {
+        let process =
+            unsafe { OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, FALSE, pid) };
+        if process.is_null() {
+            continue;
+        }
+        let process = ProcessHandle(process);
+
+        paths.extend(module_paths(&process));
+    }
+
+    Ok(paths)
+}
+
+/// Returns the paths of the modules loaded by the process, none if they can't be listed,
+/// like when the process is exiting.
+fn module_paths(process: &ProcessHandle) -> Vec<PathBuf> {
+    let mut modules: Vec<HMODULE> = vec![ptr::null_mut(); 256];
+    loop {
+        let size = (modules.len() * std::mem::size_of::<HMODULE>()) as DWORD;
+        let mut needed = 0;
+        let listed = unsafe {
+            EnumProcessModulesEx(
+                process.0,
+                modules.as_mut_ptr(),
+                size,
+                &mut needed,
+                LIST_MODULES_ALL,
+            )
+        };
+        if listed == 0 {
+            return vec![];
+        }
+
+        let count = needed as usize / std::mem::size_of::<HMODULE>();
+        if needed <= size {
+            modules.truncate(count);
+            break;
+        }
+        modules.resize(count, ptr::null_mut());
+    }
+
+    let mut buffer = vec![0u16; 32768];
+    modules
+        .into_iter()
+        .filter_map(|module| {
+            let len = unsafe {
+                GetModuleFileNameExW(
+                    process.0,
+                    module,
+                    buffer.as_mut_ptr(),
+                    buffer.len() as DWORD,
+                )
+            } as usize;
+            (len != 0).then(|| PathBuf::from(OsString::from_wide(&buffer[..len])))
+        })
+        .collect()
+}
+
+/// A safe wrapper around process snapshot handles.
+struct SnapshotHandle(HANDLE);
+impl Drop for SnapshotHandle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// A safe wrapper around search handles.
+struct FindHandle(HANDLE);
+impl Drop for FindHandle {
+    fn drop(&mut self) {
+        unsafe { FindClose(self.0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn alternate_streams_size_counts_named_streams() {
+        let dir = temp_dir("alternate-streams");
+        let path = dir.join("file.txt");
+        fs::write(&path, b"content").unwrap();
+        assert_eq!(alternate_streams_size(&path).unwrap(), 0);
+
+        fs::write(dir.join("file.txt:first"), [0; 10]).unwrap();
+        fs::write(dir.join("file.txt:second"), [0; 32]).unwrap();
+        assert_eq!(alternate_streams_size(&path).unwrap(), 42);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn alternate_streams_size_of_directory() {
+        let dir = temp_dir("alternate-streams-dir");
+        assert_eq!(alternate_streams_size(&dir).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disk_space_percentages() {
+        let space = DiskSpace {
+            free: 25,
+            total: 100,
+        };
+        assert_eq!(space.used_percent(), 75.0);
+        assert_eq!(space.free_percent(), 25.0);
+
+        let full = DiskSpace {
+            free: 0,
+            total: 500,
+        };
+        assert_eq!(full.used_percent(), 100.0);
+
+        // Unknown sizes count as an empty volume, never above a --when-above threshold
+        let empty = DiskSpace { free: 0, total: 0 };
+        assert_eq!(empty.used_percent(), 0.0);
+        assert!(!empty.is_used_above(1.0));
+    }
+
+    #[test]
+    fn disk_space_threshold() {
+        let space = DiskSpace {
+            free: 200,
+            total: 1000,
+        };
+        assert!(space.is_used_above(50.0));
+        assert!(space.is_used_above(80.0));
+        assert!(!space.is_used_above(80.5));
+        assert!(!space.is_used_above(95.0));
+    }
+}
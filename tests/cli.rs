@@ -0,0 +1,31 @@
+//! Runs the executable and checks what it prints.
+
+use std::process::{Command, Output};
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_wintempclean"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn error_format_json_prints_the_error_on_stderr() {
+    let output = run(&["--error-format", "json", "--created-before", "10x"]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("{\"error\":\"Invalid duration \\\"10x\\\" for --created-before"));
+    assert!(stderr.contains("\"causes\":["));
+    assert!(stderr.trim_end().ends_with("]}"));
+}
+
+#[test]
+fn error_format_defaults_to_text() {
+    let output = run(&["--created-before", "10x"]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid duration \"10x\" for --created-before"));
+    assert!(!stderr.starts_with('{'));
+}